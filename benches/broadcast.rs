@@ -0,0 +1,132 @@
+//! compares the traffic of flooding every message to every peer with
+//! pushing it along a tree and only announcing it to the other peers.
+//! Starts a tracker and a room of nodes on localhost for each.
+//!
+//!     cargo bench --bench broadcast -- [NODES] [MESSAGES]
+
+extern crate peas_rf_cp;
+
+use peas_rf_cp::common::roomkey::RoomKeys;
+use peas_rf_cp::node::nethandle::{NetHandle, Options};
+use peas_rf_cp::node::FromNetMsg;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TRACKER_PORT: u16 = 24600;
+/// how long the nodes get to find each other before we measure
+const SETTLE: Duration = Duration::from_secs(5);
+/// how long we wait for a message to reach everyone
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Run {
+    bytes: usize,
+    packets: usize,
+    delivered: usize,
+    expected: usize,
+    elapsed: Duration,
+}
+
+fn main() {
+    // cargo bench passes --bench
+    let args: Vec<usize> = std::env::args().skip(1).filter_map(|a| a.parse().ok()).collect();
+    let nodes = args.first().cloned().unwrap_or(16);
+    let msgs = args.get(1).cloned().unwrap_or(20);
+
+    thread::spawn(|| peas_rf_cp::tracker::server::start(TRACKER_PORT, 600));
+    thread::sleep(Duration::from_millis(300));
+    let tracker: SocketAddr = ([127, 0, 0, 1], TRACKER_PORT).into();
+
+    println!("{} nodes, {} messages", nodes, msgs);
+    let flood = run(tracker, nodes, msgs, true);
+    report("flooding", &flood, msgs);
+    let lazy = run(tracker, nodes, msgs, false);
+    report("lazy push", &lazy, msgs);
+    if flood.bytes > 0 {
+        println!("lazy push sent {:.0}% of the bytes of flooding",
+                 100.0 * lazy.bytes as f64 / flood.bytes as f64);
+    }
+}
+
+fn run(tracker: SocketAddr, nodes: usize, msgs: usize, flood: bool) -> Run {
+    let room = RoomKeys::generate();
+    let mut hs = Vec::new();
+    for i in 0..nodes {
+        let options = Options{flood, ..Options::default()};
+        hs.push(NetHandle::new(format!("bench{}", i), room.clone(), vec![tracker], options));
+        thread::sleep(Duration::from_millis(200));
+    }
+    thread::sleep(SETTLE);
+    drain(&hs);
+    let (bytes, packets) = sent(&hs);
+
+    let start = Instant::now();
+    let mut delivered = 0;
+    for m in 0..msgs {
+        hs[m % nodes].send_message(format!("bench message {}", m)).unwrap();
+        // let the tree form from the duplicates of the first messages
+        thread::sleep(Duration::from_millis(100));
+        delivered += drain(&hs);
+    }
+    while delivered < msgs * (nodes - 1) && start.elapsed() < DELIVERY_TIMEOUT {
+        thread::sleep(Duration::from_millis(20));
+        delivered += drain(&hs);
+    }
+    let elapsed = start.elapsed();
+    let (bytes_after, packets_after) = sent(&hs);
+    // the next run starts from a quiet network
+    for h in hs {
+        h.terminate();
+    }
+    Run {
+        bytes: bytes_after - bytes,
+        packets: packets_after - packets,
+        delivered,
+        expected: msgs * (nodes - 1),
+        elapsed,
+    }
+}
+
+/// reads everything the nodes have to say, returns how many messages of others they got
+fn drain(hs: &[NetHandle]) -> usize {
+    let mut n = 0;
+    for h in hs {
+        while let Ok(Some(m)) = h.read() {
+            if let FromNetMsg::NewMsg(m) = m {
+                if !m.is_myself() {
+                    n += 1;
+                }
+            }
+        }
+    }
+    n
+}
+
+/// the bytes and packets all nodes have sent so far
+fn sent(hs: &[NetHandle]) -> (usize, usize) {
+    for h in hs {
+        h.request_snapshot().unwrap();
+    }
+    let mut bytes = 0;
+    let mut packets = 0;
+    for h in hs {
+        loop {
+            match h.read() {
+                Ok(Some(FromNetMsg::Snapshot(s))) => {
+                    bytes += s.manager.bytes_sent;
+                    packets += s.manager.packets_sent;
+                    break;
+                }
+                Ok(Some(_)) => (),
+                Ok(None) => thread::sleep(Duration::from_millis(5)),
+                Err(_) => panic!("a node died"),
+            }
+        }
+    }
+    (bytes, packets)
+}
+
+fn report(name: &str, r: &Run, msgs: usize) {
+    println!("{:>10}: {} bytes in {} packets, {} bytes per message, {}/{} delivered in {:?}",
+             name, r.bytes, r.packets, r.bytes / msgs.max(1), r.delivered, r.expected, r.elapsed);
+}
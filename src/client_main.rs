@@ -1,5 +1,3 @@
-extern crate bincode;
-
 extern crate clap;
 use clap::{App, Arg, ArgMatches};
 
@@ -7,17 +5,17 @@ extern crate log;
 use log::LevelFilter;
 
 extern crate peas_rf_cp;
-use peas_rf_cp::common::id::Id;
+use peas_rf_cp::common::roomkey::RoomKeys;
 use peas_rf_cp::common::logger;
-use peas_rf_cp::node::{bot, nethandle::NetHandle};
+use peas_rf_cp::node::{bot, nethandle::NetHandle, nethandle::Options};
 use peas_rf_cp::ui;
 
-use std::fs::File;
-use std::io::{self, Read, Write};
-use std::mem;
-use std::net::ToSocketAddrs;
+use std::io::{self, Read};
+use std::net::{TcpStream, ToSocketAddrs};
 
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 const ARG_USERNAME: &str = "username";
 const ARG_LOG_LEVEL: &str = "log-level";
@@ -26,6 +24,18 @@ const ARG_NEW_ROOM: &str = "new-room";
 const ARG_JOIN_ROOM: &str = "join-room";
 const ARG_TRACKER: &str = "tracker";
 const ARG_BOT: &str = "bot";
+const ARG_TRACE: &str = "trace";
+const ARG_RELAY: &str = "relay";
+const ARG_USE_RELAY: &str = "use-relay";
+const ARG_KEY: &str = "key";
+const ARG_STATE_DIR: &str = "state-dir";
+const ARG_CONTROL: &str = "control";
+const ARG_INSPECT: &str = "inspect";
+const ARG_FANOUT: &str = "fanout";
+const ARG_ROTATE_KEY: &str = "rotate-key";
+
+/// how often a running client checks if its room file got new keys
+const ROOM_FILE_CHECK: Duration = Duration::from_secs(5);
 
 fn main() {
     let app = create_app();
@@ -38,14 +48,37 @@ fn main() {
             Ok(_) => {},
             Err(x) => log::error!("Failed to create room ({})", x),
         }
+    } else if matches.is_present(ARG_ROTATE_KEY) {
+        match rotate_key(&matches) {
+            Ok(_) => {},
+            Err(x) => log::error!("Failed to rotate the room key ({})", x),
+        }
+    } else if matches.is_present(ARG_INSPECT) {
+        match inspect(&matches) {
+            Ok(_) => {},
+            Err(x) => log::error!("Failed to inspect the node ({})", x),
+        }
     } else if matches.is_present(ARG_JOIN_ROOM) {
         match parse_room(&matches) {
-            Ok(room_id) => {
+            Ok(room) => {
                 let user = matches.value_of(ARG_USERNAME).unwrap().to_string();
                 let trck = matches.value_of(ARG_TRACKER).unwrap().to_string();
                 let bot = matches.is_present(ARG_BOT);
+                let options = Options {
+                    trace_file: matches.value_of(ARG_TRACE).map(|s| s.to_string()),
+                    relay: matches.is_present(ARG_RELAY),
+                    use_relay: matches.value_of(ARG_USE_RELAY)
+                        .map(|s| s.to_socket_addrs().unwrap().next().expect("relay address didn't resolve")),
+                    // the same id every start unless told otherwise
+                    key_file: Some(matches.value_of(ARG_KEY).map(|s| s.to_string()).unwrap_or_else(|| format!("{}.peas-key", user))),
+                    state_dir: matches.value_of(ARG_STATE_DIR).map(|s| s.to_string()),
+                    control_port: matches.value_of(ARG_CONTROL).map(|s| s.parse().expect("control port is not a number")),
+                    fanout: matches.value_of(ARG_FANOUT).map(|s| s.parse().expect("fanout is not a number")),
+                    flood: false,
+                };
 
-                run(user, room_id, trck, bot);
+                let room_file = matches.value_of(ARG_JOIN_ROOM).unwrap().to_string();
+                run(user, room, room_file, trck, bot, options);
             },
             Err(x) => log::error!("Failed to parse room ({})", x),
         }
@@ -54,22 +87,48 @@ fn main() {
     log::info!("Shutting down");
 }
 
-fn run(username: String, room_id: Id, tracker: String, bot: bool) {
+fn run(username: String, room: RoomKeys, room_file: String, tracker: String, bot: bool, options: Options) {
+    let version = room.current().version();
     let nethandle = NetHandle::new(
-        Id::from_u64(0),
         username,
-        room_id,
-        tracker.to_socket_addrs().unwrap().collect()
+        room,
+        tracker.to_socket_addrs().unwrap().collect(),
+        options
     );
 
+    let wrap = Arc::new(Mutex::new(nethandle));
+    watch_room_file(room_file, version, wrap.clone());
     if !bot {
-        let wrap = Arc::new(Mutex::new(nethandle));
         ui::cursive_main(wrap);
     } else {
-        bot::bot_main(nethandle);
+        bot::bot_main(wrap);
     }
 }
 
+/// hands the node the keys of the room file when it gets newer ones,
+/// so a rotation reaches running clients when the new file is copied over
+fn watch_room_file(room_file: String, mut version: u32, neth: Arc<Mutex<NetHandle>>) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(ROOM_FILE_CHECK);
+            let room = match RoomKeys::load(&room_file) {
+                Ok(r) => r,
+                Err(e) => {
+                    log::debug!("couldn't read the room file `{}` ({})", room_file, e);
+                    continue;
+                }
+            };
+            if room.current().version() > version {
+                version = room.current().version();
+                log::info!("room file `{}` has a new key, version {}", room_file, version);
+                if neth.lock().unwrap().rekey(room).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
 fn setup_logging<'a>(matches: &ArgMatches<'a>) {
     let level = match matches.value_of(ARG_LOG_LEVEL) {
         Some("all") => LevelFilter::max(),
@@ -93,41 +152,47 @@ fn create_room<'a>(matches: &ArgMatches<'a>) -> io::Result<()> {
     let room_name = new_room.unwrap();
     assert!(room_name.len() > 0);
 
-    let room_id = Id::new_random();
+    let room = RoomKeys::generate();
     let file_name = format!("{}.peas-room", room_name);
 
-    let mut file = File::create(&file_name)?;
-    file.write(&bincode::serialize(&room_id).unwrap()[..])?;
+    room.save(&file_name)?;
 
     log::debug!("Created room file `{}`", file_name);
 
     Ok(())
 }
 
-fn parse_room<'a>(matches: &ArgMatches<'a>) -> io::Result<Id> {
+/// prints the JSON snapshot of the client running with `--control`
+fn inspect<'a>(matches: &ArgMatches<'a>) -> io::Result<()> {
+    let port: u16 = matches.value_of(ARG_INSPECT).unwrap().parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut conn = TcpStream::connect(("127.0.0.1", port))?;
+    let mut json = String::new();
+    conn.read_to_string(&mut json)?;
+    println!("{}", json);
+    Ok(())
+}
+
+fn parse_room<'a>(matches: &ArgMatches<'a>) -> io::Result<RoomKeys> {
     let join_room = matches.value_of(ARG_JOIN_ROOM);
     assert!(join_room.is_some());
 
-    let room_file = join_room.unwrap();
-
-    let mut file = File::open(room_file)?;
+    let room = RoomKeys::load(join_room.unwrap())?;
+    log::debug!("Parsed room with id `{}`", room.id());
 
-    let id = {
-        // note: this code can be simplified but is kept this way in
-        // case we want to write/read more data than just the id
-
-        let mut buffer = vec![0; mem::size_of::<Id>()];
+    Ok(room)
+}
 
-        let room_id = {
-            file.read(&mut buffer)?;
-            bincode::deserialize::<Id>(&buffer).unwrap()
-        };
+/// replaces the key in a room file, everyone who should stay in the room needs the new file
+fn rotate_key<'a>(matches: &ArgMatches<'a>) -> io::Result<()> {
+    let room_file = matches.value_of(ARG_ROTATE_KEY).unwrap();
+    let mut room = RoomKeys::load(room_file)?;
+    room.rotate();
+    room.save(room_file)?;
 
-        log::debug!("Parsed room with id `{}`", room_id);
-        room_id
-    };
+    log::debug!("Room file `{}` now has key version {}", room_file, room.current().version());
 
-    Ok(id)
+    Ok(())
 }
 
 fn create_app<'a, 'b>() -> App<'a, 'b> {
@@ -179,6 +244,59 @@ fn create_app<'a, 'b>() -> App<'a, 'b> {
                 .help("Runs the client as a bot (does not take user input and discards user output)")
                 .requires_all(&[ARG_JOIN_ROOM, ARG_USERNAME, ARG_TRACKER])
                 .conflicts_with_all(&[ARG_NEW_ROOM]),
+        ).arg(
+            Arg::with_name(ARG_TRACE)
+                .long("trace")
+                .help("Records every sent and received packet to a trace file (read it with peas-trace)")
+                .takes_value(true)
+                .requires_all(&[ARG_JOIN_ROOM]),
+        ).arg(
+            Arg::with_name(ARG_RELAY)
+                .long("relay")
+                .help("Forwards packets for nodes that can't be reached directly")
+                .requires_all(&[ARG_JOIN_ROOM]),
+        ).arg(
+            Arg::with_name(ARG_USE_RELAY)
+                .long("use-relay")
+                .help("Lets other nodes reach this one through the relay at this address")
+                .takes_value(true)
+                .requires_all(&[ARG_JOIN_ROOM]),
+        ).arg(
+            Arg::with_name(ARG_KEY)
+                .long("key")
+                .help("Keeps this node's keypair (and so its id) in this file, creating it if it doesn't exist. USERNAME.peas-key by default")
+                .takes_value(true)
+                .requires_all(&[ARG_JOIN_ROOM]),
+        ).arg(
+            Arg::with_name(ARG_STATE_DIR)
+                .long("state-dir")
+                .help("Saves the peers of the room in this directory, to rejoin through them without the tracker")
+                .takes_value(true)
+                .requires_all(&[ARG_JOIN_ROOM]),
+        ).arg(
+            Arg::with_name(ARG_CONTROL)
+                .long("control")
+                .help("Answers --inspect on this port of localhost")
+                .takes_value(true)
+                .requires_all(&[ARG_JOIN_ROOM]),
+        ).arg(
+            Arg::with_name(ARG_FANOUT)
+                .long("fanout")
+                .help("How many peers messages are pushed to, 3 by default")
+                .takes_value(true)
+                .requires_all(&[ARG_JOIN_ROOM]),
+        ).arg(
+            Arg::with_name(ARG_ROTATE_KEY)
+                .long("rotate-key")
+                .help("Gives the room in this room file a new key and exits. Hand the file out again to everyone who should stay in the room")
+                .takes_value(true)
+                .conflicts_with_all(&[ARG_NEW_ROOM, ARG_JOIN_ROOM]),
+        ).arg(
+            Arg::with_name(ARG_INSPECT)
+                .long("inspect")
+                .help("Prints the routing table and state of the client running with --control on this port as JSON and exits")
+                .takes_value(true)
+                .conflicts_with_all(&[ARG_NEW_ROOM, ARG_JOIN_ROOM]),
         );
 
     return a;
@@ -0,0 +1,117 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// remote clocks further ahead of ours than this are not followed,
+/// so one broken clock can't drag everyone into the future
+const MAX_DRIFT_MS: u64 = 60 * 1000;
+
+/// a hybrid logical clock timestamp. Ordered by the wall clock first
+/// and a counter for events that happen in the same millisecond, or
+/// after an event from a clock that is ahead of ours
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Timestamp {
+    /// milliseconds since the unix epoch
+    wall: u64,
+    logical: u32,
+}
+
+impl Timestamp {
+    pub fn wall_millis(&self) -> u64 {
+        self.wall
+    }
+    pub fn logical(&self) -> u32 {
+        self.logical
+    }
+    /// the wall clock part as a `SystemTime`, for showing it
+    pub fn to_system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.wall)
+    }
+}
+
+/// hands out timestamps that never go backwards and are always
+/// later than every timestamp we have seen from others
+pub struct Clock {
+    last: Timestamp,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new()
+    }
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Clock{last: Timestamp::default()}
+    }
+
+    /// a timestamp for something happening here, like sending a message
+    pub fn now(&mut self) -> Timestamp {
+        let pt = physical_millis();
+        if pt > self.last.wall {
+            self.last = Timestamp{wall: pt, logical: 0};
+        } else {
+            self.last.logical += 1;
+        }
+        self.last
+    }
+
+    /// moves the clock past `remote`, a timestamp we received.
+    /// returns the timestamp of receiving it
+    pub fn update(&mut self, remote: Timestamp) -> Timestamp {
+        let pt = physical_millis();
+        if remote.wall > pt + MAX_DRIFT_MS {
+            warn!("ignoring a clock {}ms ahead of ours", remote.wall - pt);
+            return self.now();
+        }
+        let wall = pt.max(self.last.wall).max(remote.wall);
+        let logical = if wall == self.last.wall && wall == remote.wall {
+            self.last.logical.max(remote.logical) + 1
+        } else if wall == self.last.wall {
+            self.last.logical + 1
+        } else if wall == remote.wall {
+            remote.logical + 1
+        } else {
+            0
+        };
+        self.last = Timestamp{wall, logical};
+        self.last
+    }
+}
+
+fn physical_millis() -> u64 {
+    let d = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    d.as_secs() * 1000 + d.subsec_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_goes_backwards() {
+        let mut c = Clock::new();
+        let mut last = c.now();
+        for _ in 0..1000 {
+            let t = c.now();
+            assert!(t > last);
+            last = t;
+        }
+    }
+
+    #[test]
+    fn moves_past_what_it_received() {
+        let mut c = Clock::new();
+        let ahead = Timestamp{wall: physical_millis() + 1000, logical: 7};
+        let t = c.update(ahead);
+        assert_eq!(t, Timestamp{wall: ahead.wall, logical: 8});
+        assert!(c.now() > ahead);
+    }
+
+    #[test]
+    fn ignores_clocks_too_far_ahead() {
+        let mut c = Clock::new();
+        let broken = Timestamp{wall: physical_millis() + 2 * MAX_DRIFT_MS, logical: 0};
+        assert!(c.update(broken) < broken);
+        assert!(c.now() < broken);
+    }
+}
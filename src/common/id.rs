@@ -1,48 +1,157 @@
 use rand::RngCore;
 use std::fmt;
 
-/// 64-bit unsigned integer used a unique identifier.
+/// number of bytes in an `Id`
+pub const ID_BYTES: usize = 20;
+/// number of bits in an `Id`
+pub const ID_BITS: usize = ID_BYTES * 8;
+
+/// 160-bit unsigned integer used a unique identifier.
+/// The bytes are big endian, so ordering `Id`s orders their values.
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Serialize, Deserialize)]
-pub struct Id(u64);
+pub struct Id([u8; ID_BYTES]);
 
 impl Id {
     /// Creates a new `Id` with value `x`.
     #[inline]
     pub fn from_u64(x: u64) -> Id {
-        Id(x)
+        let mut bytes = [0; ID_BYTES];
+        for i in 0..8 {
+            bytes[ID_BYTES - 1 - i] = (x >> (8 * i)) as u8;
+        }
+        Id(bytes)
+    }
+
+    /// Creates a new `Id` from its big endian bytes.
+    #[inline]
+    pub fn from_bytes(bytes: [u8; ID_BYTES]) -> Id {
+        Id(bytes)
     }
 
     /// Creates a new `Id` whose value is random.
     #[inline]
     pub fn new_random() -> Id {
         let mut rng = rand::thread_rng();
-        let id = rng.next_u64();
-        Id(id)
+        let mut bytes = [0; ID_BYTES];
+        rng.fill_bytes(&mut bytes);
+        Id(bytes)
     }
 
     /// Computes the "distance" between this `Id` and another one.
     #[inline]
-    pub fn distance(&self, other: &Id) -> u64 {
-        self.0 ^ other.0
+    pub fn distance(&self, other: &Id) -> Id {
+        let mut res = [0; ID_BYTES];
+        for (i, r) in res.iter_mut().enumerate() {
+            *r = self.0[i] ^ other.0[i];
+        }
+        Id(res)
+    }
+
+    /// The big endian bytes of this `Id`.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; ID_BYTES] {
+        &self.0
     }
 
-    /// Consumes this `Id` and returns its value.
+    /// returns the number of leading zero bits of this `Id`.
     #[inline]
-    pub fn into_u64(self) -> u64 {
-        self.0
+    pub fn leading_zeros(&self) -> u32 {
+        let mut zeros = 0;
+        for b in self.0.iter() {
+            zeros += b.leading_zeros();
+            if *b != 0 {
+                break;
+            }
+        }
+        zeros
     }
 
     /// returns the number of leading common/equal bits between this
     /// `Id` and another one.
     #[inline]
     pub fn common_bits(&self, other: &Id) -> u32 {
-        (self.0 ^ other.0).leading_zeros()
+        self.distance(other).leading_zeros()
+    }
+
+    /// returns bit number `i`, counted from the most significant one.
+    #[inline]
+    pub fn bit(&self, i: usize) -> bool {
+        self.0[i / 8] & (0x80 >> (i % 8)) != 0
     }
 
+    /// Creates a random `Id` that has exactly `bits` leading bits
+    /// in common with this one.
+    pub fn random_with_common_bits(&self, bits: u32) -> Id {
+        let bits = bits as usize;
+        assert!(bits < ID_BITS, "an id can have at most {} bits in common with another one", ID_BITS - 1);
+        let mut res = Id::new_random().0;
+        for i in 0..bits + 1 {
+            let mask = 0x80 >> (i % 8);
+            // the common bits are copied and the one after them is flipped
+            let want = self.bit(i) != (i == bits);
+            if want {
+                res[i / 8] |= mask;
+            } else {
+                res[i / 8] &= !mask;
+            }
+        }
+        Id(res)
+    }
 }
 
 impl fmt::Display for Id {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordering_follows_the_value() {
+        assert!(Id::from_u64(1) < Id::from_u64(2));
+        assert!(Id::from_u64(0xff) < Id::from_u64(0x100));
+        assert_eq!(Id::from_u64(0x1234).as_bytes()[ID_BYTES - 2..], [0x12, 0x34]);
+        assert_eq!(format!("{}", Id::from_u64(0xab)), format!("{}ab", "00".repeat(ID_BYTES - 1)));
+    }
+
+    #[test]
+    fn distance_is_xor() {
+        let a = Id::new_random();
+        let b = Id::new_random();
+        assert_eq!(a.distance(&b), b.distance(&a));
+        assert_eq!(a.distance(&a), Id::from_u64(0));
+        assert_eq!(Id::from_u64(0b1100).distance(&Id::from_u64(0b1010)), Id::from_u64(0b0110));
+    }
+
+    #[test]
+    fn leading_zeros_and_common_bits() {
+        assert_eq!(Id::from_u64(0).leading_zeros(), ID_BITS as u32);
+        assert_eq!(Id::from_u64(1).leading_zeros(), ID_BITS as u32 - 1);
+        assert_eq!(Id::from_u64(0x80).leading_zeros(), ID_BITS as u32 - 8);
+        assert_eq!(Id::from_u64(4).common_bits(&Id::from_u64(5)), ID_BITS as u32 - 1);
+        assert!(Id::from_u64(1).bit(ID_BITS - 1));
+        assert!(!Id::from_u64(1).bit(ID_BITS - 2));
+    }
+
+    #[test]
+    fn random_ids_share_exactly_the_asked_bits() {
+        let me = Id::new_random();
+        for bits in [0, 1, 7, 8, 9, 80, ID_BITS as u32 - 1].iter() {
+            for _ in 0..20 {
+                assert_eq!(me.common_bits(&me.random_with_common_bits(*bits)), *bits);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn an_id_shares_fewer_bits_than_it_has() {
+        Id::new_random().random_with_common_bits(ID_BITS as u32);
     }
 }
@@ -0,0 +1,167 @@
+use common::id::{Id, ID_BYTES};
+use common::write_secret;
+use ed25519_dalek::{ExpandedSecretKey, Keypair, PublicKey, SecretKey, Signature, Signer, Verifier, SECRET_KEY_LENGTH};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::scalar::Scalar;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// the keypair a node proves its `Id` with.
+/// The id is the first bytes of the sha256 of the public key,
+/// so nobody can pick their own id without also having the key for it
+pub struct Identity {
+    keypair: Keypair,
+    id: Id,
+}
+
+/// a public key and a signature made with it, proving that whoever
+/// made it owns the id derived from the key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Proof {
+    key: [u8; 32],
+    sig: Vec<u8>,
+}
+
+/// the id that belongs to the public key `key`
+pub fn id_of_key(key: &[u8; 32]) -> Id {
+    let hash = Sha256::digest(key);
+    let mut bytes = [0; ID_BYTES];
+    bytes.copy_from_slice(&hash[..ID_BYTES]);
+    Id::from_bytes(bytes)
+}
+
+impl Identity {
+    /// creates a new random identity
+    pub fn generate() -> Self {
+        let mut bytes = [0; SECRET_KEY_LENGTH];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Identity::from_secret(&bytes).expect("any 32 bytes are a valid secret key")
+    }
+
+    fn from_secret(bytes: &[u8]) -> Option<Self> {
+        let secret = SecretKey::from_bytes(bytes).ok()?;
+        let public = PublicKey::from(&secret);
+        let id = id_of_key(public.as_bytes());
+        Some(Identity{keypair: Keypair{secret, public}, id})
+    }
+
+    /// reads the secret key in `path`, or creates a new identity and
+    /// saves its secret key there if the file doesn't exist
+    pub fn load_or_create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            let mut bytes = Vec::new();
+            File::open(path)?.read_to_end(&mut bytes)?;
+            Identity::from_secret(&bytes)
+                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "not a secret key"))
+        } else {
+            let ident = Identity::generate();
+            write_secret(path, ident.keypair.secret.as_bytes())?;
+            Ok(ident)
+        }
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.keypair.public.to_bytes()
+    }
+
+    /// a key only we and the owner of the public key `key` can make, to
+    /// encrypt what we send each other. Both ed25519 keys are turned into
+    /// x25519 keys for a Diffie-Hellman, so it is the same every time.
+    /// None if `key` isn't a usable public key
+    pub fn shared_key(&self, key: &[u8; 32]) -> Option<[u8; 32]> {
+        let theirs = CompressedEdwardsY(*key).decompress()?.to_montgomery();
+        // the first half of the expanded key is the clamped scalar signatures are made with
+        let mut scalar = [0; 32];
+        scalar.copy_from_slice(&ExpandedSecretKey::from(&self.keypair.secret).to_bytes()[..32]);
+        let shared = Scalar::from_bits(scalar) * theirs;
+        if shared.as_bytes().iter().all(|b| *b == 0) {
+            // a key of low order, anyone could make this
+            return None;
+        }
+        let mut out = [0; 32];
+        out.copy_from_slice(&Sha256::digest(shared.as_bytes()));
+        Some(out)
+    }
+
+    /// signs `msg`, anyone can check the result with `Proof::verify`
+    pub fn prove(&self, msg: &[u8]) -> Proof {
+        Proof {
+            key: self.keypair.public.to_bytes(),
+            sig: self.keypair.sign(msg).to_bytes().to_vec(),
+        }
+    }
+}
+
+impl Proof {
+    /// the public key it was made with
+    pub fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    /// checks that this is a signature of `msg`.
+    /// returns the id of the signer if it is
+    pub fn verify(&self, msg: &[u8]) -> Option<Id> {
+        let key = PublicKey::from_bytes(&self.key).ok()?;
+        let sig = Signature::try_from(&self.sig[..]).ok()?;
+        key.verify(msg, &sig).ok()?;
+        Some(id_of_key(&self.key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn proof_verifies_only_its_message() {
+        let ident = Identity::generate();
+        let proof = ident.prove(b"hello");
+        assert_eq!(proof.verify(b"hello"), Some(ident.id()));
+        assert_eq!(proof.verify(b"hellO"), None);
+        assert_eq!(id_of_key(proof.key()), ident.id());
+    }
+
+    #[test]
+    fn both_sides_share_the_same_key() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let ab = alice.shared_key(&bob.public_key()).unwrap();
+        assert_eq!(Some(ab), bob.shared_key(&alice.public_key()));
+        assert_eq!(Some(ab), alice.shared_key(&bob.public_key()));
+        let eve = Identity::generate();
+        assert!(eve.shared_key(&alice.public_key()) != Some(ab));
+    }
+
+    #[test]
+    fn low_order_keys_share_nothing() {
+        // the neutral point, every scalar times it is zero
+        let mut neutral = [0; 32];
+        neutral[0] = 1;
+        assert_eq!(Identity::generate().shared_key(&neutral), None);
+    }
+
+    #[test]
+    fn key_file_is_private_and_reloads() {
+        let path = env::temp_dir().join(format!("identity-test-{}", ::common::get_hash()));
+        let first = Identity::load_or_create(&path).unwrap();
+        let again = Identity::load_or_create(&path).unwrap();
+        assert_eq!(first.id(), again.id());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
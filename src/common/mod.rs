@@ -1,10 +1,38 @@
+pub mod hlc;
 pub mod id;
+pub mod identity;
 pub mod logger;
+pub mod roomkey;
 pub mod timer;
 use rand::RngCore;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
 
 /// get random u64 hash
 pub fn get_hash() -> u64 {
     let mut rng = rand::thread_rng();
     rng.next_u64()
 }
+
+/// writes `bytes` to `path` so that only we can read it. It goes to a
+/// temporary file first, so a crash can't leave half a key behind
+pub fn write_secret(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    // the mode is only set on files that are created
+    let _ = fs::remove_file(&tmp);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    {
+        let mut f = options.open(&tmp)?;
+        f.write_all(bytes)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,254 @@
+use common::id::Id;
+use common::write_secret;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use rand::RngCore;
+use bincode;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// how many keys of a room we keep, to read what was sent before a rotation
+const MAX_KEYS: usize = 8;
+/// how big the room files of before rooms had keys are,
+/// with a 64 bit id and with a 160 bit one
+const ID_SIZES: [usize; 2] = [8, 20];
+
+/// a symmetric key everything said in a room is encrypted with
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoomKey {
+    version: u32,
+    key: [u8; 32],
+}
+
+/// something encrypted with a room key, it can only be read and
+/// changed by whoever has the key with the same version
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sealed {
+    version: u32,
+    nonce: [u8; 12],
+    data: Vec<u8>,
+}
+
+/// the id of a room and its keys, the newest last.
+/// This is what a room file holds, whoever has it can read the room
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoomKeys {
+    id: Id,
+    keys: Vec<RoomKey>,
+}
+
+impl RoomKey {
+    fn generate(version: u32) -> Self {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        RoomKey{version, key}
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// encrypts `plain`. `ad` isn't encrypted, but `open` fails if it
+    /// isn't given the same
+    pub fn seal(&self, plain: &[u8], ad: &[u8]) -> Sealed {
+        let mut nonce = [0; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let data = self.cipher()
+            .encrypt(&Nonce::from(nonce), Payload{msg: plain, aad: ad})
+            .expect("encrypting can't fail");
+        Sealed{version: self.version, nonce, data}
+    }
+
+    /// decrypts `sealed`, None if it wasn't sealed with this key and `ad`
+    pub fn open(&self, sealed: &Sealed, ad: &[u8]) -> Option<Vec<u8>> {
+        if sealed.version != self.version {
+            return None;
+        }
+        self.cipher()
+            .decrypt(&Nonce::from(sealed.nonce), Payload{msg: &sealed.data, aad: ad})
+            .ok()
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&Key::from(self.key))
+    }
+}
+
+// the key itself is never logged
+impl fmt::Debug for RoomKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RoomKey(v{})", self.version)
+    }
+}
+
+impl Sealed {
+    /// the version of the key it was sealed with
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+impl fmt::Debug for Sealed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sealed(v{}, {} bytes)", self.version, self.data.len())
+    }
+}
+
+impl RoomKeys {
+    /// a new room with a random id and key
+    pub fn generate() -> Self {
+        RoomKeys{id: Id::new_random(), keys: vec![RoomKey::generate(0)]}
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    /// the key new things are sealed with
+    pub fn current(&self) -> &RoomKey {
+        self.keys.last().expect("a room always has a key")
+    }
+
+    pub fn seal(&self, plain: &[u8], ad: &[u8]) -> Sealed {
+        self.current().seal(plain, ad)
+    }
+
+    /// decrypts `sealed` with the key it was sealed with, if we have it
+    pub fn open(&self, sealed: &Sealed, ad: &[u8]) -> Option<Vec<u8>> {
+        self.keys.iter()
+            .find(|k| k.version == sealed.version)
+            .and_then(|k| k.open(sealed, ad))
+    }
+
+    /// replaces the current key with a new one. Whoever only has the old
+    /// keys can't read what is sent from now on, so the room file has
+    /// to be handed out again to everyone who should stay in the room
+    pub fn rotate(&mut self) {
+        let version = self.current().version + 1;
+        self.keys.push(RoomKey::generate(version));
+        if self.keys.len() > MAX_KEYS {
+            self.keys.remove(0);
+        }
+    }
+
+    /// takes the keys of `other` we don't have yet.
+    /// returns false if it is another room
+    pub fn merge(&mut self, other: &RoomKeys) -> bool {
+        if other.id != self.id {
+            return false;
+        }
+        for k in other.keys.iter() {
+            if !self.keys.iter().any(|m| m.version == k.version) {
+                self.keys.push(k.clone());
+            }
+        }
+        self.keys.sort_by_key(|k| k.version);
+        let skip = self.keys.len().saturating_sub(MAX_KEYS);
+        self.keys.drain(..skip);
+        true
+    }
+
+    /// reads a room file
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        let keys: RoomKeys = match bincode::deserialize(&bytes) {
+            Ok(k) => k,
+            // room files from before rooms had keys only hold the id,
+            // nobody can read such a room any more
+            Err(_) if ID_SIZES.contains(&bytes.len()) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "room file from before rooms had keys, create the room again"));
+            }
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        if keys.keys.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "room file without a key"));
+        }
+        Ok(keys)
+    }
+
+    /// writes the room file, only we can read it
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_secret(path.as_ref(), &bincode::serialize(self).unwrap())
+    }
+}
+
+impl fmt::Debug for RoomKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RoomKeys({}, v{})", self.id, self.current().version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn temp_file(name: &str) -> ::std::path::PathBuf {
+        env::temp_dir().join(format!("{}-{}", name, ::common::get_hash()))
+    }
+
+    #[test]
+    fn old_keys_still_open_after_a_rotation() {
+        let mut room = RoomKeys::generate();
+        let old = room.seal(b"before", b"ad");
+        room.rotate();
+        let new = room.seal(b"after", b"ad");
+        assert_eq!(new.version(), old.version() + 1);
+        assert_eq!(room.open(&old, b"ad"), Some(b"before".to_vec()));
+        assert_eq!(room.open(&new, b"ad"), Some(b"after".to_vec()));
+        assert_eq!(room.open(&new, b"other"), None);
+    }
+
+    #[test]
+    fn merge_keeps_the_newest_keys() {
+        let mut room = RoomKeys::generate();
+        let mut newer = room.clone();
+        for _ in 0..MAX_KEYS {
+            newer.rotate();
+        }
+        assert!(room.merge(&newer));
+        assert_eq!(room.keys.len(), MAX_KEYS);
+        assert_eq!(room.current().version(), MAX_KEYS as u32);
+        assert!(!room.merge(&RoomKeys::generate()));
+    }
+
+    #[test]
+    fn room_file_is_private_and_reloads() {
+        let path = temp_file("room-test");
+        let room = RoomKeys::generate();
+        room.save(&path).unwrap();
+        let loaded = RoomKeys::load(&path).unwrap();
+        assert_eq!(loaded.id(), room.id());
+        assert_eq!(loaded.open(&room.seal(b"hi", b""), b""), Some(b"hi".to_vec()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn id_only_room_files_are_refused() {
+        let path = temp_file("old-room-test");
+        fs::write(&path, bincode::serialize(&Id::new_random()).unwrap()).unwrap();
+        let e = RoomKeys::load(&path).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("before rooms had keys"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn room_files_with_64_bit_ids_are_refused() {
+        let path = temp_file("older-room-test");
+        fs::write(&path, bincode::serialize(&0x1234_5678_9abc_def0u64).unwrap()).unwrap();
+        let e = RoomKeys::load(&path).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("before rooms had keys"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -33,6 +33,10 @@ impl Timer {
         assert!(margin > 0.0 && margin <= 1.0);
         self.enabled && Instant::now().duration_since(self.start).checked_sub(self.duration).is_some()
     }
+    /// how long until it runs out, zero if it has
+    pub fn time_left(&self) -> Duration {
+        self.duration.checked_sub(self.start.elapsed()).unwrap_or(Duration::from_secs(0))
+    }
     pub fn disable(&mut self) {
         self.enabled = false;
     }
@@ -7,11 +7,16 @@ extern crate rand;
 extern crate flexi_logger;
 extern crate cursive;
 extern crate chrono;
+extern crate ed25519_dalek;
+extern crate sha2;
+extern crate chacha20poly1305;
+extern crate curve25519_dalek;
 
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
 extern crate bincode;
+extern crate serde_json;
 
 extern crate pnet;
 
@@ -5,10 +5,32 @@ use std::{error::Error, fmt};
 
 pub mod udp;
 pub mod udpmanager;
+pub mod trace;
+pub mod stream;
+pub mod ratelimit;
+pub mod tcp;
+pub mod relay;
 
 const MAX_UDP: usize = 512;
 pub type Result<T> = std::result::Result<T, NetworkError>;
 
+/// the transports a node (or tracker) accepts packets on.
+/// Every node speaks UDP, TCP is the fallback for networks that block UDP
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Transports {
+    pub udp: bool,
+    pub tcp: bool,
+}
+
+impl Transports {
+    pub fn udp_only() -> Self {
+        Transports{udp: true, tcp: false}
+    }
+    pub fn all() -> Self {
+        Transports{udp: true, tcp: true}
+    }
+}
+
 /// random network error
 #[derive(Debug)]
 pub enum NetworkError {
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// destinations we haven't sent anything to for this long are forgotten
+const IDLE_DEST: Duration = Duration::from_secs(60);
+
+/// limits on how fast a udpmanager may send, all in bytes per second
+#[derive(Debug, Clone, Copy)]
+pub struct RateConfig {
+    /// budget for everything sent from the socket
+    pub global_rate: f64,
+    pub global_burst: f64,
+    /// the rate a new destination starts at
+    pub dest_rate: f64,
+    pub dest_burst: f64,
+    /// a destination's rate never backs off below this
+    pub dest_min_rate: f64,
+    /// a destination's rate never grows above this
+    pub dest_max_rate: f64,
+    /// how much a destination's rate grows for every answered packet
+    pub dest_increase: f64,
+}
+
+impl Default for RateConfig {
+    fn default() -> Self {
+        RateConfig {
+            global_rate: 256.0 * 1024.0,
+            global_burst: 32.0 * 1024.0,
+            dest_rate: 32.0 * 1024.0,
+            dest_burst: 8.0 * 1024.0,
+            dest_min_rate: 2.0 * 1024.0,
+            dest_max_rate: 128.0 * 1024.0,
+            dest_increase: 1024.0,
+        }
+    }
+}
+
+/// classic token bucket, `tokens` may go negative when something is charged
+/// that couldn't wait
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket{rate, burst, tokens: burst, last: Instant::now()}
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + secs * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    fn has(&self, n: f64) -> bool {
+        self.tokens >= n
+    }
+
+    fn take(&mut self, n: f64) {
+        self.tokens -= n;
+    }
+}
+
+/// send budget of one destination with AIMD congestion control
+struct Dest {
+    bucket: TokenBucket,
+    last_used: Instant,
+}
+
+/// decides whether a packet may be sent right now.
+/// There is one global budget and one per destination, the latter
+/// is halved whenever a packet to it is lost and grows slowly
+/// when packets get through
+pub struct Limiter {
+    config: RateConfig,
+    global: TokenBucket,
+    dests: HashMap<SocketAddr, Dest>,
+}
+
+impl Limiter {
+    pub fn new(config: RateConfig) -> Self {
+        Limiter {
+            config,
+            global: TokenBucket::new(config.global_rate, config.global_burst),
+            dests: HashMap::new(),
+        }
+    }
+
+    /// replaces the limits, destinations start over from `dest_rate`
+    pub fn set_config(&mut self, config: RateConfig) {
+        *self = Limiter::new(config);
+    }
+
+    fn dest(&mut self, dest: SocketAddr, now: Instant) -> &mut Dest {
+        let config = self.config;
+        let d = self.dests.entry(dest).or_insert_with(|| Dest{
+            bucket: TokenBucket::new(config.dest_rate, config.dest_burst),
+            last_used: now,
+        });
+        d.last_used = now;
+        d
+    }
+
+    /// takes `size` bytes from the budgets if both have room for it.
+    /// returns false if the packet has to wait
+    pub fn try_send(&mut self, dest: SocketAddr, size: usize) -> bool {
+        let now = Instant::now();
+        let size = size as f64;
+        self.global.refill(now);
+        if !self.global.has(size) {
+            return false;
+        }
+        {
+            let d = self.dest(dest, now);
+            d.bucket.refill(now);
+            if !d.bucket.has(size) {
+                return false;
+            }
+            d.bucket.take(size);
+        }
+        self.global.take(size);
+        true
+    }
+
+    /// takes `size` bytes from the budgets no matter if there is room,
+    /// for packets that have their own congestion control
+    pub fn charge(&mut self, dest: SocketAddr, size: usize) {
+        let now = Instant::now();
+        self.global.refill(now);
+        self.global.take(size as f64);
+        let d = self.dest(dest, now);
+        d.bucket.refill(now);
+        d.bucket.take(size as f64);
+    }
+
+    /// a packet to `dest` was lost, halve its rate
+    pub fn on_loss(&mut self, dest: SocketAddr) {
+        let min = self.config.dest_min_rate;
+        let d = self.dest(dest, Instant::now());
+        d.bucket.rate = (d.bucket.rate / 2.0).max(min);
+        trace!("rate to {} backed off to {:.0} B/s", dest, d.bucket.rate);
+    }
+
+    /// a packet to `dest` was answered, grow its rate a little
+    pub fn on_success(&mut self, dest: SocketAddr) {
+        let max = self.config.dest_max_rate;
+        let inc = self.config.dest_increase;
+        let d = self.dest(dest, Instant::now());
+        d.bucket.rate = (d.bucket.rate + inc).min(max);
+    }
+
+    /// forgets destinations that haven't been used in a while
+    pub fn cleanup(&mut self) {
+        let now = Instant::now();
+        self.dests.retain(|_, d| now.duration_since(d.last_used) < IDLE_DEST);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RateConfig {
+        RateConfig {
+            global_rate: 1000.0,
+            global_burst: 1000.0,
+            dest_rate: 100.0,
+            dest_burst: 300.0,
+            dest_min_rate: 20.0,
+            dest_max_rate: 150.0,
+            dest_increase: 40.0,
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn burst_then_wait() {
+        let mut l = Limiter::new(config());
+        assert!(l.try_send(addr(1), 200));
+        assert!(l.try_send(addr(1), 100));
+        assert!(!l.try_send(addr(1), 100));
+        // another destination has its own budget
+        assert!(l.try_send(addr(2), 300));
+    }
+
+    #[test]
+    fn global_budget_is_shared() {
+        let mut l = Limiter::new(config());
+        for p in 0..3 {
+            assert!(l.try_send(addr(p), 300));
+        }
+        assert!(!l.try_send(addr(4), 200));
+    }
+
+    #[test]
+    fn charge_can_go_over() {
+        let mut l = Limiter::new(config());
+        l.charge(addr(1), 500);
+        assert!(!l.try_send(addr(1), 1));
+    }
+
+    #[test]
+    fn aimd() {
+        let mut l = Limiter::new(config());
+        l.on_loss(addr(1));
+        assert_eq!(l.dests[&addr(1)].bucket.rate, 50.0);
+        l.on_loss(addr(1));
+        l.on_loss(addr(1));
+        assert_eq!(l.dests[&addr(1)].bucket.rate, 20.0);
+        for _ in 0..10 {
+            l.on_success(addr(1));
+        }
+        assert_eq!(l.dests[&addr(1)].bucket.rate, 150.0);
+    }
+}
@@ -0,0 +1,121 @@
+use bincode::deserialize;
+use common::timer::Timer;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// the service number relay packets are sent to
+pub const RELAY_SERVICE: u32 = u32::MAX - 1;
+
+/// how often a relayed node registers with its relay again.
+/// This also keeps the NAT mapping towards the relay open
+pub const REGISTER_INTERVAL: Duration = Duration::from_secs(20);
+/// a relay forgets a client that hasn't registered for this long,
+/// and who reached a client through it after as long
+const CLIENT_TTL: Duration = Duration::from_secs(60);
+
+/// the payload of a udpmanager `Msg` sent to RELAY_SERVICE.
+/// `inner` is always a whole serialized `Msg`
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RelayMsg {
+    /// asks the receiver to relay packets to us
+    Register,
+    /// answer to `Register`, contains the address the relay sees us as
+    Registered(SocketAddr),
+    /// asks the relay to pass `inner` on to `to`
+    Forward { to: SocketAddr, inner: Vec<u8> },
+    /// `inner` was sent to us by `from` through the relay
+    Deliver { from: SocketAddr, inner: Vec<u8> },
+}
+
+/// decodes a serialized `RelayMsg` into something readable
+pub fn describe(payload: &[u8]) -> Option<String> {
+    deserialize::<RelayMsg>(payload).ok().map(|r| match r {
+        RelayMsg::Forward{to, inner} => format!("Forward {{ to: {}, {} bytes }}", to, inner.len()),
+        RelayMsg::Deliver{from, inner} => format!("Deliver {{ from: {}, {} bytes }}", from, inner.len()),
+        other => format!("{:?}", other),
+    })
+}
+
+/// the relay related state of a manager
+pub struct RelayState {
+    /// do we relay for others?
+    pub enabled: bool,
+    /// nodes we relay for, mapped to when they last registered
+    clients: HashMap<SocketAddr, Instant>,
+    /// (client, peer) pairs where peer reached the client through us,
+    /// mapped to when it last did. The client may answer them
+    contacts: HashMap<(SocketAddr, SocketAddr), Instant>,
+    /// the relay we are registered with
+    pub via: Option<SocketAddr>,
+    pub register_timer: Timer,
+    /// have we heard back from `via`?
+    pub registered: bool,
+    /// the address `via` sees us as, which is where others reach us
+    pub observed: Option<SocketAddr>,
+}
+
+impl Default for RelayState {
+    fn default() -> Self {
+        RelayState::new()
+    }
+}
+
+impl RelayState {
+    pub fn new() -> Self {
+        let mut t = Timer::new(REGISTER_INTERVAL);
+        t.disable();
+        RelayState {
+            enabled: false,
+            clients: HashMap::new(),
+            contacts: HashMap::new(),
+            via: None,
+            register_timer: t,
+            registered: false,
+            observed: None,
+        }
+    }
+
+    /// starts registering with `relay`
+    pub fn use_relay(&mut self, relay: SocketAddr) {
+        self.via = Some(relay);
+        self.registered = false;
+        self.observed = None;
+        self.register_timer = Timer::new_expired();
+    }
+
+    pub fn add_client(&mut self, client: SocketAddr) {
+        if self.clients.insert(client, Instant::now()).is_none() {
+            info!("relaying for {}", client);
+        }
+    }
+
+    /// anyone may reach a client through us, but a client may only reach
+    /// other clients and those that reached it through us first.
+    /// Otherwise we could be used to send to arbitrary addresses
+    pub fn may_forward(&self, from: SocketAddr, to: SocketAddr) -> bool {
+        self.enabled && (self.clients.contains_key(&to)
+            || (self.clients.contains_key(&from) && self.contacts.contains_key(&(from, to))))
+    }
+
+    /// remembers that we passed something from `from` on to `to`
+    pub fn forwarded(&mut self, from: SocketAddr, to: SocketAddr) {
+        if self.clients.contains_key(&to) {
+            self.contacts.insert((to, from), Instant::now());
+        }
+    }
+
+    /// forgets clients that stopped registering
+    pub fn cleanup(&mut self) {
+        let now = Instant::now();
+        self.clients.retain(|c, t| {
+            let keep = now.duration_since(*t) < CLIENT_TTL;
+            if !keep {
+                info!("stopped relaying for {}", c);
+            }
+            keep
+        });
+        let clients = &self.clients;
+        self.contacts.retain(|(c, _), t| clients.contains_key(c) && now.duration_since(*t) < CLIENT_TTL);
+    }
+}
@@ -0,0 +1,917 @@
+use bincode::{deserialize, serialize};
+use common::get_hash;
+use common::timer::Timer;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the service number every stream packet is sent to.
+/// Stream ports live in their own namespace and don't collide with services
+pub const STREAM_SERVICE: u32 = u32::MAX;
+
+/// payload bytes per `Segment::Data`, leaves room for the headers in MAX_UDP
+const SEGMENT_SIZE: usize = 400;
+/// how many segments the receiver buffers before it closes its window
+const RECV_WINDOW: u32 = 64;
+/// how many written bytes may be unacknowledged before `write` blocks
+const SEND_BUFFER: usize = 64 * SEGMENT_SIZE;
+const INITIAL_SSTHRESH: u32 = 32;
+const INITIAL_RTO: Duration = Duration::from_millis(300);
+const MIN_RTO: Duration = Duration::from_millis(100);
+const MAX_RTO: Duration = Duration::from_secs(3);
+/// retransmissions of one segment before the stream is considered dead
+const RETRIES: u32 = 8;
+/// max number of out of order segments reported in one ack
+const MAX_SACKS: usize = 16;
+/// how long a finished stream is kept around
+const LINGER: Duration = Duration::from_secs(2);
+/// duplicate acks before the oldest segment is sent again without waiting for its timer
+const DUP_ACKS: u32 = 3;
+const POLL_TIME: Duration = Duration::from_millis(10);
+
+/// a stream is identified by the other end and a random id picked by the connector
+type Key = (SocketAddr, u64);
+
+/// the packets that make up a stream, sent as the payload of a udpmanager `Msg`
+/// whose id is the stream id
+#[derive(Serialize, Deserialize, Debug)]
+enum Segment {
+    /// open a stream to a listener on a port
+    Syn(u32),
+    /// the listener accepted the stream
+    SynAck,
+    /// `bytes` is the `seq`:th segment of the stream
+    Data { seq: u32, bytes: Vec<u8> },
+    /// the writer is done, this takes up sequence number `seq`
+    Fin(u32),
+    /// everything before `next` has arrived, so has every sequence number in `sacks`.
+    /// `window` is the number of segments the receiver can take right now
+    Ack { next: u32, sacks: Vec<u32>, window: u32 },
+    /// the stream doesn't exist (anymore), give up on it
+    Rst,
+}
+
+/// instructions from `Stream`s and `StreamListener`s to the manager thread
+pub enum Command {
+    Listen(u32, Sender<Stream>),
+    Unlisten(u32),
+    Connect(Key, u32, Sender<Event>, Arc<Shared>),
+    Write(Key, Vec<u8>),
+    Close(Key),
+}
+
+/// things the manager thread tells a `Stream`
+pub enum Event {
+    Data(Vec<u8>),
+    /// the other end closed the stream, nothing more will arrive
+    Closed,
+    Failed(&'static str),
+}
+
+/// counters shared between a `Stream` and the manager thread
+pub struct Shared {
+    /// written bytes that have not been acknowledged yet
+    unacked: AtomicUsize,
+    /// received bytes that have not been read yet
+    buffered: AtomicUsize,
+    dead: AtomicBool,
+}
+
+/// a reliable, ordered byte stream to another node.
+/// Reading blocks until data arrives (or the read timeout runs out),
+/// writing blocks only when the send buffer is full.
+/// The stream is closed when dropped
+pub struct Stream {
+    key: Key,
+    cmds: Sender<Command>,
+    events: Receiver<Event>,
+    shared: Arc<Shared>,
+    leftover: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    closed: bool,
+    read_timeout: Option<Duration>,
+}
+
+/// accepts streams that other nodes open to a port
+pub struct StreamListener {
+    port: u32,
+    rec: Receiver<Stream>,
+    cmds: Sender<Command>,
+}
+
+/// a segment that has been sent but not acknowledged
+struct InFlight {
+    /// None means that this is the Fin
+    bytes: Option<Vec<u8>>,
+    sent: Instant,
+    timer: Timer,
+    retries: u32,
+    retransmitted: bool,
+    /// send it again on the next tick without waiting for the timer
+    fast_retransmit: bool,
+}
+
+/// the manager's side of a stream
+struct Conn {
+    events: Sender<Event>,
+    shared: Arc<Shared>,
+    established: bool,
+    /// port to connect to if we are the connecting side and haven't heard back
+    syn: Option<u32>,
+    syn_timer: Timer,
+    syn_retries: u32,
+
+    unsent: VecDeque<u8>,
+    next_seq: u32,
+    inflight: BTreeMap<u32, InFlight>,
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    cwnd: u32,
+    /// acks received since cwnd last grew in congestion avoidance
+    cwnd_acks: u32,
+    ssthresh: u32,
+    peer_window: u32,
+    dup_acks: u32,
+    last_next: u32,
+    srtt: Option<Duration>,
+    rto: Duration,
+
+    expected: u32,
+    /// None marks the Fin
+    out_of_order: BTreeMap<u32, Option<Vec<u8>>>,
+    fin_received: bool,
+    ack_needed: bool,
+    /// started when the stream is finished, so that late retransmissions
+    /// are still acked instead of reset
+    linger: Option<Timer>,
+}
+
+/// every stream of a manager, owned by the manager thread
+pub struct Streams {
+    conns: HashMap<Key, Conn>,
+    listeners: HashMap<u32, Sender<Stream>>,
+    cmd_tx: Sender<Command>,
+    cmd_rx: Receiver<Command>,
+    /// packets that the manager should send: destination, stream id and payload
+    outbox: Vec<(SocketAddr, u64, Vec<u8>)>,
+}
+
+/// decodes a serialized `Segment` into something readable
+pub fn describe(payload: &[u8]) -> Option<String> {
+    deserialize::<Segment>(payload).ok().map(|s| match s {
+        Segment::Data{seq, bytes} => format!("Data {{ seq: {}, {} bytes }}", seq, bytes.len()),
+        other => format!("{:?}", other),
+    })
+}
+
+impl Shared {
+    fn new() -> Arc<Self> {
+        Arc::new(Shared{
+            unacked: AtomicUsize::new(0),
+            buffered: AtomicUsize::new(0),
+            dead: AtomicBool::new(false),
+        })
+    }
+}
+
+impl Stream {
+    fn new(key: Key, cmds: Sender<Command>, events: Receiver<Event>, shared: Arc<Shared>) -> Self {
+        Stream {
+            key,
+            cmds,
+            events,
+            shared,
+            leftover: Vec::new(),
+            pos: 0,
+            eof: false,
+            closed: false,
+            read_timeout: None,
+        }
+    }
+
+    /// the node on the other end
+    pub fn peer(&self) -> SocketAddr {
+        self.key.0
+    }
+
+    /// makes `read` give up with io::ErrorKind::TimedOut after `dur`.
+    /// None blocks forever
+    pub fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.read_timeout = dur;
+    }
+
+    /// tells the other end that we won't write anything more.
+    /// Already written data is still delivered
+    pub fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            let _ = self.cmds.send(Command::Close(self.key));
+        }
+    }
+
+    fn is_dead(&self) -> bool {
+        self.shared.dead.load(Ordering::SeqCst)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.pos >= self.leftover.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            let ev = match self.read_timeout {
+                None => self.events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                Some(t) => self.events.recv_timeout(t),
+            };
+            match ev {
+                Ok(Event::Data(d)) => {
+                    self.leftover = d;
+                    self.pos = 0;
+                }
+                Ok(Event::Closed) => self.eof = true,
+                Ok(Event::Failed(why)) => return Err(io::Error::new(io::ErrorKind::ConnectionReset, why)),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "stream read timed out"));
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "udpmanager terminated"));
+                }
+            }
+        }
+        let n = cmp::min(buf.len(), self.leftover.len() - self.pos);
+        buf[..n].copy_from_slice(&self.leftover[self.pos..self.pos + n]);
+        self.pos += n;
+        self.shared.buffered.fetch_sub(n, Ordering::SeqCst);
+        Ok(n)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "write on a closed stream"));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut unacked;
+        loop {
+            if self.is_dead() {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream is dead"));
+            }
+            unacked = self.shared.unacked.load(Ordering::SeqCst);
+            if unacked < SEND_BUFFER {
+                break;
+            }
+            thread::sleep(POLL_TIME);
+        }
+        let n = cmp::min(buf.len(), SEND_BUFFER - unacked);
+        self.shared.unacked.fetch_add(n, Ordering::SeqCst);
+        self.cmds.send(Command::Write(self.key, buf[..n].to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "udpmanager terminated"))?;
+        Ok(n)
+    }
+
+    /// blocks until everything written has been acknowledged by the other end
+    fn flush(&mut self) -> io::Result<()> {
+        while self.shared.unacked.load(Ordering::SeqCst) > 0 {
+            if self.is_dead() {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream is dead"));
+            }
+            thread::sleep(POLL_TIME);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl StreamListener {
+    /// returns a newly opened stream if there is one
+    pub fn accept(&self) -> Option<Stream> {
+        match self.rec.try_recv() {
+            Ok(s) => Some(s),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => panic!("udpmanager disconnected"),
+        }
+    }
+
+    /// blocks until another node opens a stream to us
+    pub fn accept_wait(&self) -> Stream {
+        self.rec.recv().expect("udpmanager disconnected")
+    }
+
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+}
+
+impl Drop for StreamListener {
+    fn drop(&mut self) {
+        let _ = self.cmds.send(Command::Unlisten(self.port));
+    }
+}
+
+/// starts listening for streams on `port`
+pub fn listen(cmds: &Sender<Command>, port: u32) -> StreamListener {
+    let (tx, rx) = channel();
+    cmds.send(Command::Listen(port, tx)).expect("udpmanager disconnected");
+    StreamListener{port, rec: rx, cmds: cmds.clone()}
+}
+
+/// opens a stream to whoever listens on `port` at `dest`.
+/// This doesn't wait for the other end, written data is sent once it answers
+pub fn connect(cmds: &Sender<Command>, dest: SocketAddr, port: u32) -> Stream {
+    let (tx, rx) = channel();
+    let shared = Shared::new();
+    let key = (dest, get_hash());
+    cmds.send(Command::Connect(key, port, tx, shared.clone())).expect("udpmanager disconnected");
+    Stream::new(key, cmds.clone(), rx, shared)
+}
+
+impl Conn {
+    fn new(events: Sender<Event>, shared: Arc<Shared>, syn: Option<u32>) -> Self {
+        Conn {
+            events,
+            shared,
+            established: syn.is_none(),
+            syn,
+            syn_timer: Timer::new_expired(),
+            syn_retries: RETRIES,
+            unsent: VecDeque::new(),
+            next_seq: 0,
+            inflight: BTreeMap::new(),
+            fin_queued: false,
+            fin_sent: false,
+            fin_acked: false,
+            cwnd: 1,
+            cwnd_acks: 0,
+            ssthresh: INITIAL_SSTHRESH,
+            peer_window: RECV_WINDOW,
+            dup_acks: 0,
+            last_next: 0,
+            srtt: None,
+            rto: INITIAL_RTO,
+            expected: 0,
+            out_of_order: BTreeMap::new(),
+            fin_received: false,
+            ack_needed: false,
+            linger: None,
+        }
+    }
+
+    /// is the stream done in both directions?
+    fn is_finished(&self) -> bool {
+        self.fin_acked && self.fin_received
+    }
+
+    /// how many more segments the other end may send us
+    fn window(&self) -> u32 {
+        let buffered = self.shared.buffered.load(Ordering::SeqCst) / SEGMENT_SIZE;
+        RECV_WINDOW.saturating_sub(buffered as u32 + self.out_of_order.len() as u32)
+    }
+
+    fn ack(&self) -> Segment {
+        Segment::Ack{
+            next: self.expected,
+            sacks: self.out_of_order.keys().take(MAX_SACKS).cloned().collect(),
+            window: self.window(),
+        }
+    }
+
+    /// stores an arriving data segment (None for the Fin) and delivers
+    /// everything that is now in order
+    fn receive(&mut self, seq: u32, bytes: Option<Vec<u8>>) {
+        self.ack_needed = true;
+        if seq < self.expected || seq >= self.expected + RECV_WINDOW {
+            return;
+        }
+        self.out_of_order.insert(seq, bytes);
+        while let Some(seg) = self.out_of_order.remove(&self.expected) {
+            self.expected += 1;
+            match seg {
+                Some(bytes) => {
+                    self.shared.buffered.fetch_add(bytes.len(), Ordering::SeqCst);
+                    let _ = self.events.send(Event::Data(bytes));
+                }
+                None => {
+                    self.fin_received = true;
+                    let _ = self.events.send(Event::Closed);
+                }
+            }
+        }
+    }
+
+    fn handle_ack(&mut self, next: u32, sacks: Vec<u32>, window: u32) {
+        self.peer_window = window;
+
+        let mut acked: Vec<u32> = self.inflight.range(..next).map(|(s, _)| *s).collect();
+        acked.extend(sacks.into_iter().filter(|s| self.inflight.contains_key(s)));
+
+        // later segments are sacked, but the one at `next` still hasn't arrived
+        if next == self.last_next && self.inflight.contains_key(&next) {
+            self.dup_acks += 1;
+            if self.dup_acks == DUP_ACKS {
+                // fast retransmit, something was lost but later segments got through
+                debug!("stream fast retransmit");
+                if let Some(first) = self.inflight.values_mut().next() {
+                    first.fast_retransmit = true;
+                    first.retransmitted = true;
+                }
+                self.ssthresh = cmp::max(self.cwnd / 2, 2);
+                self.cwnd = self.ssthresh;
+            }
+        } else {
+            self.dup_acks = 0;
+        }
+        self.last_next = next;
+
+        for seq in acked {
+            let f = self.inflight.remove(&seq).unwrap();
+            match f.bytes {
+                Some(ref b) => {
+                    self.shared.unacked.fetch_sub(b.len(), Ordering::SeqCst);
+                }
+                None => self.fin_acked = true,
+            }
+            if !f.retransmitted {
+                self.sample_rtt(Instant::now().duration_since(f.sent));
+            }
+            // slow start below ssthresh, additive increase above
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+            } else {
+                self.cwnd_acks += 1;
+                if self.cwnd_acks >= self.cwnd {
+                    self.cwnd_acks = 0;
+                    self.cwnd += 1;
+                }
+            }
+        }
+    }
+
+    fn sample_rtt(&mut self, sample: Duration) {
+        let srtt = match self.srtt {
+            None => sample,
+            Some(old) => (old * 7 + sample) / 8,
+        };
+        self.srtt = Some(srtt);
+        self.rto = cmp::min(cmp::max(srtt * 2, MIN_RTO), MAX_RTO);
+    }
+
+    /// sends whatever needs to be sent.
+    /// returns Err if the other end stopped answering
+    fn tick(&mut self, out: &mut Vec<Segment>) -> Result<(), &'static str> {
+        if !self.established {
+            if self.syn_timer.expired(1.0) {
+                if self.syn_retries == 0 {
+                    return Err("no answer when opening the stream");
+                }
+                self.syn_retries -= 1;
+                self.syn_timer.reset_with(self.rto);
+                out.push(Segment::Syn(self.syn.unwrap()));
+            }
+            return Ok(());
+        }
+
+        // retransmit lost segments
+        let mut lost = false;
+        for (seq, f) in self.inflight.iter_mut() {
+            if f.fast_retransmit {
+                // cwnd was already halved when the duplicate acks came in
+                f.fast_retransmit = false;
+                f.timer.reset_with(self.rto);
+                out.push(match f.bytes {
+                    Some(ref b) => Segment::Data{seq: *seq, bytes: b.clone()},
+                    None => Segment::Fin(*seq),
+                });
+            } else if f.timer.expired(1.0) {
+                if f.retries == 0 {
+                    return Err("the other end stopped acknowledging");
+                }
+                f.retries -= 1;
+                f.retransmitted = true;
+                f.timer.reset_with(cmp::min(f.timer.get_timeout() * 2, MAX_RTO));
+                out.push(match f.bytes {
+                    Some(ref b) => Segment::Data{seq: *seq, bytes: b.clone()},
+                    None => Segment::Fin(*seq),
+                });
+                lost = true;
+            }
+        }
+        if lost {
+            self.ssthresh = cmp::max(self.cwnd / 2, 2);
+            self.cwnd = 1;
+            self.cwnd_acks = 0;
+        }
+
+        // send new segments while the windows allow it.
+        // With a closed window one segment is still sent to probe it
+        let allowed = cmp::max(cmp::min(self.cwnd, self.peer_window), 1) as usize;
+        while self.inflight.len() < allowed {
+            let bytes = if !self.unsent.is_empty() {
+                let n = cmp::min(self.unsent.len(), SEGMENT_SIZE);
+                Some(self.unsent.drain(..n).collect::<Vec<u8>>())
+            } else if self.fin_queued && !self.fin_sent {
+                self.fin_sent = true;
+                None
+            } else {
+                break;
+            };
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            out.push(match bytes {
+                Some(ref b) => Segment::Data{seq, bytes: b.clone()},
+                None => Segment::Fin(seq),
+            });
+            self.inflight.insert(seq, InFlight{
+                bytes,
+                sent: Instant::now(),
+                timer: Timer::new(self.rto),
+                retries: RETRIES,
+                retransmitted: false,
+                fast_retransmit: false,
+            });
+            if self.peer_window == 0 {
+                break;
+            }
+        }
+
+        if self.ack_needed {
+            self.ack_needed = false;
+            out.push(self.ack());
+        }
+        Ok(())
+    }
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Streams::new()
+    }
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        let (tx, rx) = channel();
+        Streams {
+            conns: HashMap::new(),
+            listeners: HashMap::new(),
+            cmd_tx: tx,
+            cmd_rx: rx,
+            outbox: Vec::new(),
+        }
+    }
+
+    /// how many streams are open
+    pub fn len(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// are there no open streams?
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
+    /// the channel `Stream`s and `StreamListener`s use to reach us
+    pub fn commander(&self) -> Sender<Command> {
+        self.cmd_tx.clone()
+    }
+
+    /// takes out everything that should be sent
+    pub fn drain_outbox(&mut self) -> Vec<(SocketAddr, u64, Vec<u8>)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn push(&mut self, key: Key, seg: &Segment) {
+        self.outbox.push((key.0, key.1, serialize(seg).expect("couldn't serialize")));
+    }
+
+    /// reads instructions from streams and listeners
+    pub fn handle_commands(&mut self) {
+        loop {
+            match self.cmd_rx.try_recv() {
+                Ok(Command::Listen(port, tx)) => {
+                    if self.listeners.insert(port, tx).is_some() {
+                        warn!("stream port {} was already listened on, replaced it", port);
+                    }
+                }
+                Ok(Command::Unlisten(port)) => {
+                    self.listeners.remove(&port);
+                }
+                Ok(Command::Connect(key, port, events, shared)) => {
+                    self.conns.insert(key, Conn::new(events, shared, Some(port)));
+                }
+                Ok(Command::Write(key, data)) => {
+                    if let Some(c) = self.conns.get_mut(&key) {
+                        c.unsent.extend(data);
+                    }
+                }
+                Ok(Command::Close(key)) => {
+                    if let Some(c) = self.conns.get_mut(&key) {
+                        c.fin_queued = true;
+                    }
+                }
+                // we own a sender ourselves
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// handles a packet that was sent to STREAM_SERVICE
+    pub fn handle_packet(&mut self, sender: SocketAddr, id: u64, payload: &[u8]) {
+        let key = (sender, id);
+        let seg: Segment = match deserialize(payload) {
+            Ok(s) => s,
+            Err(_) => {
+                warn!("got a stream segment that couldn't be deserialized");
+                return;
+            }
+        };
+
+        if !self.conns.contains_key(&key) {
+            match seg {
+                Segment::Syn(port) => self.accept(key, port),
+                Segment::Rst => (),
+                _ => self.push(key, &Segment::Rst),
+            }
+            return;
+        }
+
+        let mut reply = None;
+        let mut reset = false;
+        {
+            let c = self.conns.get_mut(&key).unwrap();
+            match seg {
+                Segment::Syn(_) => reply = Some(Segment::SynAck),
+                Segment::SynAck => c.established = true,
+                Segment::Data{seq, bytes} => {
+                    c.established = true;
+                    c.receive(seq, Some(bytes));
+                }
+                Segment::Fin(seq) => {
+                    c.established = true;
+                    c.receive(seq, None);
+                }
+                Segment::Ack{next, sacks, window} => {
+                    c.established = true;
+                    c.handle_ack(next, sacks, window);
+                }
+                Segment::Rst => reset = true,
+            }
+        }
+        if let Some(r) = reply {
+            self.push(key, &r);
+        }
+        if reset {
+            debug!("stream to {} was reset by the other end", sender);
+            self.fail(key, "connection reset by the other end", false);
+        }
+    }
+
+    /// someone wants to open a stream to us
+    fn accept(&mut self, key: Key, port: u32) {
+        let (tx, rx) = channel();
+        let shared = Shared::new();
+        let stream = Stream::new(key, self.cmd_tx.clone(), rx, shared.clone());
+        let accepted = match self.listeners.get(&port) {
+            Some(listener) => listener.send(stream).is_ok(),
+            None => false,
+        };
+        if !accepted {
+            debug!("{} opened a stream to port {} where no one listens", key.0, port);
+            self.push(key, &Segment::Rst);
+            return;
+        }
+        debug!("{} opened a stream to port {}", key.0, port);
+        self.conns.insert(key, Conn::new(tx, shared, None));
+        self.push(key, &Segment::SynAck);
+    }
+
+    /// gives up on a stream, telling both the user and (maybe) the other end
+    fn fail(&mut self, key: Key, why: &'static str, tell_peer: bool) {
+        if let Some(c) = self.conns.remove(&key) {
+            c.shared.dead.store(true, Ordering::SeqCst);
+            let _ = c.events.send(Event::Failed(why));
+        }
+        if tell_peer {
+            self.push(key, &Segment::Rst);
+        }
+    }
+
+    /// retransmits, sends new data and acks, and removes finished streams
+    pub fn tick(&mut self) {
+        let mut out = Vec::new();
+        let mut failed = Vec::new();
+        let mut finished = Vec::new();
+        for (key, c) in self.conns.iter_mut() {
+            let mut segs = Vec::new();
+            match c.tick(&mut segs) {
+                Ok(()) => (),
+                Err(why) => failed.push((*key, why)),
+            }
+            if c.is_finished() {
+                match c.linger {
+                    None => c.linger = Some(Timer::new(LINGER)),
+                    Some(ref t) if t.expired(1.0) => finished.push(*key),
+                    Some(_) => (),
+                }
+            }
+            out.extend(segs.into_iter().map(|s| (*key, s)));
+        }
+        for (key, seg) in out {
+            self.push(key, &seg);
+        }
+        for (key, why) in failed {
+            warn!("stream to {} failed: {}", key.0, why);
+            self.fail(key, why, true);
+        }
+        for key in finished {
+            debug!("stream to {} finished", key.0);
+            self.conns.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    /// two stream managers connected by a fake network that hands every
+    /// packet in a batch to `net`, which may drop or reorder them
+    struct Pair {
+        a: Streams,
+        b: Streams,
+    }
+
+    impl Pair {
+        fn new() -> Self {
+            Pair{a: Streams::new(), b: Streams::new()}
+        }
+
+        fn pump<F>(&mut self, net: &mut F)
+            where F: FnMut(Vec<(SocketAddr, u64, Vec<u8>)>) -> Vec<(SocketAddr, u64, Vec<u8>)>
+        {
+            self.a.handle_commands();
+            self.b.handle_commands();
+            self.a.tick();
+            self.b.tick();
+            for (_, id, p) in net(self.a.drain_outbox()) {
+                self.b.handle_packet(addr(1), id, &p);
+            }
+            for (_, id, p) in net(self.b.drain_outbox()) {
+                self.a.handle_packet(addr(2), id, &p);
+            }
+        }
+
+        /// sends `data` from a to b through `net` and returns what b read until eof
+        fn transfer<F>(&mut self, data: &[u8], mut net: F) -> Vec<u8>
+            where F: FnMut(Vec<(SocketAddr, u64, Vec<u8>)>) -> Vec<(SocketAddr, u64, Vec<u8>)>
+        {
+            let listener = listen(&self.b.commander(), 7);
+            let mut w = connect(&self.a.commander(), addr(2), 7);
+            let mut written = 0;
+            let mut r = None;
+            let mut got = Vec::new();
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(20) {
+                if written < data.len() {
+                    written += w.write(&data[written..]).unwrap();
+                    if written == data.len() {
+                        w.close();
+                    }
+                }
+                self.pump(&mut net);
+                if r.is_none() {
+                    r = listener.accept();
+                }
+                if let Some(ref mut r) = r {
+                    r.set_read_timeout(Some(Duration::from_millis(1)));
+                    let mut buf = [0; 1024];
+                    match r.read(&mut buf) {
+                        Ok(0) => return got,
+                        Ok(n) => got.extend_from_slice(&buf[..n]),
+                        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+                        Err(e) => panic!("read failed: {}", e),
+                    }
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            panic!("transfer didn't finish, got {} of {} bytes", got.len(), data.len());
+        }
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn transfers_in_order() {
+        let d = data(10 * SEGMENT_SIZE + 17);
+        assert_eq!(Pair::new().transfer(&d, |p| p), d);
+    }
+
+    #[test]
+    fn reorders_segments() {
+        let d = data(20 * SEGMENT_SIZE);
+        let got = Pair::new().transfer(&d, |mut p| {
+            p.reverse();
+            p
+        });
+        assert_eq!(got, d);
+    }
+
+    #[test]
+    fn retransmits_lost_segments() {
+        let d = data(20 * SEGMENT_SIZE);
+        // a quarter of the packets get lost. Not every 4th, that can drop
+        // the same ack of every round trip forever
+        let mut rng = StdRng::seed_from_u64(4);
+        let got = Pair::new().transfer(&d, |p| {
+            p.into_iter().filter(|_| rng.gen_range(0, 4) != 0).collect()
+        });
+        assert_eq!(got, d);
+    }
+
+    #[test]
+    fn fast_retransmit_halves_cwnd() {
+        let (tx, _rx) = channel();
+        let mut c = Conn::new(tx, Shared::new(), None);
+        c.cwnd = 8;
+        c.unsent.extend(data(8 * SEGMENT_SIZE));
+        let mut out = Vec::new();
+        c.tick(&mut out).unwrap();
+        assert_eq!(c.inflight.len(), 8);
+
+        // segment 0 is lost, the others arrive and each gives the same ack
+        for i in 1..3 {
+            c.handle_ack(0, (1..i + 1).collect(), RECV_WINDOW);
+        }
+        let before = c.cwnd;
+        c.handle_ack(0, (1..4).collect(), RECV_WINDOW);
+        assert_eq!(c.ssthresh, before / 2);
+        let cwnd = c.cwnd;
+        assert!(cwnd < before);
+        let mut out = Vec::new();
+        c.tick(&mut out).unwrap();
+        match out[0] {
+            Segment::Data{seq, ..} => assert_eq!(seq, 0),
+            ref s => panic!("expected a retransmission, got {:?}", s),
+        }
+        // not treated as a timeout
+        assert_eq!(c.cwnd, cwnd);
+        assert_eq!(c.inflight[&0].retries, RETRIES);
+    }
+
+    #[test]
+    fn close_ends_both_directions() {
+        let mut p = Pair::new();
+        let listener = listen(&p.b.commander(), 7);
+        let mut w = connect(&p.a.commander(), addr(2), 7);
+        w.write_all(b"bye").unwrap();
+        w.close();
+        assert!(w.write(b"more").is_err());
+        let mut r = None;
+        for _ in 0..100 {
+            p.pump(&mut |p| p);
+            if r.is_none() {
+                r = listener.accept();
+            }
+        }
+        let mut r = r.expect("the stream wasn't accepted");
+        r.set_read_timeout(Some(Duration::from_millis(10)));
+        let mut got = Vec::new();
+        r.read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"bye");
+
+        r.close();
+        for _ in 0..100 {
+            p.pump(&mut |p| p);
+        }
+        assert!(p.a.conns.values().all(|c| c.is_finished()));
+        assert!(p.b.conns.values().all(|c| c.is_finished()));
+        w.flush().unwrap();
+    }
+}
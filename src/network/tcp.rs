@@ -0,0 +1,89 @@
+use super::*;
+use bincode::{deserialize, serialize};
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// frames larger than this are considered garbage
+const MAX_FRAME: usize = 64 * 1024;
+
+/// writes `msg` to `stream` prefixed with its length as a big endian u32
+pub fn write_frame<T>(stream: &mut TcpStream, msg: &T) -> Result<()>
+where
+    T: Serialize,
+{
+    let seri = serialize(msg).expect("could not serialize msg");
+    if seri.len() > MAX_FRAME {
+        error!("frame to large! {} bytes is larger than {}", seri.len(), MAX_FRAME);
+        return Err(NetworkError::NoMessage);
+    }
+    let len = seri.len() as u32;
+    let header = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    stream.write_all(&header)?;
+    stream.write_all(&seri)?;
+    Ok(())
+}
+
+/// reads one length prefixed message from `stream`.
+/// returns: Ok(message) if a whole message was read
+///          Err(NetworkError::NoMessage) if the message couldn't be deserialized
+///          Err(NetworkError::Timeout) if the read timeout of `stream` ran out
+///          Err(NetworkError::IOError(e)) if the connection broke
+pub fn read_frame<T>(stream: &mut TcpStream) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).map_err(timeout_or_io)?;
+    let len = (header[0] as usize) << 24 | (header[1] as usize) << 16 | (header[2] as usize) << 8 | header[3] as usize;
+    if len > MAX_FRAME {
+        return Err(NetworkError::Other("received a frame that was too big"));
+    }
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).map_err(timeout_or_io)?;
+    deserialize(&buf).map_err(|_| {
+        warn!("TCP: received a message that couldn't be deserialized");
+        NetworkError::NoMessage
+    })
+}
+
+/// connects to `dst`, giving up after `timeout`
+pub fn connect(dst: SocketAddr, timeout: Duration) -> Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&dst, timeout).map_err(timeout_or_io)?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// the TCP equivalent of `udp::send_with_response`. Opens a connection to `dst`,
+/// sends `msg` and waits up to `timeout` for a response that fulfills `pred`
+pub fn send_with_response<T, U, F>(msg: &T, dst: SocketAddr, timeout: Duration, pred: F) -> Result<U>
+where
+    T: Serialize,
+    U: DeserializeOwned,
+    F: Fn(&U) -> bool,
+{
+    let mut stream = connect(dst, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    write_frame(&mut stream, msg)?;
+    loop {
+        let resp = read_frame(&mut stream)?;
+        if pred(&resp) {
+            return Ok(resp);
+        }
+        debug!("pred failed");
+    }
+}
+
+/// opens a listener on the same address as a udp socket
+pub fn listen_beside(udp_adr: SocketAddr) -> Result<TcpListener> {
+    Ok(TcpListener::bind(udp_adr)?)
+}
+
+fn timeout_or_io(e: io::Error) -> NetworkError {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => NetworkError::Timeout,
+        _ => NetworkError::from(e),
+    }
+}
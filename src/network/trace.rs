@@ -0,0 +1,83 @@
+use bincode::{deserialize_from, serialize_into, ErrorKind};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// which way a traced packet went
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// one packet as seen by the udpmanager
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    pub time: SystemTime,
+    pub direction: Direction,
+    /// the source if received, the destination if sent
+    pub peer: SocketAddr,
+    pub service: u32,
+    pub id: u64,
+    pub payload: Vec<u8>,
+}
+
+/// appends `Record`s to a trace file
+pub struct Recorder {
+    out: BufWriter<File>,
+}
+
+/// reads `Record`s from a trace file one by one
+pub struct Reader {
+    inp: BufReader<File>,
+}
+
+impl Recorder {
+    /// creates (or truncates) the trace file at `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Recorder{out: BufWriter::new(File::create(path)?)})
+    }
+
+    /// writes one packet to the trace.
+    /// The trace is flushed every time so nothing is lost if we crash
+    pub fn record(&mut self, direction: Direction, peer: SocketAddr, service: u32, id: u64, payload: &[u8]) {
+        let rec = Record{
+            time: SystemTime::now(),
+            direction,
+            peer,
+            service,
+            id,
+            payload: payload.to_vec(),
+        };
+        let res = serialize_into(&mut self.out, &rec)
+            .map_err(io::Error::other)
+            .and_then(|_| self.out.flush());
+        if let Err(e) = res {
+            warn!("could not write to the trace file: {}", e);
+        }
+    }
+}
+
+impl Reader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Reader{inp: BufReader::new(File::open(path)?)})
+    }
+}
+
+impl Iterator for Reader {
+    type Item = io::Result<Record>;
+
+    /// returns None when the end of the trace has been reached
+    fn next(&mut self) -> Option<Self::Item> {
+        match deserialize_from(&mut self.inp) {
+            Ok(rec) => Some(Ok(rec)),
+            Err(e) => match *e {
+                ErrorKind::Io(ref ioe) if ioe.kind() == io::ErrorKind::UnexpectedEof => None,
+                ErrorKind::Io(ioe) => Some(Err(ioe)),
+                other => Some(Err(io::Error::new(io::ErrorKind::InvalidData, other.to_string()))),
+            },
+        }
+    }
+}
@@ -1,41 +1,143 @@
 
-use std::net::{SocketAddr,UdpSocket};
+use std::net::{SocketAddr,UdpSocket,TcpListener,TcpStream,Shutdown};
 use std::sync::mpsc::{Sender,Receiver,channel,TryRecvError};
 use common::timer::Timer;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::thread;
 use network::udp;
 use super::*;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 use bincode::{deserialize, serialize};
 use std::slice::Iter;
 use common::get_hash;
+use network::trace::{Direction, Recorder};
+use network::stream::{self, Stream, StreamListener, Streams, STREAM_SERVICE};
+use network::ratelimit::{Limiter, RateConfig};
+use network::tcp;
+use network::relay::{RelayMsg, RelayState, RELAY_SERVICE, REGISTER_INTERVAL};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 const TICKET_TTL: Duration = Duration::from_millis(150);
+/// the resend timeout doubles on every retry up to this
+const MAX_TICKET_TTL: Duration = Duration::from_millis(600);
+/// bytes a `Msg` takes on the wire besides its payload
+const MSG_OVERHEAD: usize = 20;
 const SLEEP_TIME: Duration = Duration::from_millis(30);
 const RETRIES: u32 = 3;
+/// a ticket that got no answer in this long fails, even if the send
+/// budget held it back and it used up none of its retries
+const TICKET_LIFETIME: Duration = Duration::from_millis(2000);
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+/// a TCP peer that doesn't take a frame in this long is dropped,
+/// so it can't hold up everyone else
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_millis(200);
+/// how long a new TCP connection has to say who it is
+const TCP_HELLO_TIMEOUT: Duration = Duration::from_millis(1000);
+/// the most TCP connections we keep open
+const MAX_TCP_CONNS: usize = 64;
+/// the service number of the nonce that confirms where a TCP connection
+/// is reached, see `tcp_handshake`
+const TCP_CHECK_SERVICE: u32 = u32::MAX - 2;
+/// how often the nonce is sent, and how long to wait for it each time
+const TCP_CHECK_TRIES: u32 = 5;
+const TCP_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+/// how long an address we heard from directly is kept from being routed through a relay
+const DIRECT_TTL: Duration = Duration::from_secs(60);
 
 /// manager that can handle multiple active sessions over
 /// one UDP socket. This starts in a new thread.
 /// A "session" is a one packet request to another node
 /// and a one packet response.
 /// Things that can respond to requests are called Services
-/// and active sessions are called Tickets
+/// and active sessions are called Tickets.
+/// Reliable byte streams (see `network::stream`) share the same socket.
+/// The same messages are also accepted over length prefixed TCP on the
+/// same address, for peers whose network blocks UDP.
+/// Nodes that can't be reached directly are reached through a relay,
+/// see `set_route`
 pub struct Manager {
     to_man: Sender<Request>,
+    to_streams: Sender<stream::Command>,
+    link: Link,
+    transports: Transports,
+}
+
+/// a trace file shared between the manager thread and all services
+type SharedRecorder = Arc<Mutex<Recorder>>;
+
+/// open TCP connections mapped by the address the other end is reached at.
+/// Each one has its own lock so a slow peer only holds up the writes to it
+type TcpConns = Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>>;
+
+/// destinations that have to be reached through a relay, mapped to the relay
+type Routes = Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>;
+
+/// everything needed to put a `Msg` on the wire, shared between
+/// the manager thread and all services
+struct Link {
     sock: UdpSocket,
+    tcp: TcpConns,
+    /// messages read from TCP connections are sent here
+    incoming: Sender<(SocketAddr, Msg)>,
+    routes: Routes,
+    trace: Option<SharedRecorder>,
+    /// packets and bytes sent so far
+    sent: Arc<(AtomicUsize, AtomicUsize)>,
+    limiter: Arc<Mutex<Limiter>>,
 }
 
 /// instructions that can be sent to a Manager
 enum Request {
     /// request to activate a new session
     Send(Ticket),
+    /// a response the send budget had no room for, sent when there is
+    Respond(Msg, SocketAddr),
     Service(Service),
+    Limits(RateConfig),
+    /// the node at this address accepts TCP, use it if UDP doesn't get through
+    AcceptsTcp(SocketAddr),
+    /// start relaying for nodes that register with us
+    EnableRelay,
+    /// register with a relay so that others can reach us through it
+    UseRelay(SocketAddr),
+    /// asks for the current `Stats`
+    Stats(Sender<Stats>),
     Terminate,
 }
 
+/// what the manager thread is busy with
+#[derive(Serialize, Debug, Clone)]
+pub struct Stats {
+    /// sessions waiting for a response
+    pub pending_tickets: usize,
+    pub services: usize,
+    pub streams: usize,
+    /// destinations we have fallen back to TCP for
+    pub tcp_fallbacks: usize,
+    pub relaying: bool,
+    /// the relay we are registered with, if any
+    pub relay_via: Option<SocketAddr>,
+    /// the address our relay sees us as, once it answered
+    pub relay_observed: Option<SocketAddr>,
+    pub packets_sent: usize,
+    /// payload and header bytes of the packets sent
+    pub bytes_sent: usize,
+}
+
+/// the order in which waiting tickets get to use the send budget.
+/// Tickets of the same priority are sent oldest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// keeps the network together, e.g. kademlia pings and lookups
+    High,
+    Normal,
+    /// bulk traffic that may wait
+    Low,
+}
+
 /// results from a `Ticket` sent from the manager thread.
 struct TicketResponse {
     payload: Option<Vec<u8>>,
@@ -58,6 +160,13 @@ struct Ticket {
     payload: Vec<u8>,
     dest: SocketAddr,
     service: u32,
+    priority: Priority,
+    /// fails the ticket when it runs out, see TICKET_LIFETIME
+    deadline: Timer,
+    /// the relay to send it through instead of the route of `dest`
+    via: Option<SocketAddr>,
+    /// if the destination's rate was already cut for this ticket
+    backed_off: bool,
 }
 
 /// holds the necessary info for a service
@@ -81,7 +190,8 @@ pub struct SendHandle<T> {
 /// the entry point for interacting with a local service
 pub struct ServiceHandle {
     rec: Receiver<ServiceResponse>,
-    sock: UdpSocket,
+    link: Link,
+    to_man: Sender<Request>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -95,12 +205,46 @@ struct Msg {
 impl Manager {
     /// starts a new manager on `sock`
     pub fn start(sock: UdpSocket) -> Self {
+        Manager::start_traced(sock, None)
+    }
+    /// starts a new manager on `sock` that writes every sent and
+    /// received `Msg` to `trace` (if it is Some)
+    pub fn start_traced(sock: UdpSocket, trace: Option<Recorder>) -> Self {
         let (tx, rx) = channel();
-        let sock_clone = sock.try_clone().unwrap();
+        let (tcp_tx, tcp_rx) = channel();
+        let link = Link{
+            sock,
+            tcp: Arc::new(Mutex::new(HashMap::new())),
+            incoming: tcp_tx,
+            routes: Arc::new(Mutex::new(HashMap::new())),
+            trace: trace.map(|r| Arc::new(Mutex::new(r))),
+            sent: Arc::new((AtomicUsize::new(0), AtomicUsize::new(0))),
+            limiter: Arc::new(Mutex::new(Limiter::new(RateConfig::default()))),
+        };
+
+        let mut transports = Transports::udp_only();
+        match tcp::listen_beside(link.sock.local_addr().unwrap()) {
+            Ok(listener) => {
+                let link_clone = link.try_clone();
+                thread::spawn(move || {
+                    tcp_acceptor(listener, link_clone);
+                });
+                transports.tcp = true;
+            }
+            Err(e) => warn!("couldn't listen for TCP, only UDP will work: {}", e),
+        }
+
+        let link_clone = link.try_clone();
+        let streams = Streams::new();
+        let to_streams = streams.commander();
         thread::spawn(move || {
-            manager_main(rx, sock_clone);
+            manager_main(rx, link_clone, tcp_rx, streams);
         });
-        Manager{to_man: tx, sock: sock}
+        Manager{to_man: tx, to_streams, link, transports}
+    }
+    /// the transports other nodes can reach this manager on
+    pub fn transports(&self) -> Transports {
+        self.transports
     }
     pub fn terminate(self) {
         info!("Udp Manager is terminating as per request...");
@@ -109,11 +253,73 @@ impl Manager {
     /// takes a manager and creates a new service with it
     pub fn register_service(&self, service: u32) -> ServiceHandle {
         let (tx, rx) = channel();
-        let servh = ServiceHandle{rec: rx, sock: self.sock.try_clone().unwrap()};
+        let servh = ServiceHandle{rec: rx, link: self.link.try_clone(), to_man: self.to_man.clone()};
         let ser = Service{service: service, pipe: tx};
         self.to_man.send(Request::Service(ser)).unwrap();
         servh
     }
+    /// tells the manager which transports the node at `dest` accepts.
+    /// Tickets to it fall back to TCP if it does and UDP gets no answers
+    pub fn set_transports(&self, dest: SocketAddr, transports: Transports) {
+        if transports.tcp {
+            self.to_man.send(Request::AcceptsTcp(dest)).unwrap();
+        }
+    }
+    /// makes everything sent to `dest` go through `relay`.
+    /// None removes the route
+    pub fn set_route(&self, dest: SocketAddr, relay: Option<SocketAddr>) {
+        let mut routes = self.link.routes.lock().unwrap();
+        match relay {
+            Some(r) if r != dest => {
+                routes.insert(dest, r);
+            }
+            _ => {
+                routes.remove(&dest);
+            }
+        }
+    }
+    /// lets nodes that can't be reached directly register with us,
+    /// we then forward packets to and from them
+    pub fn enable_relay(&self) {
+        self.to_man.send(Request::EnableRelay).unwrap();
+    }
+    /// keeps us registered with `relay` so that nodes that can't reach
+    /// us directly can go through it
+    pub fn use_relay(&self, relay: SocketAddr) {
+        self.to_man.send(Request::UseRelay(relay)).unwrap();
+    }
+    /// waits up to `timeout` for our relay to answer, and returns the
+    /// address it sees us as. That is the address to give to others
+    pub fn relay_address(&self, timeout: Duration) -> Option<SocketAddr> {
+        let timer = Timer::new(timeout);
+        loop {
+            if let Some(adr) = self.stats().relay_observed {
+                return Some(adr);
+            }
+            if timer.expired(1.0) {
+                return None;
+            }
+            thread::sleep(SLEEP_TIME);
+        }
+    }
+    /// changes how fast the manager may send
+    pub fn set_limits(&self, config: RateConfig) {
+        self.to_man.send(Request::Limits(config)).unwrap();
+    }
+    /// what the manager thread is busy with, blocks until it answers
+    pub fn stats(&self) -> Stats {
+        let (tx, rx) = channel();
+        self.to_man.send(Request::Stats(tx)).unwrap();
+        rx.recv().expect("the manager thread died")
+    }
+    /// accepts reliable streams that other nodes open to `port`
+    pub fn listen(&self, port: u32) -> StreamListener {
+        stream::listen(&self.to_streams, port)
+    }
+    /// opens a reliable stream to `port` on the node at `dest`
+    pub fn connect(&self, dest: SocketAddr, port: u32) -> Stream {
+        stream::connect(&self.to_streams, dest, port)
+    }
 }
 
 /// takes a service and receives a request from it.
@@ -134,22 +340,52 @@ where T: DeserializeOwned
                   .map(|de| (de, sr.source, sr.id)))
 }
 
-/// respond to a request to a service
+/// respond to a request to a service.
+/// It is sent right away if the send budget allows it, otherwise the
+/// manager sends it once there is room
 pub fn service_respond<T>(servh: &ServiceHandle, resp: &T, id: u64, to: SocketAddr) -> Result<()>
 where T: Serialize
 {
     let resp_serialized = serialize(resp).expect("could not serialize resp");
     let to_send = Msg{
         service: 0,
-        id: id,
+        id,
         payload: resp_serialized,
     };
-    udp::send(&servh.sock, &to_send, to)?;
-    Ok(())
+    if servh.link.limiter.lock().unwrap().try_send(to, to_send.payload.len() + MSG_OVERHEAD) {
+        servh.link.transmit(&to_send, to)
+    } else {
+        servh.to_man.send(Request::Respond(to_send, to))
+            .map_err(|_| NetworkError::Other("udpmanager disconnected"))
+    }
 }
 
 /// initiates a new session. Sending `msg` to all `dests` to a service `service`
 pub fn send<T,U>(man: &Manager, msg: &T, dests: Vec<SocketAddr>, service: u32) -> SendHandle<U>
+where U: DeserializeOwned,
+      T: Serialize
+{
+    send_with_priority(man, msg, dests, service, Priority::Normal)
+}
+
+/// same as `send` but the tickets wait for the send budget with `priority`
+pub fn send_with_priority<T,U>(man: &Manager, msg: &T, dests: Vec<SocketAddr>, service: u32, priority: Priority) -> SendHandle<U>
+where U: DeserializeOwned,
+      T: Serialize
+{
+    send_tickets(man, msg, dests, None, service, priority)
+}
+
+/// sends `msg` to `dest` through the relay `via` if it is Some, without
+/// making it the route of `dest`. For nodes we haven't verified yet
+pub fn send_via<T,U>(man: &Manager, msg: &T, dest: SocketAddr, via: Option<SocketAddr>, service: u32, priority: Priority) -> SendHandle<U>
+where U: DeserializeOwned,
+      T: Serialize
+{
+    send_tickets(man, msg, vec![dest], via, service, priority)
+}
+
+fn send_tickets<T,U>(man: &Manager, msg: &T, dests: Vec<SocketAddr>, via: Option<SocketAddr>, service: u32, priority: Priority) -> SendHandle<U>
 where U: DeserializeOwned,
       T: Serialize
 {
@@ -167,6 +403,10 @@ where U: DeserializeOwned,
             payload: seri.clone(),
             dest: *d,
             service: service,
+            priority: priority,
+            deadline: Timer::new(TICKET_LIFETIME),
+            backed_off: false,
+            via: via,
         };
         man.to_man.send(Request::Send(t)).unwrap();
     }
@@ -264,95 +504,766 @@ where T: DeserializeOwned
 }
 
 /// the main function of the manager thread
-fn manager_main(recv: Receiver<Request>, sock: UdpSocket) {
+fn manager_main(recv: Receiver<Request>, link: Link, tcp_rx: Receiver<(SocketAddr, Msg)>, mut streams: Streams) {
     let mut services = Vec::new();
-    let mut tickets = Vec::new();
-    udp::set_nonblocking(&sock).unwrap();
+    let mut tickets: Vec<Ticket> = Vec::new();
+    let limiter = link.limiter.clone();
+    // responses waiting for the send budget, with when they were held back
+    let mut responses: Vec<(Msg, SocketAddr, Timer)> = Vec::new();
+    let mut cleanup_timer = Timer::from_millis(1000*10);
+    // nodes that said they accept TCP, and those of them we have fallen back to
+    let mut tcp_capable = HashSet::new();
+    let mut use_tcp = HashSet::new();
+    // connections being opened on helper threads, they report back on `opened`
+    let mut connecting = HashSet::new();
+    let (opened_tx, opened) = channel();
+    let mut relay = RelayState::new();
+    // who we recently heard from directly, they are never routed through a relay
+    let mut direct: HashMap<SocketAddr, Instant> = HashMap::new();
+    udp::set_nonblocking(&link.sock).unwrap();
 
+    'main:
     loop {
         // read new stuff for the manager
-        match recv.try_recv() {
-            Ok(Request::Send(tick)) => {
-                tickets.push(tick);
-            }
-            Ok(Request::Service(ser)) => {
-                services.push(ser);
-            }
-            Ok(Request::Terminate) => {
-                break;
-            }
-            Err(TryRecvError::Empty) => (),
-            Err(TryRecvError::Disconnected) => {
-                error!("master died before us");
-                break;
+        loop {
+            match recv.try_recv() {
+                Ok(Request::Send(tick)) => {
+                    tickets.push(tick);
+                }
+                Ok(Request::Respond(msg, to)) => {
+                    responses.push((msg, to, Timer::new(MAX_TICKET_TTL)));
+                }
+                Ok(Request::Service(ser)) => {
+                    // registering a service again replaces the old handle
+                    services.retain(|s: &Service| s.service != ser.service);
+                    services.push(ser);
+                }
+                Ok(Request::Limits(config)) => {
+                    limiter.lock().unwrap().set_config(config);
+                }
+                Ok(Request::AcceptsTcp(adr)) => {
+                    tcp_capable.insert(adr);
+                }
+                Ok(Request::EnableRelay) => {
+                    info!("relaying for nodes that ask for it");
+                    relay.enabled = true;
+                }
+                Ok(Request::UseRelay(adr)) => {
+                    info!("registering with relay {}", adr);
+                    relay.use_relay(adr);
+                }
+                Ok(Request::Stats(tx)) => {
+                    tx.send(Stats{
+                        pending_tickets: tickets.len(),
+                        services: services.len(),
+                        streams: streams.len(),
+                        tcp_fallbacks: use_tcp.len(),
+                        relaying: relay.enabled,
+                        relay_via: relay.via,
+                        relay_observed: relay.observed,
+                        packets_sent: link.sent.0.load(Ordering::Relaxed),
+                        bytes_sent: link.sent.1.load(Ordering::Relaxed),
+                    }).ok();
+                }
+                Ok(Request::Terminate) => {
+                    break 'main;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    error!("master died before us");
+                    break 'main;
+                }
             }
         }
 
         // receive new things
         // TODO: timeout on this?
         loop {
-            let (sender, msg): (_, Msg) = match udp::recv_once(&sock) {
+            let (sender, msg): (_, Msg) = match udp::recv_once(&link.sock) {
                 Ok(x) => x,
                 Err(NetworkError::NoMessage) => continue,
                 Err(NetworkError::Timeout) => break,
                 Err(ioerror) => panic!(ioerror),
             };
+            receive(sender, msg, &link, &mut relay, &mut direct, &mut services, &mut tickets, &mut streams, &limiter);
+        }
+        // and whatever arrived over TCP
+        while let Ok((sender, msg)) = tcp_rx.try_recv() {
+            receive(sender, msg, &link, &mut relay, &mut direct, &mut services, &mut tickets, &mut streams, &limiter);
+        }
+        // the connections that are open are used by `send_raw` from now on
+        while let Ok((dest, res)) = opened.try_recv() {
+            connecting.remove(&dest);
+            if let Err(e) = res {
+                warn!("couldn't connect to {} over TCP: {}", dest, e);
+            }
+        }
 
-            // was sent to a service
-            if msg.service != 0 {
-                for s in services.iter() {
-                    if msg.service == s.service {
-                        s.pipe.send(ServiceResponse{
-                            payload: msg.payload,
-                            source: sender,
-                            id: msg.id
-                        }).expect("send to service failed");
-                        break;
-                    }
+        // stay registered with our relay
+        if relay.register_timer.expired(1.0) {
+            if let Some(via) = relay.via {
+                if let Err(e) = link.send_raw(&relay_msg(&RelayMsg::Register), via) {
+                    warn!("couldn't register with relay {}: {}", via, e);
                 }
-            } else { // was a response to a ticket
-                for i in (0..tickets.len()).rev() {
-                    if tickets[i].id == msg.id && sender == tickets[i].dest {
-                        tickets[i].requester.send(TicketResponse{
-                            payload: Some(msg.payload),
-                            source: sender
-                        }).expect("send as response to ticket failed");
-                        tickets.remove(i);
-                        break;
-                    }
+            }
+            relay.register_timer.reset_with(REGISTER_INTERVAL);
+        }
+
+        // responses go before tickets, they were asked for already.
+        // The asker has given up on those that waited too long
+        responses.retain(|(_, _, t)| !t.expired(1.0));
+        let mut held = Vec::new();
+        for (m, to, t) in responses.drain(..) {
+            if limiter.lock().unwrap().try_send(to, m.payload.len() + MSG_OVERHEAD) {
+                if let Err(e) = link.transmit(&m, to) {
+                    warn!("couldn't send a response to {}: {}", to, e);
                 }
+            } else {
+                held.push((m, to, t));
             }
         }
+        responses = held;
 
-        // see if a ticket needs to be sent again
+        // remove tickets that ran out of retries or time, unless the
+        // destination can be tried over TCP instead
         for i in (0..tickets.len()).rev() {
-            if tickets[i].timer.expired(1.0) {
-                if tickets[i].retries == 0 {
-                    debug!("a ticket expired");
-                    tickets[i].requester.send(TicketResponse{
-                        payload: None,
-                        source: tickets[i].dest
-                    }).expect("expired ticket pipe");
-                    tickets.remove(i);
-                } else {
-                    if tickets[i].timer.get_timeout() == Duration::from_millis(0) {
-                        // debug!("sending a ticket");
-                    } else {
-                        debug!("resending a ticket");
-                    }
-                    tickets[i].retries -= 1;
-                    tickets[i].timer.reset_with(TICKET_TTL);
-                    send_msg(&sock, tickets[i].id, tickets[i].service, &tickets[i].payload, tickets[i].dest);
+            let out_of_retries = tickets[i].timer.expired(1.0) && tickets[i].retries == 0;
+            if out_of_retries || tickets[i].deadline.expired(1.0) {
+                let dest = tickets[i].dest;
+                if tcp_capable.contains(&dest) && !use_tcp.contains(&dest) {
+                    info!("{} doesn't answer over UDP, falling back to TCP", dest);
+                    use_tcp.insert(dest);
+                    tickets[i].retries = RETRIES;
+                    tickets[i].timer = Timer::new_expired();
+                    tickets[i].deadline.reset();
+                    continue;
+                }
+                debug!("a ticket expired");
+                if !tickets[i].backed_off {
+                    limiter.lock().unwrap().on_loss(dest);
                 }
+                // the SendHandle may have been dropped, that's fine
+                tickets[i].requester.send(TicketResponse{
+                    payload: None,
+                    source: dest
+                }).ok();
+                tickets.remove(i);
+            }
+        }
+
+        // (re)send tickets whose timer ran out, as long as the budget allows it.
+        // Those that don't fit stay expired and get another chance next round
+        let mut due: Vec<usize> = (0..tickets.len()).filter(|&i| tickets[i].timer.expired(1.0)).collect();
+        due.sort_by_key(|&i| tickets[i].priority);
+        for i in due {
+            let t = &mut tickets[i];
+            let mut limiter = limiter.lock().unwrap();
+            if !limiter.try_send(t.dest, t.payload.len() + MSG_OVERHEAD) {
+                continue;
+            }
+            let timeout = t.timer.get_timeout();
+            if timeout == Duration::from_millis(0) {
+                t.timer.reset_with(TICKET_TTL);
+            } else {
+                debug!("resending a ticket");
+                // one cut per ticket, however many of its sends were lost
+                if !t.backed_off {
+                    t.backed_off = true;
+                    limiter.on_loss(t.dest);
+                }
+                t.timer.reset_with(std::cmp::min(timeout * 2, MAX_TICKET_TTL));
+            }
+            drop(limiter);
+            t.retries -= 1;
+            // connecting can take a while, until it is done UDP is tried
+            if use_tcp.contains(&t.dest) && !link.has_tcp(&t.dest) && connecting.insert(t.dest) {
+                let (dest, link, opened_tx) = (t.dest, link.try_clone(), opened_tx.clone());
+                thread::spawn(move || {
+                    let _ = opened_tx.send((dest, link.open_tcp(dest)));
+                });
+            }
+            let m = Msg{id: t.id, service: t.service, payload: t.payload.clone()};
+            if let Err(e) = link.transmit_via(&m, t.dest, t.via) {
+                warn!("couldn't send a ticket to {}: {}", t.dest, e);
+            }
+        }
+
+        // move the streams forward, they do their own congestion control
+        // but still count against the budget
+        streams.handle_commands();
+        streams.tick();
+        for (dest, id, payload) in streams.drain_outbox() {
+            limiter.lock().unwrap().charge(dest, payload.len() + MSG_OVERHEAD);
+            let m = Msg{id, service: STREAM_SERVICE, payload};
+            if let Err(e) = link.transmit(&m, dest) {
+                warn!("couldn't send a stream segment to {}: {}", dest, e);
             }
         }
 
+        if cleanup_timer.expired(1.0) {
+            limiter.lock().unwrap().cleanup();
+            relay.cleanup();
+            direct.retain(|_, t| t.elapsed() < DIRECT_TTL);
+            cleanup_timer.reset();
+        }
+
         thread::sleep(SLEEP_TIME);
     }
     info!("Udp Manager terminated");
 }
 
-fn send_msg(sock: &UdpSocket, id: u64, service: u32, payload: &Vec<u8>, dest: SocketAddr) {
-    udp::send(sock, &Msg{id: id, service: service, payload: payload.clone()}, dest).expect("udp::send error in udpmanager");
+/// handles a `Msg` that just arrived from the network.
+/// Relay packets are unwrapped or forwarded, everything else is dispatched
+#[allow(clippy::too_many_arguments)]
+fn receive(sender: SocketAddr,
+           msg: Msg,
+           link: &Link,
+           relay: &mut RelayState,
+           direct: &mut HashMap<SocketAddr, Instant>,
+           services: &mut Vec<Service>,
+           tickets: &mut Vec<Ticket>,
+           streams: &mut Streams,
+           limiter: &Mutex<Limiter>)
+{
+    // the nonce of `tcp_handshake`, it goes back through our connection to
+    // `sender` to show that the connection is ours
+    if msg.service == TCP_CHECK_SERVICE {
+        if link.has_tcp(&sender) {
+            let _ = link.send_raw(&msg, sender);
+        }
+        return;
+    }
+    if msg.service != RELAY_SERVICE {
+        direct.insert(sender, Instant::now());
+        record(&link.trace, Direction::Received, sender, &msg);
+        dispatch(sender, msg, services, tickets, streams, limiter);
+        return;
+    }
+
+    let rmsg: RelayMsg = match deserialize(&msg.payload) {
+        Ok(r) => r,
+        Err(_) => {
+            warn!("got a relay message that couldn't be deserialized");
+            return;
+        }
+    };
+    match rmsg {
+        RelayMsg::Register => {
+            if relay.enabled {
+                relay.add_client(sender);
+                let _ = link.send_raw(&relay_msg(&RelayMsg::Registered(sender)), sender);
+            } else {
+                debug!("{} wanted us to relay but we don't", sender);
+            }
+        }
+        RelayMsg::Registered(observed) => {
+            if relay.via == Some(sender) && !relay.registered {
+                info!("registered with relay {}, it sees us as {}", sender, observed);
+                relay.registered = true;
+                relay.observed = Some(observed);
+            }
+        }
+        RelayMsg::Forward{to, inner} => {
+            if relay.may_forward(sender, to) {
+                // both ends use up our budget, so relaying can't be used to
+                // send more than we would send ourselves
+                let size = inner.len() + MSG_OVERHEAD;
+                {
+                    let mut limiter = limiter.lock().unwrap();
+                    if !limiter.try_send(to, size) {
+                        debug!("no budget to relay from {} to {}", sender, to);
+                        return;
+                    }
+                    limiter.charge(sender, size);
+                }
+                relay.forwarded(sender, to);
+                let deliver = RelayMsg::Deliver{from: sender, inner};
+                if let Err(e) = link.send_raw(&relay_msg(&deliver), to) {
+                    warn!("couldn't relay from {} to {}: {}", sender, to, e);
+                }
+            } else {
+                debug!("refused to relay from {} to {}", sender, to);
+            }
+        }
+        RelayMsg::Deliver{from, inner} => {
+            let inner: Msg = match deserialize(&inner) {
+                Ok(m) => m,
+                Err(_) => {
+                    warn!("got a relayed message that couldn't be deserialized");
+                    return;
+                }
+            };
+            // only the relay we registered with, or the one we reach `from`
+            // through ourselves, may speak for it. Anyone else could take
+            // over its traffic
+            let ours = relay.via == Some(sender);
+            let mut routes = link.routes.lock().unwrap();
+            let trusted = ours
+                || routes.get(&from) == Some(&sender)
+                || tickets.iter().any(|t| t.dest == from && t.via == Some(sender));
+            if !trusted {
+                debug!("{} delivered something from {} that we don't reach through it", sender, from);
+                return;
+            }
+            // answer the same way it came, unless `from` can be reached directly
+            if ours && !direct.contains_key(&from) {
+                routes.entry(from).or_insert(sender);
+            }
+            drop(routes);
+            record(&link.trace, Direction::Received, from, &inner);
+            dispatch(from, inner, services, tickets, streams, limiter);
+        }
+    }
+}
+
+/// wraps a `RelayMsg` in a `Msg`
+fn relay_msg(r: &RelayMsg) -> Msg {
+    Msg{service: RELAY_SERVICE, id: 0, payload: serialize(r).expect("couldn't serialize")}
+}
+
+/// hands a received `Msg` to whoever is waiting for it
+fn dispatch(sender: SocketAddr,
+            msg: Msg,
+            services: &mut Vec<Service>,
+            tickets: &mut Vec<Ticket>,
+            streams: &mut Streams,
+            limiter: &Mutex<Limiter>)
+{
+    if msg.service == STREAM_SERVICE {
+        streams.handle_packet(sender, msg.id, &msg.payload);
+    } else if msg.service != 0 { // was sent to a service
+        if let Some(i) = services.iter().position(|s| s.service == msg.service) {
+            let gone = services[i].pipe.send(ServiceResponse{
+                payload: msg.payload,
+                source: sender,
+                id: msg.id
+            }).is_err();
+            if gone {
+                // its ServiceHandle was dropped
+                debug!("service {} is gone", msg.service);
+                services.remove(i);
+            }
+        }
+    } else { // was a response to a ticket
+        for i in (0..tickets.len()).rev() {
+            if tickets[i].id == msg.id && sender == tickets[i].dest {
+                limiter.lock().unwrap().on_success(sender);
+                tickets[i].requester.send(TicketResponse{
+                    payload: Some(msg.payload),
+                    source: sender
+                }).ok();
+                tickets.remove(i);
+                break;
+            }
+        }
+    }
+}
+
+impl Link {
+    fn try_clone(&self) -> Link {
+        Link{
+            sock: self.sock.try_clone().unwrap(),
+            tcp: self.tcp.clone(),
+            incoming: self.incoming.clone(),
+            routes: self.routes.clone(),
+            trace: self.trace.clone(),
+            sent: self.sent.clone(),
+            limiter: self.limiter.clone(),
+        }
+    }
+
+    fn has_tcp(&self, adr: &SocketAddr) -> bool {
+        self.tcp.lock().unwrap().contains_key(adr)
+    }
+
+    /// sends `msg` to `dest`, through its relay if it has one
+    fn transmit(&self, msg: &Msg, dest: SocketAddr) -> Result<()> {
+        self.transmit_via(msg, dest, None)
+    }
+
+    /// sends `msg` to `dest` through `via`, or the route of `dest` if None
+    fn transmit_via(&self, msg: &Msg, dest: SocketAddr, via: Option<SocketAddr>) -> Result<()> {
+        let relay = via.or_else(|| self.routes.lock().unwrap().get(&dest).cloned());
+        match relay {
+            Some(r) => {
+                let fwd = RelayMsg::Forward{to: dest, inner: serialize(msg).expect("couldn't serialize")};
+                self.send_raw(&relay_msg(&fwd), r)?;
+            }
+            None => self.send_raw(msg, dest)?,
+        }
+        record(&self.trace, Direction::Sent, dest, msg);
+        self.sent.0.fetch_add(1, Ordering::Relaxed);
+        self.sent.1.fetch_add(msg.payload.len() + MSG_OVERHEAD, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// sends `msg` over TCP if there is a connection to `dest`, otherwise over UDP
+    fn send_raw(&self, msg: &Msg, dest: SocketAddr) -> Result<()> {
+        let conn = self.tcp.lock().unwrap().get(&dest).cloned();
+        match conn {
+            Some(conn) => {
+                let res = tcp::write_frame(&mut conn.lock().unwrap(), msg);
+                if res.is_err() {
+                    // a frame may be half written, the connection is no good anymore
+                    drop_tcp(&self.tcp, dest, &conn);
+                }
+                res?
+            }
+            None => {
+                udp::send(&self.sock, msg, dest)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// connects to `dest` over TCP, later messages to it will use the connection
+    fn open_tcp(&self, dest: SocketAddr) -> Result<()> {
+        let mut stream = tcp::connect(dest, TCP_CONNECT_TIMEOUT)?;
+        // tell it where we are reached, the connection comes from another port
+        stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT))?;
+        tcp::write_frame(&mut stream, &self.sock.local_addr()?)?;
+        self.add_tcp(dest, stream, None)
+    }
+
+    /// remembers an open connection to `peer` and starts reading from it.
+    /// A connection we already have to `peer` is never replaced.
+    /// If `claim` is Some the connection is known by the claimed address
+    /// once the nonce sent there comes back through it
+    fn add_tcp(&self, peer: SocketAddr, stream: TcpStream, claim: Option<(SocketAddr, u64)>) -> Result<()> {
+        stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT))?;
+        stream.set_read_timeout(None)?;
+        let reader = stream.try_clone()?;
+        let conn = Arc::new(Mutex::new(stream));
+        {
+            let mut conns = self.tcp.lock().unwrap();
+            if conns.contains_key(&peer) {
+                return Err(NetworkError::Other("there already is a TCP connection to there"));
+            }
+            conns.insert(peer, conn.clone());
+        }
+        let conns = self.tcp.clone();
+        let incoming = self.incoming.clone();
+        thread::spawn(move || {
+            tcp_reader(reader, peer, conn, conns, incoming, claim);
+        });
+        Ok(())
+    }
+}
+
+/// makes `conn`, known by `from` so far, the connection to `adr`.
+/// It replaces whatever connection `adr` had, that one may not be theirs.
+/// Returns false if `conn` was dropped in the meantime
+fn confirm_tcp(conns: &TcpConns, from: SocketAddr, adr: SocketAddr, conn: &Arc<Mutex<TcpStream>>) -> bool {
+    let old = {
+        let mut conns = conns.lock().unwrap();
+        if !conns.get(&from).is_some_and(|c| Arc::ptr_eq(c, conn)) {
+            return false;
+        }
+        conns.remove(&from);
+        conns.insert(adr, conn.clone())
+    };
+    if let Some(old) = old {
+        let _ = old.lock().unwrap().shutdown(Shutdown::Both);
+    }
+    true
+}
+
+/// forgets the connection to `peer` if it is still `conn` and closes it
+fn drop_tcp(conns: &TcpConns, peer: SocketAddr, conn: &Arc<Mutex<TcpStream>>) {
+    let mut conns = conns.lock().unwrap();
+    if conns.get(&peer).is_some_and(|c| Arc::ptr_eq(c, conn)) {
+        conns.remove(&peer);
+    }
+    if let Ok(s) = conn.try_lock() {
+        let _ = s.shutdown(Shutdown::Both);
+    }
+}
+
+/// accepts TCP connections from other nodes until the manager is gone.
+/// Each one says hello on a thread of its own, see `tcp_handshake`,
+/// so a connection that says nothing doesn't hold up the others
+fn tcp_acceptor(listener: TcpListener, link: Link) {
+    // connections that haven't said hello yet
+    let greeting = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let (s, peer) = match stream.and_then(|s| s.peer_addr().map(|p| (s, p))) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("couldn't accept a TCP connection: {}", e);
+                continue;
+            }
+        };
+        if link.tcp.lock().unwrap().len() + greeting.load(Ordering::Relaxed) >= MAX_TCP_CONNS {
+            warn!("too many TCP connections, refused {}", peer);
+            continue;
+        }
+        greeting.fetch_add(1, Ordering::Relaxed);
+        let link = link.try_clone();
+        let greeting = greeting.clone();
+        thread::spawn(move || {
+            tcp_handshake(s, peer, &link, &greeting);
+        });
+    }
+}
+
+/// reads the hello of the connection `s` from `peer`, which says which
+/// address its node is reached at. Until a nonce sent there over UDP
+/// comes back through the connection it is known by the address it
+/// connects from, so anyone else on the host can't take over the
+/// traffic of that node
+fn tcp_handshake(mut s: TcpStream, peer: SocketAddr, link: &Link, greeting: &AtomicUsize) {
+    let hello = s.set_read_timeout(Some(TCP_HELLO_TIMEOUT)).map_err(NetworkError::from)
+        .and_then(|_| tcp::read_frame::<SocketAddr>(&mut s));
+    greeting.fetch_sub(1, Ordering::Relaxed);
+    let claim = match hello {
+        Ok(adr) if adr.ip() == peer.ip() => Some((adr, get_hash())),
+        Ok(adr) => {
+            debug!("{} says it is reached at {}, using where it connects from", peer, adr);
+            None
+        }
+        Err(e) => {
+            debug!("{} connected over TCP but didn't say who it is: {}", peer, e);
+            return;
+        }
+    };
+    debug!("{} connected over TCP", peer);
+    if let Err(e) = link.add_tcp(peer, s, claim) {
+        warn!("couldn't use the TCP connection from {}: {}", peer, e);
+        return;
+    }
+    if let Some((adr, nonce)) = claim {
+        let check = Msg{service: TCP_CHECK_SERVICE, id: nonce, payload: Vec::new()};
+        for _ in 0..TCP_CHECK_TRIES {
+            // confirmed or closed
+            if !link.has_tcp(&peer) {
+                break;
+            }
+            if let Err(e) = udp::send(&link.sock, &check, adr) {
+                debug!("couldn't ask {} whether {} is theirs: {}", adr, peer, e);
+            }
+            thread::sleep(TCP_CHECK_INTERVAL);
+        }
+    }
+}
+
+/// reads messages from one TCP connection until it closes.
+/// If the nonce of `claim` comes back the messages are from the claimed address
+fn tcp_reader(mut stream: TcpStream,
+              mut peer: SocketAddr,
+              conn: Arc<Mutex<TcpStream>>,
+              conns: TcpConns,
+              incoming: Sender<(SocketAddr, Msg)>,
+              mut claim: Option<(SocketAddr, u64)>)
+{
+    loop {
+        match tcp::read_frame::<Msg>(&mut stream) {
+            Ok(msg) if msg.service == TCP_CHECK_SERVICE => {
+                match claim {
+                    Some((adr, nonce)) if msg.id == nonce => {
+                        if !confirm_tcp(&conns, peer, adr, &conn) {
+                            break;
+                        }
+                        debug!("the TCP connection from {} is reached at {}", peer, adr);
+                        peer = adr;
+                        claim = None;
+                    }
+                    _ => debug!("{} sent a TCP check we didn't ask for", peer),
+                }
+            }
+            Ok(msg) => {
+                if incoming.send((peer, msg)).is_err() {
+                    break;
+                }
+            }
+            Err(NetworkError::NoMessage) => continue,
+            Err(_) => break,
+        }
+    }
+    debug!("TCP connection to {} closed", peer);
+    drop_tcp(&conns, peer, &conn);
+}
+
+/// whether `msg` sent to a service fits in one packet, even when it
+/// goes through a relay
+pub fn will_fit<T>(msg: &T) -> bool
+where T: Serialize
+{
+    let inner = Msg{service: 0, id: 0, payload: serialize(msg).expect("couldn't serialize")};
+    // the longest address there is
+    let to = SocketAddr::from(([0u16; 8], 0));
+    let fwd = RelayMsg::Forward{to, inner: serialize(&inner).expect("couldn't serialize")};
+    udp::will_fit(&relay_msg(&fwd))
 }
 
+/// sends a raw `Msg` to `dest` without expecting anything back.
+/// Used to replay recorded traffic into a node
+pub fn send_msg(sock: &UdpSocket, id: u64, service: u32, payload: &[u8], dest: SocketAddr) -> Result<()> {
+    udp::send(sock, &Msg{id, service, payload: payload.to_vec()}, dest)?;
+    Ok(())
+}
+
+/// writes `msg` to the trace if tracing is enabled
+fn record(trace: &Option<SharedRecorder>, dir: Direction, peer: SocketAddr, msg: &Msg) {
+    if let Some(ref t) = *trace {
+        t.lock().unwrap().record(dir, peer, msg.service, msg.id, &msg.payload);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> Manager {
+        Manager::start(UdpSocket::bind("127.0.0.1:0").unwrap())
+    }
+
+    fn wait_for<T, F: FnMut() -> Option<T>>(mut f: F) -> Option<T> {
+        let start = std::time::Instant::now();
+        while start.elapsed() < Duration::from_secs(3) {
+            if let Some(x) = f() {
+                return Some(x);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    #[test]
+    fn relay_tells_the_observed_address() {
+        let relay = manager();
+        let a = manager();
+        relay.enable_relay();
+        a.use_relay(relay.link.sock.local_addr().unwrap());
+        assert_eq!(a.relay_address(Duration::from_secs(3)), Some(a.link.sock.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn deliver_only_from_our_relay() {
+        let relay = manager();
+        let a = manager();
+        let stranger = manager();
+        let relay_adr = relay.link.sock.local_addr().unwrap();
+        let a_adr = a.link.sock.local_addr().unwrap();
+        relay.enable_relay();
+        a.use_relay(relay_adr);
+        a.relay_address(Duration::from_secs(3)).unwrap();
+        let servh = a.register_service(7);
+
+        // someone that isn't our relay claims to deliver from `victim`
+        let victim: SocketAddr = "10.1.2.3:4000".parse().unwrap();
+        let inner = serialize(&Msg{service: 7, id: 1, payload: serialize(&1u32).unwrap()}).unwrap();
+        stranger.link.send_raw(&relay_msg(&RelayMsg::Deliver{from: victim, inner}), a_adr).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(service_get::<u32>(&servh).is_none());
+        assert!(a.link.routes.lock().unwrap().get(&victim).is_none());
+
+        // through our relay it arrives, and answers go back the same way
+        let b = manager();
+        let b_adr = b.link.sock.local_addr().unwrap();
+        let fwd = RelayMsg::Forward{to: a_adr, inner: serialize(&Msg{service: 7, id: 2, payload: serialize(&2u32).unwrap()}).unwrap()};
+        b.link.send_raw(&relay_msg(&fwd), relay_adr).unwrap();
+        let got = wait_for(|| service_get::<u32>(&servh)).expect("nothing was relayed");
+        assert_eq!((got.0, got.1), (2, b_adr));
+        assert_eq!(a.link.routes.lock().unwrap().get(&b_adr), Some(&relay_adr));
+
+        // and the answer to `b` comes back from a relay that isn't its own
+        let servb = b.register_service(8);
+        b.set_route(a_adr, Some(relay_adr));
+        a.link.transmit(&Msg{service: 8, id: 3, payload: serialize(&3u32).unwrap()}, b_adr).unwrap();
+        let got = wait_for(|| service_get::<u32>(&servb)).expect("the answer wasn't relayed");
+        assert_eq!((got.0, got.1), (3, a_adr));
+    }
+
+    #[test]
+    fn clients_only_reach_those_that_reached_them() {
+        let relay = manager();
+        let a = manager();
+        let b = manager();
+        let relay_adr = relay.link.sock.local_addr().unwrap();
+        let a_adr = a.link.sock.local_addr().unwrap();
+        let b_adr = b.link.sock.local_addr().unwrap();
+        relay.enable_relay();
+        a.use_relay(relay_adr);
+        a.relay_address(Duration::from_secs(3)).unwrap();
+        let servb = b.register_service(7);
+        let fwd = |n: u32| RelayMsg::Forward{to: b_adr, inner: serialize(&Msg{service: 7, id: n as u64, payload: serialize(&n).unwrap()}).unwrap()};
+
+        // `b` would take it, but never reached `a` so the relay doesn't send there
+        b.set_route(a_adr, Some(relay_adr));
+        a.link.send_raw(&relay_msg(&fwd(1)), relay_adr).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(service_get::<u32>(&servb).is_none());
+
+        // once it did, `a` can answer
+        let sera = a.register_service(8);
+        let hello = RelayMsg::Forward{to: a_adr, inner: serialize(&Msg{service: 8, id: 2, payload: serialize(&2u32).unwrap()}).unwrap()};
+        b.link.send_raw(&relay_msg(&hello), relay_adr).unwrap();
+        wait_for(|| service_get::<u32>(&sera)).expect("nothing was relayed");
+        a.link.send_raw(&relay_msg(&fwd(3)), relay_adr).unwrap();
+        let got = wait_for(|| service_get::<u32>(&servb)).expect("the answer wasn't relayed");
+        assert_eq!((got.0, got.1), (3, a_adr));
+    }
+
+    #[test]
+    fn tcp_connection_is_known_by_the_advertised_address() {
+        let a = manager();
+        let b = manager();
+        let a_adr = a.link.sock.local_addr().unwrap();
+        let b_adr = b.link.sock.local_addr().unwrap();
+        let servh = b.register_service(7);
+
+        a.link.open_tcp(b_adr).unwrap();
+        wait_for(|| b.link.has_tcp(&a_adr).then_some(())).expect("the connection wasn't confirmed");
+        let m = Msg{service: 7, id: 1, payload: serialize(&42u32).unwrap()};
+        a.link.send_raw(&m, b_adr).unwrap();
+
+        let start = std::time::Instant::now();
+        let got = loop {
+            if let Some(got) = service_get::<u32>(&servh) {
+                break got;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "nothing arrived over TCP");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(got.0, 42);
+        assert_eq!(got.1, a_adr);
+        assert!(b.link.has_tcp(&a_adr));
+    }
+
+    #[test]
+    fn tcp_connection_cant_claim_someone_else() {
+        let a = manager();
+        let b = manager();
+        let a_adr = a.link.sock.local_addr().unwrap();
+        let b_adr = b.link.sock.local_addr().unwrap();
+        a.link.open_tcp(b_adr).unwrap();
+        wait_for(|| b.link.has_tcp(&a_adr).then_some(())).expect("the connection wasn't confirmed");
+        let theirs = b.link.tcp.lock().unwrap().get(&a_adr).cloned().unwrap();
+
+        // another process on the host says it is `a`, but never sees the nonce
+        let mut s = tcp::connect(b_adr, TCP_CONNECT_TIMEOUT).unwrap();
+        tcp::write_frame(&mut s, &a_adr).unwrap();
+        let fake = s.local_addr().unwrap();
+        wait_for(|| b.link.has_tcp(&fake).then_some(())).expect("the connection wasn't accepted");
+        thread::sleep(TCP_CHECK_INTERVAL * TCP_CHECK_TRIES);
+
+        let conns = b.link.tcp.lock().unwrap();
+        assert!(Arc::ptr_eq(&conns[&a_adr], &theirs));
+        assert!(conns.contains_key(&fake));
+    }
+
+    #[test]
+    fn silent_tcp_connections_dont_hold_up_others() {
+        let a = manager();
+        let b = manager();
+        let a_adr = a.link.sock.local_addr().unwrap();
+        let b_adr = b.link.sock.local_addr().unwrap();
+        // each would hold up the accepts for TCP_HELLO_TIMEOUT
+        let _silent: Vec<TcpStream> = (0..5).map(|_| tcp::connect(b_adr, TCP_CONNECT_TIMEOUT).unwrap()).collect();
+        let start = Instant::now();
+        a.link.open_tcp(b_adr).unwrap();
+        wait_for(|| b.link.has_tcp(&a_adr).then_some(())).expect("the connection wasn't confirmed");
+        assert!(start.elapsed() < TCP_HELLO_TIMEOUT);
+    }
+}
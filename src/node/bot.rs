@@ -1,4 +1,5 @@
 use std::ops::Add;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -7,7 +8,7 @@ use rand::{thread_rng, Rng};
 
 use node::nethandle::NetHandle;
 
-pub fn bot_main(neth: NetHandle) {
+pub fn bot_main(neth: Arc<Mutex<NetHandle>>) {
     const MIN_WAIT_MS: u64 = 1_000;
     const MAX_WAIT_MS: u64 = 10_000;
     const SLEEP_MS: u64 = 500;
@@ -19,7 +20,7 @@ pub fn bot_main(neth: NetHandle) {
 
     loop {
         loop {
-            match neth.read() {
+            match neth.lock().unwrap().read() {
                 Ok(Some(_)) => {}
                 Ok(None) => break,
                 Err(e) => {
@@ -35,7 +36,7 @@ pub fn bot_main(neth: NetHandle) {
             let message = cnt.to_string();
             log::debug!("BOT: sending message `{}`", message);
 
-            match neth.send_message(message) {
+            match neth.lock().unwrap().send_message(message) {
                 Ok(_) => {}
                 Err(e) => {
                     log::error!("{:?}", e);
@@ -1,44 +1,137 @@
 
-use node::ktable::{Ktable, Entry};
+use node::ktable::Ktable;
+use node::overlay::Overlay;
 use std::net::SocketAddr;
 use node::cache::Cache;
+use node::causal::{CausalQueue, MAX_DEPS};
+use node::history::History;
+use node::presence::Announcement;
 use network::udpmanager as UM;
 use common::get_hash;
 use common::id::Id;
-use super::{Message,FromNetMsg};
+use common::identity::Identity;
+use common::roomkey::{RoomKeys, Sealed};
+use super::{Message,FromNetMsg,SendStatus};
 use std::sync::{Mutex, Arc};
 use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use std::thread;
 use common::timer::Timer;
-use std::collections::HashMap;
-
-const MAX_CONNECTIONS: u32 = 3;
-
-/// handles everything that has to do with the broadcast network
+use std::collections::{HashMap, HashSet};
+use rand::{thread_rng, Rng};
+use common::hlc::Timestamp;
+use bincode::{deserialize, serialize};
+
+/// the longest message text that is sent, see `fits`
+pub const MAX_MESSAGE_LEN: usize = 80;
+/// how often we compare recent messages with a connected peer
+const DIGEST_MS: u64 = 5 * 1000;
+/// how many message ids a digest or a request for messages holds,
+/// so it fits in one packet
+const DIGEST_IDS: usize = 40;
+/// how long we wait for a message we heard of to be pushed to us
+/// before we ask for it
+const IHAVE_TIMEOUT_MS: u64 = 500;
+/// how long we wait before sending one of our messages again that reached no one
+const RETRY_MS: u64 = 3 * 1000;
+/// how often we try to send one of our messages before giving up on it
+const MAX_TRIES: u32 = 5;
+/// how long we keep counting the acks of our messages, and passing on
+/// the acks of others
+const ACK_WINDOW: Duration = Duration::from_secs(60);
+/// how many message ids are remembered to drop duplicates of messages
+const MSG_CACHE: usize = 1000;
+/// how many hashes of other packets are remembered, these come a lot more
+/// often so they have a cache of their own and don't push the messages out
+const PACKET_CACHE: usize = 1000;
+/// how many acks fit in one packet
+const ACKS_PER_PACKET: usize = 32;
+/// how long acks are collected before they are passed on, so the
+/// ones from further down the tree go up together
+const ACK_DELAY_MS: u64 = 500;
+
+/// handles everything that has to do with the broadcast network.
+/// Messages are pushed along a tree in the active view of the overlay,
+/// the other active peers only get the ids and ask for what they miss (Plumtree).
+/// A peer we get a message from twice is moved off the tree
 pub struct BroadcastManager<'a> {
-    connected: HashMap<SocketAddr, Id>,
+    overlay: Overlay<'a>,
+    /// ids of the messages we have seen
     cache: Cache<u64>,
+    /// hashes of the other packets we have seen
+    packets: Cache<u64>,
     active: Vec<(Msg, UM::SendHandle<()>)>,
     ktable: Arc<Mutex<Ktable>>,
     service: UM::ServiceHandle,
     udpman: &'a UM::Manager,
     chan_out: Sender<FromNetMsg>,
     my_id: Id,
-    ting_timer: Timer,
-    ting_cur: Option<Ting>,
+    room_id: Id,
+    keys: RoomKeys,
+    /// the broadcast service of the room
+    service_no: u32,
+    causal: CausalQueue,
+    history: Arc<Mutex<History>>,
+    digest_timer: Timer,
+    /// when we joined, nothing before it is missed
+    joined: Timestamp,
+    /// active peers that only get the ids of messages, the others get them pushed
+    lazy: HashSet<SocketAddr>,
+    /// ids to announce to lazy peers at the end of the update
+    announce: HashMap<SocketAddr, Vec<u64>>,
+    /// messages we heard of but didn't get yet, with the peers that have them
+    missing: HashMap<u64, (Vec<SocketAddr>, Timer)>,
+    /// push every message to every active peer instead
+    flood: bool,
+    /// announcements received since `take_presence` was last called
+    presence: Vec<Announcement>,
+    /// our own messages we are still sending or counting acks for
+    mine: HashMap<u64, Outgoing>,
+    /// who pushed the messages of others to us, acks go back the same way
+    parents: HashMap<u64, (SocketAddr, Instant)>,
+    /// how many members got each message of others that we haven't told
+    /// its parent yet, passed on when the timer expires
+    acks: HashMap<u64, (usize, Timer)>,
 }
 
-struct Ting {
-    dst: Entry,
-    timer: Timer,
-    sendh: UM::SendHandle<()>,
-    sendh_done: bool
+/// one of our own messages, until it reached someone or we gave up
+struct Outgoing {
+    msg: Message,
+    /// the peers it was pushed to
+    sent: HashSet<SocketAddr>,
+    tries: u32,
+    retry: Timer,
+    /// how many members got it, as the acks say
+    acked: usize,
+    since: Instant,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum MsgPayload {
-    IsAlive(Id),
     Msg(Message),
-    Ting,
+    /// the ids of the sender's recent messages, and the earliest clock among
+    /// them. Answered with the messages the sender lacks and a `Want`
+    /// for the ones we lack
+    Digest(Timestamp, Vec<u64>),
+    /// asks for the messages with these ids
+    Want(Vec<u64>),
+    /// a message someone missed, delivered but not passed on
+    Repair(Message),
+    /// the sender has these messages, ask for them if they don't arrive
+    IHave(Vec<u64>),
+    /// asks for these messages, and for them to be pushed from now on
+    IWant(Vec<u64>),
+    /// the sender got a message twice and stops pushing messages to us
+    Prune,
+    /// someone is in the room, passed on like a message but to every active peer
+    Presence(Announcement),
+    /// someone is still in the room, only pushed along the tree. One that
+    /// gets lost is made up for by the next
+    Heartbeat(Announcement),
+    /// how many members below the sender on the tree got these messages.
+    /// Sent to who pushed a message to us, adding up until it reaches its
+    /// sender. They aren't signed, so a member of the room could make them up
+    Ack(Vec<(u64, u32)>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,31 +141,93 @@ struct Msg {
     payload: MsgPayload,
 }
 
+/// a `Msg` as it is sent, with the payload sealed with the room key
+/// so only the members of the room can read it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Packet {
+    hash: u64,
+    sender_id: Id,
+    payload: Sealed,
+}
+
+/// decodes a serialized broadcast `Packet` into something readable,
+/// the payload stays sealed
+pub fn describe(payload: &[u8]) -> Option<String> {
+    deserialize::<Packet>(payload).ok().map(|m| format!("{:?}", m))
+}
+
+/// what the payload is bound to, so it can't be sent under another hash
+fn packet_ad(hash: u64, sender_id: Id) -> Vec<u8> {
+    serialize(&(hash, sender_id)).unwrap()
+}
+
+/// `msg` as it goes on the wire, sealed with the current key of `keys`
+fn seal(keys: &RoomKeys, msg: &Msg) -> Packet {
+    let plain = serialize(&msg.payload).unwrap();
+    Packet {
+        hash: msg.hash,
+        sender_id: msg.sender_id,
+        payload: keys.seal(&plain, &packet_ad(msg.hash, msg.sender_id)),
+    }
+}
+
+/// whether `msg` fits in one packet once it is signed and has as many
+/// dependencies as it can get, so it is never too big to send.
+/// The text and the name of the sender are what makes it bigger
+pub fn fits(keys: &RoomKeys, msg: &Message, identity: &Identity) -> bool {
+    let mut m = msg.clone();
+    m.deps = vec![(0, Timestamp::default()); MAX_DEPS];
+    m.sign(identity);
+    let hash = m.id;
+    UM::will_fit(&seal(keys, &Msg{hash, payload: MsgPayload::Msg(m), sender_id: identity.id()}))
+}
+
 impl<'a> BroadcastManager<'a> {
     pub fn new(
         ktable: Arc<Mutex<Ktable>>,
         service: UM::ServiceHandle,
         udpman: &'a UM::Manager,
         chan_out: Sender<FromNetMsg>,
-        my_id: Id
+        my_id: Id,
+        keys: RoomKeys,
+        history: Arc<Mutex<History>>,
+        overlay: Overlay<'a>,
+        flood: bool
     ) -> Self {
+        let mut causal = CausalQueue::new();
+        let joined = causal.now();
+        let room_id = keys.id();
         BroadcastManager{
-            connected: HashMap::new(),
-            cache: Cache::new(100),
+            overlay: overlay,
+            cache: Cache::new(MSG_CACHE),
+            packets: Cache::new(PACKET_CACHE),
             active: Vec::new(),
             ktable: ktable,
             service: service,
             udpman: udpman,
             chan_out: chan_out,
             my_id: my_id,
-            ting_timer: Timer::from_millis(1000*10),
-            ting_cur: None,
+            room_id: room_id,
+            keys: keys,
+            service_no: super::room_service(room_id, super::BROADCAST_SERVICE),
+            causal: causal,
+            history: history,
+            digest_timer: Timer::from_millis(DIGEST_MS),
+            joined: joined,
+            lazy: HashSet::new(),
+            announce: HashMap::new(),
+            missing: HashMap::new(),
+            flood: flood,
+            presence: Vec::new(),
+            mine: HashMap::new(),
+            parents: HashMap::new(),
+            acks: HashMap::new(),
         }
     }
 
     pub fn update(&mut self) {
 
-        let mut resend: Vec<Msg> = Vec::new();
+        let mut resend: Vec<(Msg, Vec<SocketAddr>)> = Vec::new();
 
         // update and remove done active broadcasts
         for i in (0..self.active.len()).rev() {
@@ -82,31 +237,50 @@ impl<'a> BroadcastManager<'a> {
                 let (m, sh) = self.active.remove(i);
                 let mut want_to_resend = false;
 
+                let reached: Vec<SocketAddr> = sh.iter().filter(|a| !sh.is_dead(a)).cloned().collect();
+                self.reached(m.hash, &reached);
+
                 for a in sh.iter() {
+                    let peer = self.overlay.active().iter().find(|e| e.get_addr() == *a).map(|e| e.get_id());
                     if sh.is_dead(a) {
-                        self.remove_connection(&a);
+                        self.remove_connection(*a, peer);
                         want_to_resend = true;
+                    } else if let Some(id) = peer {
+                        self.ktable.lock().unwrap().touch(id);
                     }
                 }
 
                 if want_to_resend {
-                    resend.push(m);
+                    let tried = sh.iter().cloned().collect();
+                    resend.push((m, tried));
                 }
             }
         }
 
-        // connect to more
-        self.connect_closest();
+        // keep the overlay together
+        self.overlay.update();
+        let overlay = &self.overlay;
+        self.lazy.retain(|a| overlay.is_active(*a));
 
         // TODO: optimera
-        // resend stuff where atleast one connection didn't respond
-        for r in resend.into_iter() {
+        // resend stuff where atleast one connection didn't respond,
+        // to the peers that replaced it
+        for (r, tried) in resend.into_iter() {
             debug!("resending something");
-            self.broadcast_a_msg(r, None);
+            self.broadcast_a_msg(r, &tried);
+        }
+
+        // compare recent messages with someone, in case one of us missed some
+        if self.digest_timer.expired(1.0) {
+            self.digest_timer.reset();
+            self.send_digest();
         }
 
-        // update and maybe start ting
-        self.update_ting();
+        self.retry_mine();
+
+        // deliver messages that have waited too long for what they depend on
+        let overdue = self.causal.update();
+        self.deliver(overdue);
 
         // read and broadcast
         let mut count = 10;
@@ -117,43 +291,121 @@ impl<'a> BroadcastManager<'a> {
                 count -= 1;
             }
 
-            if let Some((Msg{hash, payload, sender_id}, sender, id)) = UM::service_get(&self.service) {
+            if let Some((packet, sender, id)) = UM::service_get::<Packet>(&self.service) {
                 UM::service_respond(&self.service, &(), id, sender).unwrap();
+                let Msg{hash, payload, sender_id} = match self.open(packet) {
+                    Some(m) => m,
+                    None => {
+                        debug!("couldn't open a message from {}, we don't have its key", sender);
+                        continue;
+                    }
+                };
+
+                // anti-entropy is only done with our neighbours
+                let repairing = matches!(payload, MsgPayload::Digest(..) | MsgPayload::Want(_) | MsgPayload::Repair(_));
+                if repairing && !self.overlay.active().iter().any(|e| e.get_addr() == sender) {
+                    debug!("{} isn't a neighbour, ignored its repair traffic", sender);
+                    continue;
+                }
 
-                if self.cache.insert(hash) {
+                let fresh = match payload {
+                    MsgPayload::Msg(_) => self.cache.insert(hash),
+                    _ => self.packets.insert(hash),
+                };
+                if !fresh {
+                    if let MsgPayload::Msg(_) = payload {
+                        self.prune(sender);
+                    }
+                } else {
                     let broadcast =
                         match payload {
                             MsgPayload::Msg(ref msg) => {
                                 debug!("received msg: '{}'", msg.get_message());
-                                let mut copy = msg.clone();
-                                copy.is_myself = false;
-                                self.chan_out.send(FromNetMsg::from_message(copy)).unwrap();
-                                true
+                                self.missing.remove(&hash);
+                                self.parents.insert(hash, (sender, Instant::now()));
+                                self.receive(msg.clone(), sender)
+                            }
+                            MsgPayload::Digest(since, ref ids) => {
+                                self.answer_digest(sender, since, ids);
+                                false
+                            }
+                            MsgPayload::Want(ref ids) => {
+                                let msgs: Vec<Message> = {
+                                    let history = self.history.lock().unwrap();
+                                    ids.iter().take(DIGEST_IDS).filter_map(|id| history.get(*id)).collect()
+                                };
+                                for m in msgs {
+                                    self.repair(sender, m);
+                                }
+                                false
                             }
-                            MsgPayload::IsAlive(alive_id) => {
-                                if self.ting_cur.is_some() {
-                                    if self.ting_cur.as_ref().unwrap().dst.get_id() == alive_id {
-                                        self.ting_cur = None;
-                                        self.ting_timer.reset();
-                                        debug!("ting target could be reached");
+                            MsgPayload::IHave(ref ids) => {
+                                for id in ids.iter().take(DIGEST_IDS) {
+                                    if self.cache.contains(id) || self.causal.knows(*id) {
+                                        continue;
+                                    }
+                                    let m = self.missing.entry(*id)
+                                        .or_insert_with(|| (Vec::new(), Timer::from_millis(IHAVE_TIMEOUT_MS)));
+                                    if !m.0.contains(&sender) {
+                                        m.0.push(sender);
                                     }
                                 }
-                                true
+                                false
                             }
-                            MsgPayload::Ting => {
-                                debug!("responding to a ting");
-                                let m = self.new_msg(MsgPayload::IsAlive(self.my_id));
-                                self.broadcast_a_msg(m, None);
+                            MsgPayload::IWant(ref ids) => {
+                                // they were pruned, push to them again
+                                self.lazy.remove(&sender);
+                                let msgs: Vec<Message> = {
+                                    let history = self.history.lock().unwrap();
+                                    ids.iter().take(DIGEST_IDS).filter_map(|id| history.get(*id)).collect()
+                                };
+                                for m in msgs {
+                                    let m = self.from_message(m);
+                                    let sh = self.send(&m, vec![sender]);
+                                    // counted once it got there
+                                    if self.mine.contains_key(&m.hash) {
+                                        self.active.push((m, sh));
+                                    }
+                                }
+                                false
+                            }
+                            MsgPayload::Presence(ref a) | MsgPayload::Heartbeat(ref a) => {
+                                if a.check(self.room_id) {
+                                    self.presence.push(a.clone());
+                                    true
+                                } else {
+                                    warn!("{} sent us a presence announcement with a bad signature, dropped it", sender);
+                                    false
+                                }
+                            }
+                            MsgPayload::Prune => {
+                                debug!("{} pruned us", sender);
+                                self.lazy.insert(sender);
+                                false
+                            }
+                            MsgPayload::Repair(ref msg) => {
+                                debug!("repaired msg: '{}'", msg.get_message());
+                                // the tree it is pushed along goes first
+                                self.parents.entry(msg.id).or_insert((sender, Instant::now()));
+                                self.receive(msg.clone(), sender);
+                                false
+                            }
+                            MsgPayload::Ack(ref acks) => {
+                                for &(id, count) in acks.iter().take(ACKS_PER_PACKET) {
+                                    self.ack(id, count as usize);
+                                }
                                 false
                             }
                         };
 
                     if broadcast {
-                        // are we also connected to this fellow broadcaster?
-                        if !self.connected.contains_key(&sender) {
-                            self.connected.insert(sender, sender_id);
-                            self.ktable.lock().unwrap().offer(Entry::new(sender, sender_id));
+                        // marks it as seen if we already have it. The sender_id
+                        // isn't proven so unknown senders aren't added
+                        let mut ktab = self.ktable.lock().unwrap();
+                        if ktab.knows(sender, sender_id) {
+                            ktab.touch(sender_id);
                         }
+                        drop(ktab);
 
                         let my_id = self.my_id;
                         self.broadcast_a_msg(
@@ -162,134 +414,420 @@ impl<'a> BroadcastManager<'a> {
                                 payload: payload,
                                 sender_id: my_id
                             },
-                            Some(sender)
+                            &[sender]
                         );
                     }
                 }
             }
         }
+
+        self.ask_for_missing();
+        self.send_announcements();
+        self.send_acks();
     }
 
-    fn remove_connection(&mut self, adr: &SocketAddr) {
-        if self.connected.contains_key(&adr) {
-            let id = self.connected.remove(&adr).unwrap();
-            debug!("removed {} from connected", adr);
-            self.ktable.lock().unwrap().delete_id(id);
+    /// sends our messages that reached no one again, and gives up on them
+    /// after a while. Forgets the ones that are too old to still be acked
+    fn retry_mine(&mut self) {
+        let active = &self.active;
+        let mut retry = Vec::new();
+        let mut failed = Vec::new();
+        for (id, out) in self.mine.iter_mut() {
+            if !out.sent.is_empty() || !out.retry.expired(1.0) || active.iter().any(|(m, _)| m.hash == *id) {
+                continue;
+            }
+            if out.tries >= MAX_TRIES {
+                failed.push(*id);
+            } else {
+                out.tries += 1;
+                out.retry.reset();
+                retry.push(out.msg.clone());
+            }
         }
+        for id in failed {
+            warn!("message {} reached no one, gave up on it", id);
+            self.mine.remove(&id);
+            let _ = self.chan_out.send(FromNetMsg::Status(self.room_id, id, SendStatus::Failed));
+        }
+        for msg in retry {
+            debug!("sending message {} again", msg.id);
+            let m = self.from_message(msg);
+            self.broadcast_a_msg(m, &[]);
+        }
+        self.mine.retain(|_, out| out.sent.is_empty() || out.since.elapsed() < ACK_WINDOW);
+        self.parents.retain(|_, &mut (_, since)| since.elapsed() < ACK_WINDOW);
     }
 
-    /// try to connect to peers if we are connected to too few
-    fn connect_closest(&mut self) {
-        let ktab = self.ktable.lock().unwrap();
-        if self.connected.len() < MAX_CONNECTIONS as usize {
-            let closest = ktab.get(MAX_CONNECTIONS);
-            for c in closest {
-                if !self.connected.contains_key(&c.get_addr()) {
-                    debug!("connected to {}", c.get_addr());
-                    self.connected.insert(c.get_addr(), c.get_id());
-                }
+    /// one of our messages with `id` was pushed to `peers`
+    fn reached(&mut self, id: u64, peers: &[SocketAddr]) {
+        if let Some(out) = self.mine.get_mut(&id) {
+            let before = out.sent.len();
+            out.sent.extend(peers.iter().cloned());
+            if out.sent.len() > before {
+                let _ = self.chan_out.send(FromNetMsg::Status(self.room_id, id, SendStatus::Sent(out.sent.len())));
+            }
+        }
+    }
+
+    /// `count` more members got the message with `id`, counts them if it
+    /// is ours or adds them to what we pass on to who pushed it to us
+    fn ack(&mut self, id: u64, count: usize) {
+        if count == 0 {
+            return;
+        }
+        if let Some(out) = self.mine.get_mut(&id) {
+            out.acked += count;
+            let _ = self.chan_out.send(FromNetMsg::Status(self.room_id, id, SendStatus::Acked(out.acked)));
+            return;
+        }
+        if self.parents.contains_key(&id) {
+            self.acks.entry(id)
+                .or_insert_with(|| (0, Timer::from_millis(ACK_DELAY_MS)))
+                .0 += count;
+        }
+    }
+
+    /// passes on the counts that were collected long enough, one packet per parent
+    fn send_acks(&mut self) {
+        let due: Vec<u64> = self.acks.iter()
+            .filter(|(_, (_, t))| t.expired(1.0))
+            .map(|(id, _)| *id)
+            .collect();
+        let mut to: HashMap<SocketAddr, Vec<(u64, u32)>> = HashMap::new();
+        for id in due {
+            let (count, _) = self.acks.remove(&id).unwrap();
+            if let Some(&(parent, _)) = self.parents.get(&id) {
+                to.entry(parent).or_default().push((id, count as u32));
+            }
+        }
+        for (peer, acks) in to {
+            for chunk in acks.chunks(ACKS_PER_PACKET) {
+                let m = self.new_msg(MsgPayload::Ack(chunk.to_vec()));
+                self.send(&m, vec![peer]);
+            }
+        }
+    }
+
+    /// asks for the messages that were announced but not pushed to us in time,
+    /// and takes the peer that has them on the tree
+    fn ask_for_missing(&mut self) {
+        let mut wants: HashMap<SocketAddr, Vec<u64>> = HashMap::new();
+        for (id, &mut (ref mut have, ref mut timer)) in self.missing.iter_mut() {
+            if timer.expired(1.0) && !have.is_empty() {
+                let peer = have.remove(0);
+                wants.entry(peer).or_default().push(*id);
+                timer.reset();
+            }
+        }
+        // nobody left to ask, anti-entropy will have to find it
+        self.missing.retain(|_, &mut (ref have, ref timer)| !have.is_empty() || !timer.expired(1.0));
+        for (peer, ids) in wants {
+            debug!("asking {} for {} messages that weren't pushed to us", peer, ids.len());
+            self.lazy.remove(&peer);
+            for chunk in ids.chunks(DIGEST_IDS) {
+                let m = self.new_msg(MsgPayload::IWant(chunk.to_vec()));
+                self.send(&m, vec![peer]);
             }
         }
     }
 
+    fn send_announcements(&mut self) {
+        let announce: Vec<(SocketAddr, Vec<u64>)> = self.announce.drain().collect();
+        for (peer, ids) in announce {
+            for chunk in ids.chunks(DIGEST_IDS) {
+                let m = self.new_msg(MsgPayload::IHave(chunk.to_vec()));
+                self.send(&m, vec![peer]);
+            }
+        }
+    }
+
+    /// stops pushing messages to `peer` and tells it to do the same
+    fn prune(&mut self, peer: SocketAddr) {
+        if self.flood || !self.overlay.is_active(peer) || !self.lazy.insert(peer) {
+            return;
+        }
+        debug!("got a message twice, pruning {}", peer);
+        let m = self.new_msg(MsgPayload::Prune);
+        self.send(&m, vec![peer]);
+    }
+
+    /// sends the ids of our recent messages to a random connected peer
+    fn send_digest(&mut self) {
+        let peers: Vec<SocketAddr> = self.overlay.active().iter().map(|e| e.get_addr()).collect();
+        if peers.is_empty() {
+            return;
+        }
+        let peer = peers[thread_rng().gen_range(0, peers.len())];
+        let (ids, earliest) = self.history.lock().unwrap().recent(DIGEST_IDS);
+        let m = self.new_msg(MsgPayload::Digest(earliest.unwrap_or(self.joined), ids));
+        self.send(&m, vec![peer]);
+    }
+
+    /// sends `peer` what it lacks of our messages since `since`
+    /// and asks for what we lack of its `ids`
+    fn answer_digest(&mut self, peer: SocketAddr, since: Timestamp, ids: &[u64]) {
+        let ids = &ids[..ids.len().min(DIGEST_IDS)];
+        let missing = self.history.lock().unwrap().missing_from(since, ids, DIGEST_IDS);
+        if !missing.is_empty() {
+            debug!("{} missed {} messages", peer, missing.len());
+        }
+        for m in missing {
+            self.repair(peer, m);
+        }
+        let wanted: Vec<u64> = ids.iter().cloned().filter(|id| !self.causal.knows(*id)).collect();
+        if !wanted.is_empty() {
+            debug!("we missed {} messages that {} has", wanted.len(), peer);
+            let m = self.new_msg(MsgPayload::Want(wanted));
+            self.send(&m, vec![peer]);
+        }
+    }
+
+    fn repair(&self, peer: SocketAddr, msg: Message) {
+        // not the id of the message, the pushed copy still has to be
+        // forwarded when it arrives
+        let m = self.new_msg(MsgPayload::Repair(msg));
+        self.send(&m, vec![peer]);
+    }
+
+    /// the peers broadcasts are sent to
+    pub fn connected(&self) -> Vec<(SocketAddr, Id)> {
+        self.overlay.active().iter().map(|e| (e.get_addr(), e.get_id())).collect()
+    }
+
+    pub fn is_lazy(&self, adr: SocketAddr) -> bool {
+        self.lazy.contains(&adr)
+    }
+
+    /// how many peers the overlay could replace failed ones with
+    pub fn passive(&self) -> usize {
+        self.overlay.passive().len()
+    }
+
+    fn remove_connection(&mut self, adr: SocketAddr, id: Option<Id>) {
+        debug!("removed {} from connected", adr);
+        self.overlay.failed(adr);
+        if let Some(id) = id {
+            self.ktable.lock().unwrap().delete_id(id);
+        }
+    }
+
     /// broadcast `msg` to all other nodes
-    fn broadcast_a_msg(&mut self, msg: Msg, ban: Option<SocketAddr>) {
-        if self.connected.is_empty() {
+    fn broadcast_a_msg(&mut self, msg: Msg, ban: &[SocketAddr]) {
+        let (is_msg, is_heartbeat) = match msg.payload {
+            MsgPayload::Msg(_) => (true, false),
+            MsgPayload::Heartbeat(_) => (false, true),
+            _ => (false, false),
+        };
+        if self.overlay.active().is_empty() {
+            // our own messages are tried again later
             warn!("no one to send to, dropping the message");
-            self.chan_out.send(FromNetMsg::NotSent).unwrap();
             return;
         }
 
-        self.cache.insert(msg.hash);
+        if is_msg {
+            self.cache.insert(msg.hash);
+        } else {
+            self.packets.insert(msg.hash);
+        }
 
-        let targets: Vec<SocketAddr> =
-            self.connected.keys()
-                .map(|e| *e)
-                .filter(|a| ban.map_or(true, |b| b != *a))
+        let peers: Vec<SocketAddr> =
+            self.overlay.active().iter()
+                .map(|e| e.get_addr())
+                .filter(|a| !ban.contains(a))
                 .collect();
+        // only messages can be asked for, heartbeats go along the tree
+        // and everything else is pushed to everyone
+        let (lazy, targets): (Vec<SocketAddr>, Vec<SocketAddr>) = peers.into_iter()
+            .partition(|a| (is_msg || is_heartbeat) && !self.flood && self.lazy.contains(a));
+        if is_msg {
+            for a in lazy {
+                self.announce.entry(a).or_default().push(msg.hash);
+            }
+        }
 
         // no one to send to
         if targets.is_empty() {
             return;
         }
 
-        let sh = UM::send(
-            &self.udpman,
-            &msg,
-            targets,
-            super::BROADCAST_SERVICE,
-        );
+        let sh = self.send(&msg, targets);
 
         self.active.push((msg, sh));
     }
 
-    /// broadcasts a new message to everyone else
-    pub fn broadcast(&mut self, msg: Message) {
+    /// how many received messages are held back for causal order
+    pub fn held(&self) -> usize {
+        self.causal.held()
+    }
+
+    /// signs our own `msg` with `identity`, sends it to the room and delivers it to ourselves.
+    /// How far it gets is told with `FromNetMsg::Status`
+    pub fn broadcast(&mut self, mut msg: Message, identity: &Identity) {
+        self.causal.stamp(&mut msg);
+        msg.sign(identity);
+        self.deliver(vec![msg.clone()]);
+        let _ = self.chan_out.send(FromNetMsg::Status(self.room_id, msg.id, SendStatus::Queued));
+        self.mine.insert(msg.id, Outgoing {
+            msg: msg.clone(),
+            sent: HashSet::new(),
+            tries: 0,
+            retry: Timer::from_millis(RETRY_MS),
+            acked: 0,
+            since: Instant::now(),
+        });
         let m = self.from_message(msg);
-        self.broadcast_a_msg(m, None);
-    }
-
-    fn update_ting(&mut self) {
-        // check if ting should be started
-        if self.ting_timer.expired(1.0) {
-            assert!(self.ting_cur.is_none());
-            let mran = self.ktable.lock().unwrap().random();
-
-            if let Some(ran) = mran {
-                self.ting_timer.disable();
-                let m = Msg{hash: get_hash(), payload: MsgPayload::Ting, sender_id: self.my_id};
-                let sh = UM::send(
-                    &self.udpman,
-                    &m,
-                    vec![ran.get_addr()],
-                    super::BROADCAST_SERVICE
-                );
-                self.cache.insert(m.hash);
-                let mut t = Timer::from_millis(1000*3);
-                t.disable();
-                self.ting_cur = Some(Ting{dst: ran, sendh: sh, sendh_done: false, timer: t});
-                debug!("sending a ting");
+        self.broadcast_a_msg(m, &[]);
+    }
 
-            } else {
-                debug!("no one to ting");
-                self.ting_timer.reset();
+    /// whether `msg` can be sent, see `fits`
+    pub fn fits(&self, msg: &Message, identity: &Identity) -> bool {
+        fits(&self.keys, msg, identity)
+    }
+
+    /// tells the room about our presence
+    pub fn announce(&mut self, a: Announcement) {
+        let m = self.new_msg(MsgPayload::Presence(a));
+        self.broadcast_a_msg(m, &[]);
+    }
+
+    /// tells the room we are still here, only a first one is pushed to everyone
+    pub fn heartbeat(&mut self, a: Announcement) {
+        let payload = if a.is_joined() {
+            MsgPayload::Presence(a)
+        } else {
+            MsgPayload::Heartbeat(a)
+        };
+        let m = self.new_msg(payload);
+        self.broadcast_a_msg(m, &[]);
+    }
+
+    /// waits until what we are sending got through or `deadline` passes,
+    /// so the last things we say aren't lost when the node stops
+    pub fn flush(&mut self, deadline: Instant) {
+        while !self.active.is_empty() && Instant::now() < deadline {
+            for &mut (_, ref mut sh) in self.active.iter_mut() {
+                sh.update();
             }
+            self.active.retain(|(_, sh)| !sh.is_done());
+            thread::sleep(Duration::from_millis(10));
         }
-        // update ongoing ting
-        // TODO: this is the ugliest thing i have ever seen
-        if self.ting_cur.is_some() {
-            if !self.ting_cur.as_ref().unwrap().sendh_done {
-                self.ting_cur.as_mut().unwrap().sendh.update();
-
-                if self.ting_cur.as_ref().unwrap().sendh.is_done() {
-                    self.ting_cur.as_mut().unwrap().sendh_done = true;
-
-                    if self.ting_cur.as_ref().unwrap().sendh.borrow_single_answer().is_some() {
-                        self.ting_cur.as_mut().unwrap().timer.reset();
-                    } else {
-                        let i = self.ting_cur.as_ref().unwrap().dst;
-                        self.ktable.lock().unwrap().delete_id(i.get_id());
-                        self.ting_cur = None;
-                        self.ting_timer.reset();
-                    }
-                }
-            } else if self.ting_cur.as_ref().unwrap().timer.expired(1.0) {
-                let i = self.ting_cur.as_ref().unwrap().dst;
-                if !self.connected.contains_key(&i.get_addr()) {
-                    debug!("creating an extra bridge to {} because a ting timed out", i.get_addr());
-                    self.connected.insert(i.get_addr(), i.get_id());
-                }
-                self.ting_timer.reset();
-                self.ting_cur = None;
+    }
+
+    /// the presence announcements we received since the last call
+    pub fn take_presence(&mut self) -> Vec<Announcement> {
+        self.presence.drain(..).collect()
+    }
+
+    /// delivers `msgs` we got from another node's history,
+    /// leaving out the ones we already have
+    pub fn backlog(&mut self, history: Vec<Message>) {
+        let mut msgs = Vec::new();
+        for mut m in history {
+            if m.room_id != self.room_id || self.causal.knows(m.id) {
+                continue;
+            }
+            if !m.check() {
+                warn!("dropped message {} of the history without a valid signature", m.id);
+                continue;
+            }
+            if self.cache.insert(m.id) {
+                msgs.push(m);
+            }
+        }
+        if msgs.is_empty() {
+            return;
+        }
+        msgs.sort_by_key(|m| (m.clock, m.sender_id));
+        {
+            let mut history = self.history.lock().unwrap();
+            for m in msgs.iter() {
+                history.push(m.clone());
+            }
+        }
+        let ready = self.causal.backlog(&msgs);
+        let _ = self.chan_out.send(FromNetMsg::Backlog(self.room_id, msgs));
+        // messages that were waiting for the backlog
+        self.deliver(ready);
+    }
+
+    /// checks a message someone pushed or repaired and queues it for delivery.
+    /// returns false if it is dropped, it isn't passed on then
+    fn receive(&mut self, mut msg: Message, from: SocketAddr) -> bool {
+        if msg.room_id != self.room_id {
+            debug!("{} sent us a message of another room", from);
+            return false;
+        }
+        if !msg.check() {
+            warn!("{} sent us a message without a valid signature, dropped it", from);
+            return false;
+        }
+        msg.is_myself = false;
+        let ready = self.causal.receive(msg);
+        self.deliver(ready);
+        true
+    }
+
+    /// hands `msgs` to the user and keeps them as history.
+    /// The messages of others are acked
+    fn deliver(&mut self, msgs: Vec<Message>) {
+        for m in msgs {
+            self.history.lock().unwrap().push(m.clone());
+            if !m.is_myself {
+                self.ack(m.id, 1);
             }
+            let _ = self.chan_out.send(FromNetMsg::from_message(m));
         }
+    }
+
+    /// seals `msg` with the current room key and sends it to `to`
+    fn send(&self, msg: &Msg, to: Vec<SocketAddr>) -> UM::SendHandle<()> {
+        UM::send(self.udpman, &seal(&self.keys, msg), to, self.service_no)
+    }
+
+    /// the `Msg` in `packet`, None if we can't read it
+    fn open(&self, packet: Packet) -> Option<Msg> {
+        let plain = self.keys.open(&packet.payload, &packet_ad(packet.hash, packet.sender_id))?;
+        let payload = deserialize(&plain).ok()?;
+        Some(Msg{hash: packet.hash, sender_id: packet.sender_id, payload})
+    }
 
+    /// the keys of the room
+    pub fn keys(&self) -> &RoomKeys {
+        &self.keys
     }
+
+    /// takes newer keys of the room, what we send from now on is
+    /// sealed with the newest
+    pub fn rekey(&mut self, keys: &RoomKeys) {
+        if self.keys.merge(keys) {
+            info!("room {} is now using key version {}", self.room_id, self.keys.current().version());
+        }
+    }
+
     fn new_msg(&self, pay: MsgPayload) -> Msg {
         Msg{hash: get_hash(), payload: pay, sender_id: self.my_id}
     }
     fn from_message(&self, msg: Message) -> Msg {
-        self.new_msg(MsgPayload::Msg(msg))
+        // the same id as the message, so it is known to the cache
+        // if it arrives from someone's history too
+        Msg{hash: msg.id, payload: MsgPayload::Msg(msg), sender_id: self.my_id}
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_largest_message_fits_a_relayed_packet() {
+        let ident = Identity::generate();
+        let keys = RoomKeys::generate();
+        let text = "x".repeat(MAX_MESSAGE_LEN);
+        let m = Message::new(u64::MAX, text.clone(), ident.id(), "n".repeat(32), keys.id(), true);
+        assert!(fits(&keys, &m, &ident));
+
+        let m = Message::new(u64::MAX, text, ident.id(), "n".repeat(100), keys.id(), true);
+        assert!(!fits(&keys, &m, &ident));
+    }
+}
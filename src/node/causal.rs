@@ -0,0 +1,204 @@
+use std::time::{Duration, Instant};
+
+use common::hlc::{Clock, Timestamp};
+use common::id::Id;
+use node::cache::Cache;
+use super::Message;
+
+/// how many senders' latest messages a message depends on.
+/// Every one makes the message 20 bytes bigger, see `broadcast::fits`
+pub const MAX_DEPS: usize = 3;
+/// how many messages we hold back waiting for what they depend on
+const MAX_HELD: usize = 64;
+/// a message waiting longer than this is delivered anyway, whatever
+/// it depended on is most likely never arriving
+const HOLD_TIMEOUT: Duration = Duration::from_secs(5);
+/// how many delivered messages we remember
+const DELIVERED_CACHE: usize = 1000;
+/// a message only depends on messages this much older than it, by the
+/// clock. Older ones everyone has had the time to get
+const DEP_AGE_MS: u64 = 10 * 1000;
+
+/// delivers the messages of a room in causal order: a message is only
+/// delivered after the messages its sender had seen when sending it.
+/// Messages that don't depend on each other are delivered in clock order
+pub struct CausalQueue {
+    clock: Clock,
+    delivered: Cache<u64>,
+    /// the last delivered message of the senders we heard from, most recent last
+    heads: Vec<(Id, u64, Timestamp)>,
+    held: Vec<(Instant, Message)>,
+    /// when we started, what was sent before we never waited for
+    since: Timestamp,
+}
+
+impl CausalQueue {
+    pub fn new() -> Self {
+        let mut clock = Clock::new();
+        let since = clock.now();
+        CausalQueue {
+            clock,
+            delivered: Cache::new(DELIVERED_CACHE),
+            heads: Vec::new(),
+            held: Vec::new(),
+            since,
+        }
+    }
+
+    /// a timestamp for something happening here
+    pub fn now(&mut self) -> Timestamp {
+        self.clock.now()
+    }
+
+    /// gives our own `msg` a timestamp and what it depends on, and delivers it
+    pub fn stamp(&mut self, msg: &mut Message) {
+        msg.clock = self.clock.now();
+        let now = msg.clock.wall_millis();
+        msg.deps = self.heads.iter()
+            .filter(|&&(_, _, t)| t.wall_millis() + DEP_AGE_MS >= now)
+            .map(|&(_, id, t)| (id, t))
+            .collect();
+        self.deliver(msg);
+    }
+
+    /// takes a received message, returns the messages that can be delivered now
+    pub fn receive(&mut self, msg: Message) -> Vec<Message> {
+        if self.knows(msg.id) {
+            return Vec::new();
+        }
+        self.held.push((Instant::now(), msg));
+        self.release()
+    }
+
+    /// marks `msgs` from another node's history as delivered,
+    /// returns the messages that were waiting for them
+    pub fn backlog(&mut self, msgs: &[Message]) -> Vec<Message> {
+        for m in msgs {
+            self.clock.update(m.clock);
+            self.deliver(m);
+        }
+        self.release()
+    }
+
+    /// if we delivered or are holding the message with `id`
+    pub fn knows(&self, id: u64) -> bool {
+        self.delivered.contains(&id) || self.held.iter().any(|(_, m)| m.id == id)
+    }
+
+    /// returns the held messages that have waited too long,
+    /// along with the messages that were waiting for them
+    pub fn update(&mut self) -> Vec<Message> {
+        if self.held.iter().any(|&(t, _)| t.elapsed() > HOLD_TIMEOUT) {
+            self.release()
+        } else {
+            Vec::new()
+        }
+    }
+
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    fn release(&mut self) -> Vec<Message> {
+        let mut out = Vec::new();
+        loop {
+            // the earliest message that has all it depends on, and failing
+            // that the earliest one that can't wait any more
+            let next = {
+                let ready = |(_, m): &(Instant, Message)| m.deps.iter().all(|d| self.satisfied(m, d));
+                let overdue = self.held.len() > MAX_HELD;
+                self.held.iter()
+                    .enumerate()
+                    .filter(|&(_, h)| ready(h) || overdue || h.0.elapsed() > HOLD_TIMEOUT)
+                    .min_by_key(|(_, (_, m))| (m.clock, m.sender_id))
+                    .map(|(i, _)| i)
+            };
+            match next {
+                Some(i) => {
+                    let (_, msg) = self.held.remove(i);
+                    self.clock.update(msg.clock);
+                    self.deliver(&msg);
+                    out.push(msg);
+                }
+                None => return out,
+            }
+        }
+    }
+
+    /// has `msg` got its dependency `dep`? Those sent before we started,
+    /// or too long before `msg`, we may never get or have forgotten
+    fn satisfied(&self, msg: &Message, dep: &(u64, Timestamp)) -> bool {
+        let &(id, t) = dep;
+        self.delivered.contains(&id)
+            || t < self.since
+            || t.wall_millis() + DEP_AGE_MS < msg.clock.wall_millis()
+    }
+
+    fn deliver(&mut self, msg: &Message) {
+        self.delivered.insert(msg.id);
+        self.heads.retain(|&(s, _, _)| s != msg.sender_id);
+        self.heads.push((msg.sender_id, msg.id, msg.clock));
+        if self.heads.len() > MAX_DEPS {
+            self.heads.remove(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn msg(id: u64, sender: u64) -> Message {
+        Message::new(id, format!("m{}", id), Id::from_u64(sender), String::new(), Id::from_u64(0), false)
+    }
+
+    fn ids(msgs: Vec<Message>) -> Vec<u64> {
+        msgs.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn waits_for_what_a_message_depends_on() {
+        let mut alice = CausalQueue::new();
+        let mut bob = CausalQueue::new();
+        let mut first = msg(1, 1);
+        alice.stamp(&mut first);
+        let mut second = msg(2, 1);
+        alice.stamp(&mut second);
+        assert_eq!(second.deps, vec![(1, first.clock)]);
+
+        assert!(bob.receive(second).is_empty());
+        assert_eq!(bob.held(), 1);
+        assert_eq!(ids(bob.receive(first)), vec![1, 2]);
+        assert_eq!(bob.held(), 0);
+    }
+
+    #[test]
+    fn messages_from_before_joining_are_not_waited_for() {
+        let mut alice = CausalQueue::new();
+        let mut first = msg(1, 1);
+        alice.stamp(&mut first);
+        thread::sleep(Duration::from_millis(5));
+        let mut bob = CausalQueue::new();
+        let mut second = msg(2, 1);
+        alice.stamp(&mut second);
+        assert_eq!(ids(bob.receive(second)), vec![2]);
+    }
+
+    #[test]
+    fn depends_on_the_last_message_of_every_sender() {
+        let mut alice = CausalQueue::new();
+        let mut bob = CausalQueue::new();
+        let mut a1 = msg(1, 1);
+        alice.stamp(&mut a1);
+        let mut b1 = msg(2, 2);
+        bob.stamp(&mut b1);
+        alice.receive(b1.clone());
+        let mut a2 = msg(3, 1);
+        alice.stamp(&mut a2);
+        let deps: Vec<u64> = a2.deps.iter().map(|d| d.0).collect();
+        assert_eq!(deps, vec![1, 2]);
+        assert!(a2.clock > b1.clock);
+        assert!(alice.knows(2));
+    }
+}
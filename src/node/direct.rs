@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use rand::RngCore;
+
+use bincode::{deserialize, serialize};
+use common::id::Id;
+use common::identity::{id_of_key, Proof};
+use network::udpmanager as UM;
+use node::cache::Cache;
+use node::kademlia::{self, IdLookup};
+use node::ktable::{Entry, Ktable};
+use node::room::Node;
+use std::sync::{Arc, Mutex};
+use super::{DirectMessage, FromNetMsg};
+
+/// how many received direct messages we remember, so a resent one isn't delivered twice
+const RECEIVED_CACHE: usize = 200;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum DirectMsg {
+    /// asks for the receiver's public key, proven by signing the nonce
+    KeyRequest(u64),
+    /// a message sealed for the receiver, with the sender's public key and the nonce
+    Msg([u8; 32], [u8; 12], Vec<u8>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum DirectReply {
+    Key(Proof),
+    /// the message was received, false if it couldn't be read
+    Ack(bool),
+}
+
+/// what is sealed in a `DirectMsg::Msg`
+#[derive(Serialize, Deserialize)]
+struct Body {
+    id: u64,
+    sender_name: String,
+    msg: String,
+    sent: SystemTime,
+}
+
+/// decodes a serialized `DirectMsg` into something readable, the message stays sealed
+pub fn describe(payload: &[u8]) -> Option<String> {
+    deserialize::<DirectMsg>(payload).ok().map(|m| match m {
+        DirectMsg::Msg(_, _, data) => format!("Msg({} bytes)", data.len()),
+        m => format!("{:?}", m),
+    })
+}
+
+fn key_bytes(room: Id, nonce: u64) -> Vec<u8> {
+    serialize(&(room, nonce)).unwrap()
+}
+
+// a sealed message is bound to the room and the receiver, so it can't be passed on to someone else
+fn msg_ad(room: Id, to: Id) -> Vec<u8> {
+    serialize(&(room, to)).unwrap()
+}
+
+enum Step<'a> {
+    /// looking for the receiver's node
+    Lookup(IdLookup<'a>),
+    /// asking the receiver for its public key
+    Key(Entry, u64, UM::SendHandle<DirectReply>),
+    /// waiting for the receiver to acknowledge the message
+    Sending(UM::SendHandle<DirectReply>),
+}
+
+struct Outgoing<'a> {
+    id: u64,
+    to: Id,
+    msg: String,
+    step: Step<'a>,
+}
+
+/// private messages between two users of a room. The receiver is found
+/// with a lookup of its id, and the message is sealed with a key only
+/// the two of them can make and sent straight to it
+pub struct Direct<'a> {
+    node: &'a Node,
+    room_id: Id,
+    service: UM::ServiceHandle,
+    service_no: u32,
+    /// the kademlia service of the room, to look for receivers
+    kad_no: u32,
+    ktab: Arc<Mutex<Ktable>>,
+    outgoing: Vec<Outgoing<'a>>,
+    /// the public keys of the users we have talked to
+    keys: HashMap<Id, [u8; 32]>,
+    received: Cache<u64>,
+}
+
+impl<'a> Direct<'a> {
+    pub fn new(node: &'a Node, room_id: Id, service: UM::ServiceHandle, service_no: u32, kad_no: u32, ktab: Arc<Mutex<Ktable>>) -> Self {
+        Direct {
+            node,
+            room_id,
+            service,
+            service_no,
+            kad_no,
+            ktab,
+            outgoing: Vec::new(),
+            keys: HashMap::new(),
+            received: Cache::new(RECEIVED_CACHE),
+        }
+    }
+
+    /// sends `msg` to the user `to`, `FromNetMsg::DirectDelivered` tells how it went
+    pub fn send(&mut self, id: u64, to: Id, msg: String) {
+        let node = self.node;
+        // we might know where they are already
+        let known = self.ktab.lock().unwrap().closest_to(1, to).into_iter().find(|e| e.get_id() == to);
+        let step = match known {
+            Some(e) => self.reach(e, to, id, &msg),
+            None => {
+                debug!("looking for {} to send them a direct message", to);
+                Step::Lookup(IdLookup::new(&node.udpman, self.kad_no, &node.identity, to, node.myself, self.ktab.clone()))
+            }
+        };
+        self.outgoing.push(Outgoing{id, to, msg, step});
+    }
+
+    /// answers key requests, takes in messages and moves our own messages along
+    pub fn update(&mut self) {
+        let mut count = 10;
+        while count > 0 {
+            count -= 1;
+            match UM::service_get::<DirectMsg>(&self.service) {
+                Some((msg, from, id)) => {
+                    let reply = self.handle(msg);
+                    UM::service_respond(&self.service, &reply, id, from).unwrap();
+                }
+                None => break,
+            }
+        }
+
+        for i in (0..self.outgoing.len()).rev() {
+            let done = match self.outgoing[i].step {
+                Step::Lookup(ref mut l) => {
+                    l.update();
+                    l.is_done()
+                }
+                Step::Key(_, _, ref mut sh) | Step::Sending(ref mut sh) => {
+                    sh.update();
+                    sh.is_done()
+                }
+            };
+            if !done {
+                continue;
+            }
+            let out = self.outgoing.remove(i);
+            let (id, to, msg) = (out.id, out.to, out.msg);
+            let step = match out.step {
+                Step::Lookup(l) => {
+                    match l.into_answer().into_iter().find(|e| e.get_id() == to) {
+                        Some(e) => Some(self.reach(e, to, id, &msg)),
+                        None => {
+                            debug!("couldn't find {} in room {}", to, self.room_id);
+                            None
+                        }
+                    }
+                }
+                Step::Key(e, nonce, sh) => {
+                    let key = match sh.borrow_single_answer() {
+                        Some(DirectReply::Key(proof)) if proof.verify(&key_bytes(self.room_id, nonce)) == Some(to) => Some(*proof.key()),
+                        _ => None,
+                    };
+                    match key {
+                        Some(key) => {
+                            self.keys.insert(to, key);
+                            Some(self.reach(e, to, id, &msg))
+                        }
+                        None => {
+                            debug!("{} didn't prove its key", to);
+                            None
+                        }
+                    }
+                }
+                Step::Sending(sh) => {
+                    let delivered = matches!(sh.borrow_single_answer(), Some(&DirectReply::Ack(true)));
+                    let _ = self.node.chan_out.send(FromNetMsg::DirectDelivered(self.room_id, id, delivered));
+                    continue;
+                }
+            };
+            match step {
+                Some(step) => self.outgoing.push(Outgoing{id, to, msg, step}),
+                None => {
+                    let _ = self.node.chan_out.send(FromNetMsg::DirectDelivered(self.room_id, id, false));
+                }
+            }
+        }
+    }
+
+    /// asks the node of `to` for its key, or sends it the message if we have it
+    fn reach(&mut self, e: Entry, to: Id, id: u64, msg: &str) -> Step<'a> {
+        let node = self.node;
+        kademlia::learn_entry(&node.udpman, &e);
+        let shared = self.keys.get(&to).and_then(|k| node.identity.shared_key(k));
+        match shared {
+            Some(shared) => {
+                let body = Body {
+                    id,
+                    sender_name: node.user_name.clone(),
+                    msg: msg.to_string(),
+                    sent: SystemTime::now(),
+                };
+                let mut nonce = [0; 12];
+                rand::thread_rng().fill_bytes(&mut nonce);
+                let data = ChaCha20Poly1305::new(&Key::from(shared))
+                    .encrypt(&Nonce::from(nonce), Payload{msg: &serialize(&body).unwrap(), aad: &msg_ad(self.room_id, to)})
+                    .expect("encrypting can't fail");
+                let m = DirectMsg::Msg(node.identity.public_key(), nonce, data);
+                Step::Sending(UM::send_via(&node.udpman, &m, e.get_addr(), e.get_relay(), self.service_no, UM::Priority::Normal))
+            }
+            None => {
+                let nonce = rand::thread_rng().next_u64();
+                let sh = UM::send_via(&node.udpman, &DirectMsg::KeyRequest(nonce), e.get_addr(), e.get_relay(), self.service_no, UM::Priority::Normal);
+                Step::Key(e, nonce, sh)
+            }
+        }
+    }
+
+    fn handle(&mut self, msg: DirectMsg) -> DirectReply {
+        let node = self.node;
+        match msg {
+            DirectMsg::KeyRequest(nonce) => DirectReply::Key(node.identity.prove(&key_bytes(self.room_id, nonce))),
+            DirectMsg::Msg(key, nonce, data) => {
+                let body = node.identity.shared_key(&key)
+                    .and_then(|shared| {
+                        ChaCha20Poly1305::new(&Key::from(shared))
+                            .decrypt(&Nonce::from(nonce), Payload{msg: &data, aad: &msg_ad(self.room_id, node.identity.id())})
+                            .ok()
+                    })
+                    .and_then(|plain| deserialize::<Body>(&plain).ok());
+                let body = match body {
+                    Some(b) => b,
+                    None => {
+                        debug!("got a direct message we couldn't read");
+                        return DirectReply::Ack(false);
+                    }
+                };
+                let from = id_of_key(&key);
+                // a message sent again because our ack got lost is only acked
+                if self.received.insert(body.id) {
+                    self.keys.insert(from, key);
+                    let m = DirectMessage {
+                        id: body.id,
+                        room_id: self.room_id,
+                        sender_id: from,
+                        sender_name: body.sender_name,
+                        msg: body.msg,
+                        sent: body.sent,
+                    };
+                    let _ = node.chan_out.send(FromNetMsg::Direct(m));
+                }
+                DirectReply::Ack(true)
+            }
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use bincode::{self, serialize_into, Options};
+use network::stream::Stream;
+use common::hlc::Timestamp;
+use common::roomkey::{RoomKeys, Sealed};
+use super::{HistoryRange, Message};
+
+/// how many delivered messages of a room we keep to hand out
+const MAX_HISTORY: usize = 500;
+/// the most messages sent in answer to one request
+const MAX_ANSWER: usize = 200;
+/// the largest answer we accept, in bytes
+const MAX_ANSWER_BYTES: u64 = 256 * 1024;
+/// a transfer that stalls this long is given up on
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+/// how many transfers of a room go on at once
+const WORKERS: usize = 2;
+/// how many transfers may wait for a worker, more are turned away
+const QUEUED: usize = 8;
+
+/// the recently delivered messages of a room, in the order they were delivered
+pub struct History {
+    msgs: VecDeque<Message>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History{msgs: VecDeque::new()}
+    }
+
+    pub fn push(&mut self, msg: Message) {
+        if self.msgs.len() >= MAX_HISTORY {
+            self.msgs.pop_front();
+        }
+        self.msgs.push_back(msg);
+    }
+
+    /// the id of the last delivered message
+    pub fn last_id(&self) -> Option<u64> {
+        self.msgs.back().map(|m| m.id)
+    }
+
+    /// the ids of the last `n` delivered messages, and the earliest clock among them
+    pub fn recent(&self, n: usize) -> (Vec<u64>, Option<Timestamp>) {
+        let skip = self.msgs.len().saturating_sub(n);
+        let recent = self.msgs.iter().skip(skip);
+        (recent.clone().map(|m| m.id).collect(), recent.map(|m| m.clock).min())
+    }
+
+    /// at most `n` of the messages sent at or after `since` that aren't in `known`
+    pub fn missing_from(&self, since: Timestamp, known: &[u64], n: usize) -> Vec<Message> {
+        self.msgs.iter()
+            .filter(|m| m.clock >= since && !known.contains(&m.id))
+            .take(n)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Message> {
+        self.msgs.iter().find(|m| m.id == id).cloned()
+    }
+
+    /// the messages in `range`, at most `MAX_ANSWER` of the latest ones.
+    /// A message id we don't know gets everything we have
+    pub fn range(&self, range: HistoryRange) -> Vec<Message> {
+        let msgs: Vec<&Message> = match range {
+            HistoryRange::Since(t) => self.msgs.iter().filter(|m| m.get_timestamp() >= t).collect(),
+            HistoryRange::After(id) => match self.msgs.iter().position(|m| m.id == id) {
+                Some(i) => self.msgs.iter().skip(i + 1).collect(),
+                None => self.msgs.iter().collect(),
+            },
+        };
+        let skip = msgs.len().saturating_sub(MAX_ANSWER);
+        msgs.into_iter().skip(skip).cloned().collect()
+    }
+}
+
+fn options() -> impl Options {
+    // the same encoding as `bincode::serialize`, but with a limit on what we take in
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_ANSWER_BYTES)
+}
+
+/// what a sealed answer is bound to, so it can't be passed off as the answer to another request
+fn answer_ad(range: &HistoryRange) -> Vec<u8> {
+    bincode::serialize(range).unwrap()
+}
+
+/// a transfer waiting for a worker of `Transfers`
+enum Job {
+    Serve(Stream, RoomKeys),
+    Fetch(Stream, HistoryRange, RoomKeys, Sender<Vec<Message>>),
+}
+
+/// serves and fetches the history of a room on a few threads of its own,
+/// so a flood of requests can't start a thread each
+pub struct Transfers {
+    jobs: SyncSender<Job>,
+}
+
+impl Transfers {
+    /// starts the workers, they stop when this is dropped
+    pub fn new(history: Arc<Mutex<History>>) -> Self {
+        let (tx, rx) = sync_channel(QUEUED);
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..WORKERS {
+            let rx: Arc<Mutex<Receiver<Job>>> = rx.clone();
+            let history = history.clone();
+            thread::spawn(move || loop {
+                // the lock is only held while waiting for a job
+                let job = match rx.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                match job {
+                    Job::Serve(stream, keys) => serve(stream, &history, &keys),
+                    Job::Fetch(stream, range, keys, tx) => fetch(stream, range, &keys, &tx),
+                }
+            });
+        }
+        Transfers{jobs: tx}
+    }
+
+    /// answers the history request coming in on `stream`, sealed with the
+    /// room key. It is turned away if too many are waiting already
+    pub fn serve(&self, stream: Stream, keys: RoomKeys) {
+        if let Err(TrySendError::Full(Job::Serve(mut stream, _))) = self.jobs.try_send(Job::Serve(stream, keys)) {
+            debug!("too many history transfers, turned {} away", stream.peer());
+            stream.close();
+        }
+    }
+
+    /// asks the other end of `stream` for the messages in `range`.
+    /// The answer comes out of the returned receiver, which is
+    /// disconnected without an answer if the transfer failed
+    pub fn fetch(&self, stream: Stream, range: HistoryRange, keys: RoomKeys) -> Receiver<Vec<Message>> {
+        let (tx, rx) = channel();
+        if let Err(TrySendError::Full(Job::Fetch(mut stream, ..))) = self.jobs.try_send(Job::Fetch(stream, range, keys, tx)) {
+            debug!("too many history transfers, not asking {}", stream.peer());
+            stream.close();
+        }
+        rx
+    }
+}
+
+fn serve(mut stream: Stream, history: &Mutex<History>, keys: &RoomKeys) {
+    stream.set_read_timeout(Some(TRANSFER_TIMEOUT));
+    let peer = stream.peer();
+    let range: HistoryRange = match options().deserialize_from(&mut stream) {
+        Ok(r) => r,
+        Err(e) => {
+            debug!("bad history request from {}: {}", peer, e);
+            return;
+        }
+    };
+    let msgs = history.lock().unwrap().range(range);
+    debug!("sending {} messages of history to {}", msgs.len(), peer);
+    let sealed = keys.seal(&bincode::serialize(&msgs).unwrap(), &answer_ad(&range));
+    let res = serialize_into(&mut stream, &sealed)
+        .map_err(|e| e.to_string())
+        .and_then(|_| stream.flush().map_err(|e| e.to_string()));
+    if let Err(e) = res {
+        debug!("couldn't send history to {}: {}", peer, e);
+    }
+    stream.close();
+}
+
+fn fetch(mut stream: Stream, range: HistoryRange, keys: &RoomKeys, tx: &Sender<Vec<Message>>) {
+    stream.set_read_timeout(Some(TRANSFER_TIMEOUT));
+    let peer: SocketAddr = stream.peer();
+    let res = serialize_into(&mut stream, &range)
+        .and_then(|_| options().deserialize_from::<_, Sealed>(&mut stream));
+    let sealed = match res {
+        Ok(s) => s,
+        Err(e) => {
+            debug!("couldn't get history from {}: {}", peer, e);
+            return;
+        }
+    };
+    let msgs = keys.open(&sealed, &answer_ad(&range))
+        .and_then(|plain| bincode::deserialize::<Vec<Message>>(&plain).ok());
+    match msgs {
+        Some(msgs) => {
+            let _ = tx.send(msgs);
+        }
+        None => debug!("couldn't read the history from {}, sealed with key v{}", peer, sealed.version()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::id::Id;
+    use network::udpmanager::Manager;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn fetches_through_the_workers() {
+        let a = Manager::start(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b_adr = sock.local_addr().unwrap();
+        let b = Manager::start(sock);
+        let listener = b.listen(9);
+        let keys = RoomKeys::generate();
+        let history = Arc::new(Mutex::new(History::new()));
+        for i in 0..3 {
+            history.lock().unwrap().push(Message::new(i, format!("m{}", i), Id::from_u64(1), String::new(), keys.id(), false));
+        }
+        let server = Transfers::new(history);
+        let client = Transfers::new(Arc::new(Mutex::new(History::new())));
+
+        let rx = client.fetch(a.connect(b_adr, 9), HistoryRange::After(0), keys.clone());
+        server.serve(listener.accept_wait(), keys);
+        let msgs = rx.recv_timeout(Duration::from_secs(5)).expect("no history came");
+        assert_eq!(msgs.iter().map(|m| m.id).collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,63 @@
+use std::net::SocketAddr;
+use network::udpmanager::Stats;
+
+/// everything a node knows about the network at one point in time.
+/// Ids are hex strings so the JSON dump is readable
+#[derive(Serialize, Debug, Clone)]
+pub struct NodeSnapshot {
+    pub id: String,
+    pub addr: SocketAddr,
+    pub manager: Stats,
+    pub rooms: Vec<RoomSnapshot>,
+}
+
+/// the routing table and what the node is doing in one room
+#[derive(Serialize, Debug, Clone)]
+pub struct RoomSnapshot {
+    pub room: String,
+    /// only the buckets that have entries
+    pub buckets: Vec<BucketSnapshot>,
+    /// the peers broadcasts are sent to, the active view of the overlay
+    pub connected: Vec<PeerSnapshot>,
+    /// the size of the passive view
+    pub passive_peers: usize,
+    pub lookups: Vec<LookupSnapshot>,
+    pub stored_values: usize,
+    /// received messages waiting for the messages they depend on
+    pub held_messages: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BucketSnapshot {
+    /// how many leading bits the entries have in common with us
+    pub index: usize,
+    pub entries: Vec<EntrySnapshot>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct EntrySnapshot {
+    pub id: String,
+    pub addr: SocketAddr,
+    pub relay: Option<SocketAddr>,
+    /// seconds since we last heard from it, None if never
+    pub last_seen_secs: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerSnapshot {
+    pub id: String,
+    pub addr: SocketAddr,
+    /// only gets the ids of messages, they are not pushed to it
+    pub lazy: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LookupSnapshot {
+    /// "refresh", "get" or "put"
+    pub kind: &'static str,
+    pub target: String,
+    /// queries waiting for an answer
+    pub in_flight: usize,
+    /// nodes heard of so far
+    pub candidates: usize,
+}
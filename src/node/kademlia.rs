@@ -1,250 +1,645 @@
 
-use std::net::{UdpSocket, SocketAddr};
-use ::network::{Result,NetworkError};
-use tracker::api;
+use std::net::SocketAddr;
+use ::network::Result;
 use ::common::id::Id;
-use ::node::ktable::{Entry,Ktable};
+use ::common::identity::{Identity, Proof};
+use ::common::get_hash;
+use ::node::ktable::{Entry,Eviction,Ktable};
+use ::node::store::{Signed, Store};
 use std::sync::{Arc,Mutex};
-use std::collections::{HashMap,HashSet};
+use std::collections::{hash_map, HashMap};
+use std::thread;
+use std::time::Duration;
 use network::udpmanager as UM;
+use bincode::{deserialize, serialize};
+use serde::ser::Serialize;
+use sha2::{Digest, Sha256};
 
 const LOOKUP_SIZE: usize = 5;
 const K: usize = 3;
+/// how many queries of a lookup are in flight at a time
+pub const ALPHA: usize = 3;
+/// how often a blocking lookup checks on its queries
+const LOOKUP_POLL: Duration = Duration::from_millis(5);
 
 #[derive(Serialize, Deserialize, Debug)]
 enum KadMsg {
     /// checks if another host is alive
-    Ping,
-    /// answer to `Ping`
-    Pong(Id),
+    /// Ping(nonce)
+    Ping(u64),
+    /// answer to `Ping`, proves the id of the answerer by signing the nonce
+    Pong(Proof),
     /// requests id to be looked up
-    /// Lookup(id_to_lookup, requester_entry)
-    Lookup(Id, Entry),
-    /// answer to lookup
-    Answer(Vec<Entry>),
+    /// Lookup(id_to_lookup, requester_entry, nonce, proof_of_requester_entry)
+    Lookup(Id, Entry, u64, Proof),
+    /// answer to lookup, the proof is a signature of the lookup's nonce
+    Answer(Vec<Entry>, Proof),
+    /// asks the receiver to keep a value signed by its publisher
+    /// Store(key, value)
+    Store(Id, Signed),
+    /// answer to `Store`, false if the value wasn't kept
+    Stored(bool),
+    /// same as `Lookup`, but the value under the id is wanted
+    /// FindValue(key, requester_entry, nonce, proof_of_requester_entry)
+    FindValue(Id, Entry, u64, Proof),
+    /// answer to `FindValue` if the value was found, instead of `Answer`
+    /// Value(value, proof)
+    Value(Signed, Proof),
 }
 
 impl KadMsg {
     pub fn is_pong(&self) -> bool {
-        if let KadMsg::Pong(_) = self {
+        if let KadMsg::Pong(..) = self {
             return true;
         }
         return false;
     }
     pub fn is_answer(&self) -> bool {
-        if let KadMsg::Answer(_) = self {
+        if let KadMsg::Answer(..) = self {
             return true;
         }
         return false;
     }
 }
 
-/// represents an ongoing id lookup
+/// what a `Pong` to `Ping(nonce)` signs
+fn pong_bytes(nonce: u64) -> Vec<u8> {
+    serialize(&("pong", nonce)).unwrap()
+}
+
+/// what the requester of a lookup signs, which binds its id to its entry
+fn lookup_bytes(lookup_id: Id, requester: &Entry, nonce: u64) -> Vec<u8> {
+    serialize(&("lookup", lookup_id, requester, nonce)).unwrap()
+}
+
+/// what an `Answer` or `Value` to a lookup with `nonce` signs, `payload` being
+/// its entries or its value and ttl. So nobody in between can change them
+fn answer_bytes<T: Serialize>(nonce: u64, payload: &T) -> Vec<u8> {
+    let digest = Sha256::digest(&serialize(payload).unwrap());
+    serialize(&("answer", nonce, &digest[..])).unwrap()
+}
+
+/// how far a lookup has gotten with one peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeerState {
+    /// queried, no answer yet
+    Pending,
+    /// answered with a valid proof
+    Responded,
+    /// timed out or couldn't prove its id
+    Failed,
+}
+
+/// represents an ongoing id lookup.
+/// Up to `alpha` peers are queried at a time and a new query is sent as soon
+/// as one of them finishes, so a slow peer only holds up its own slot
 pub struct IdLookup<'a> {
     udpman: &'a UM::Manager,
-    visited: HashSet<SocketAddr>,
-    best: Ktable,
+    /// the kademlia service of the room
+    service: u32,
+    lookup_id: Id,
+    alpha: usize,
+    /// everyone we have heard of, closest to `lookup_id` first
+    candidates: Vec<Entry>,
+    /// peers we have queried
+    state: HashMap<SocketAddr, PeerState>,
     ktable: Arc<Mutex<Ktable>>,
     msg: KadMsg,
-    cur: Option<UM::SendHandle<KadMsg>>,
-    map: HashMap<SocketAddr, Id>,
+    nonce: u64,
+    /// the queries in flight, answers have to be signed by the
+    /// key of the entry's id
+    in_flight: Vec<(Entry, UM::SendHandle<KadMsg>)>,
+    done: bool,
+    /// what a value lookup found, with its ttl left
+    value: Option<(Vec<u8>, Duration)>,
 }
 
 impl<'a> IdLookup<'a> {
-    /// initializes and starts an id lookup on `lookup_id`
+    /// initializes and starts an id lookup on `lookup_id` with `ALPHA` queries in flight
     /// It will update `ktable` continously
-    pub fn new(udpman: &'a UM::Manager, lookup_id: Id, myself: Entry, ktable: Arc<Mutex<Ktable>>) -> Self {
-        let msg = KadMsg::Lookup(lookup_id, myself);
+    pub fn new(udpman: &'a UM::Manager, service: u32, identity: &Identity, lookup_id: Id, myself: Entry, ktable: Arc<Mutex<Ktable>>) -> Self {
+        IdLookup::with_alpha(udpman, service, identity, lookup_id, myself, ktable, ALPHA)
+    }
+
+    /// same as `new` but with up to `alpha` queries in flight
+    pub fn with_alpha(udpman: &'a UM::Manager, service: u32, identity: &Identity, lookup_id: Id, myself: Entry, ktable: Arc<Mutex<Ktable>>, alpha: usize) -> Self {
+        IdLookup::start(udpman, service, identity, lookup_id, myself, ktable, alpha, false)
+    }
 
-        let initial: HashMap<SocketAddr, Id> = ktable.lock().unwrap()
-            .closest_to(2*K as u32, lookup_id)
-            .into_iter()
-            .map(|e| (e.get_addr(), e.get_id()))
-            .collect();
+    /// looks up the value stored under `key`. The lookup stops at
+    /// the first node that has it, see `take_value`
+    pub fn find_value(udpman: &'a UM::Manager, service: u32, identity: &Identity, key: Id, myself: Entry, ktable: Arc<Mutex<Ktable>>) -> Self {
+        IdLookup::start(udpman, service, identity, key, myself, ktable, ALPHA, true)
+    }
 
-        let sendh = if initial.is_empty() {
-            debug!("no one connected for an id lookup");
-            None
+    #[allow(clippy::too_many_arguments)]
+    fn start(udpman: &'a UM::Manager, service: u32, identity: &Identity, lookup_id: Id, myself: Entry, ktable: Arc<Mutex<Ktable>>, alpha: usize, find_value: bool) -> Self {
+        assert!(alpha > 0, "a lookup needs at least one query in flight");
+        let nonce = get_hash();
+        let proof = identity.prove(&lookup_bytes(lookup_id, &myself, nonce));
+        let msg = if find_value {
+            KadMsg::FindValue(lookup_id, myself, nonce, proof)
         } else {
-            Some(UM::send(
-                udpman,
-                &msg,
-                initial.keys().map(|k| *k).collect(),
-                super::KAD_SERVICE
-            ))
+            KadMsg::Lookup(lookup_id, myself, nonce, proof)
+        };
+
+        let initial = {
+            let mut ktab = ktable.lock().unwrap();
+            ktab.mark_refreshed(lookup_id);
+            ktab.closest_to(2*K as u32, lookup_id)
         };
+        if initial.is_empty() {
+            debug!("no one connected for an id lookup");
+        }
 
-        IdLookup {
+        let mut lookup = IdLookup {
             udpman: udpman,
-            visited: HashSet::new(),
-            best: Ktable::new(K as u32, lookup_id),
+            service: service,
+            lookup_id: lookup_id,
+            alpha: alpha,
+            candidates: Vec::new(),
+            state: HashMap::new(),
             ktable: ktable,
             msg: msg,
-            cur: sendh,
-            map: initial,
+            nonce: nonce,
+            in_flight: Vec::new(),
+            done: false,
+            value: None,
+        };
+        for e in initial {
+            lookup.add_candidate(e);
         }
+        lookup.query_more();
+        lookup
     }
 
     /// is the lookup done?
     pub fn is_done(&self) -> bool {
-        self.cur.is_none()
+        self.done
     }
 
     /// call this over and over until it is done
     pub fn update(&mut self) {
-        self.tick(false);
+        self.tick();
     }
 
     /// block the thread and wait for the lookup to finish
     pub fn update_wait(&mut self) {
         while !self.is_done() {
-            self.tick(true);
+            self.tick();
+            if !self.is_done() {
+                thread::sleep(LOOKUP_POLL);
+            }
         }
     }
 
-    fn tick(&mut self, block: bool) {
+    fn tick(&mut self) {
         if self.is_done() {
             return
         }
 
-        if block {
-            self.cur.as_mut().unwrap().update_wait();
-        } else {
-            self.cur.as_mut().unwrap().update();
+        for i in (0..self.in_flight.len()).rev() {
+            self.in_flight[i].1.update();
+            if self.in_flight[i].1.is_done() {
+                let (asked, sendh) = self.in_flight.remove(i);
+                self.process(asked, sendh);
+                if self.is_done() {
+                    return;
+                }
+            }
         }
 
-        if !self.cur.as_ref().unwrap().is_done() {
-            return;
-        }
+        self.query_more();
+    }
 
-        // say that we have visited all destinations
-        for c in self.cur.as_mut().unwrap().iter() {
-            self.visited.insert(*c);
-        }
+    /// handles the finished query to `asked`
+    fn process(&mut self, asked: Entry, sendh: UM::SendHandle<KadMsg>) {
+        let ktable = self.ktable.clone();
+        let mut ktab = ktable.lock().unwrap();
+        let proven = |bytes: Vec<u8>, proof: &Proof| {
+            let ok = proof.verify(&bytes) == Some(asked.get_id());
+            if !ok {
+                // whoever answered doesn't own the id we were told about
+                warn!("{} answered a lookup without proving it is {}", asked.get_addr(), asked.get_id());
+            }
+            ok
+        };
+        let answer = match sendh.borrow_single_answer() {
+            Some(KadMsg::Answer(ans, proof)) if proven(answer_bytes(self.nonce, ans), proof) => Some(ans),
+            Some(KadMsg::Value(value, proof)) if proven(answer_bytes(self.nonce, value), proof) => {
+                self.state.insert(asked.get_addr(), PeerState::Responded);
+                learn_route(self.udpman, &asked);
+                ktab.offer(asked);
+                if value.publisher(self.lookup_id).is_none() {
+                    // the node we asked is fine, the value isn't
+                    warn!("{} had a value for {} that wasn't properly signed", asked.get_addr(), self.lookup_id);
+                    return;
+                }
+                self.value = Some((value.value.clone(), value.ttl()));
+                self.in_flight.clear();
+                self.done = true;
+                return;
+            },
+            Some(KadMsg::Answer(..)) | Some(KadMsg::Value(..)) => None,
+            Some(_) => {
+                error!("id lookup got something that was not KadMsg::Answer");
+                None
+            },
+            None => None,
+        };
 
-        // process all responses
-        {
-            let mut ktab = self.ktable.lock().unwrap();
-            for c in self.cur.as_ref().unwrap().iter() {
-                // remove dead
-                if self.cur.as_ref().unwrap().is_dead(c) {
-                    let before = self.map.get(c).unwrap();
-                    ktab.delete_id(*before);
-                    self.best.delete_id(*before);
-                } else {
-                    // is alive, add it
-                    if let KadMsg::Answer(ans) = self.cur.as_ref().unwrap().borrow_answer(c) {
-                        for a in ans {
-                            if !self.visited.contains(&a.get_addr()) {
-                                ktab.offer(*a);
-                                self.best.offer(*a);
-                            }
-                        }
-                    } else {
-                        error!("id lookup got something that was not KadMsg::Answer");
+        match answer {
+            Some(ans) => {
+                // is alive and verified, add it. The entries in the
+                // answer are only added once they have answered us themselves
+                self.state.insert(asked.get_addr(), PeerState::Responded);
+                learn_route(self.udpman, &asked);
+                ktab.offer(asked);
+                for a in ans {
+                    if !self.state.contains_key(&a.get_addr()) {
+                        learn_entry(self.udpman, a);
+                        self.add_candidate(*a);
                     }
                 }
-            }
+            },
+            None => {
+                self.state.insert(asked.get_addr(), PeerState::Failed);
+                ktab.delete_id(asked.get_id());
+            },
         }
+    }
 
-        // start a new round
-        let all = self.best.get(u32::max_value());
-        let kbest = &all[..std::cmp::min(all.len(), K+1)];
-        if self.has_looked_at_all(kbest) {
-            self.cur = None;
-        } else {
-            self.map.clear();
-            for b in all.iter() {
-                if self.map.len() >= K {
-                    break;
-                }
-                if !self.visited.contains(&b.get_addr()) {
-                    self.map.insert(b.get_addr(), b.get_id());
-                }
-            }
-            self.cur = Some(UM::send(
-                self.udpman,
-                &self.msg,
-                self.map.keys().map(|k| *k).collect(),
-                super::KAD_SERVICE
-            ));
+    /// inserts `e` into `candidates`, keeping them sorted
+    fn add_candidate(&mut self, e: Entry) {
+        if self.candidates.iter().any(|c| c.get_addr() == e.get_addr()) {
+            return;
         }
+        let dist = e.get_id().distance(&self.lookup_id);
+        let pos = self.candidates
+            .iter()
+            .position(|c| c.get_id().distance(&self.lookup_id) > dist)
+            .unwrap_or(self.candidates.len());
+        self.candidates.insert(pos, e);
+    }
+
+    /// the `K` closest candidates that haven't failed
+    fn closest_alive(&self) -> Vec<Entry> {
+        self.candidates
+            .iter()
+            .filter(|c| self.state.get(&c.get_addr()) != Some(&PeerState::Failed))
+            .take(K)
+            .cloned()
+            .collect()
     }
 
-    /// is every entry in `best` inside `visited`?
-    fn has_looked_at_all(&self, best: &[Entry]) -> bool {
-        for x in best.iter() {
-            if !self.visited.contains(&x.get_addr()) {
-                return false;
+    /// fills up the free query slots with the closest unqueried candidates
+    /// among the `K` closest, or finishes the lookup once all of those have answered
+    fn query_more(&mut self) {
+        let closest = self.closest_alive();
+        if closest.iter().all(|c| self.state.get(&c.get_addr()) == Some(&PeerState::Responded)) {
+            // converged, queries still in flight can't change the answer
+            self.in_flight.clear();
+            self.done = true;
+            return;
+        }
+
+        for c in closest {
+            if self.in_flight.len() >= self.alpha {
+                break;
+            }
+            if let hash_map::Entry::Vacant(v) = self.state.entry(c.get_addr()) {
+                v.insert(PeerState::Pending);
+                let sendh = UM::send_via(
+                    self.udpman,
+                    &self.msg,
+                    c.get_addr(),
+                    c.get_relay(),
+                    self.service,
+                    UM::Priority::High
+                );
+                self.in_flight.push((c, sendh));
             }
         }
-        true
     }
 
     /// turns this lookup into the actual answer
     pub fn into_answer(self) -> Vec<Entry> {
-        self.best.get(K as u32)
+        self.closest_alive()
+    }
+
+    /// the id being looked up
+    pub fn target(&self) -> Id {
+        self.lookup_id
+    }
+
+    /// how many queries are waiting for an answer
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// how many nodes the lookup has heard of
+    pub fn candidates(&self) -> usize {
+        self.candidates.len()
+    }
+
+    /// the value a finished value lookup found, and its ttl left
+    pub fn take_value(&mut self) -> Option<(Vec<u8>, Duration)> {
+        self.value.take()
     }
 }
 
-/// creates a ktable in a mutex for cross thread use
-pub fn create_ktable(my_id: Id) -> Arc<Mutex<Ktable>> {
-    Arc::new(Mutex::new(Ktable::new(K as u32, my_id)))
+/// stores a value at the `K` closest nodes to its key
+pub struct Put<'a> {
+    udpman: &'a UM::Manager,
+    service: u32,
+    key: Id,
+    msg: KadMsg,
+    lookup: Option<IdLookup<'a>>,
+    storing: Option<UM::SendHandle<KadMsg>>,
+    stored: usize,
 }
 
-/// queries all trackers and returns the first bootstrap node that is alive
-/// returns Err(NetworkError::Timeout) if no tracker responded
-// TODO: update tracker to remove `sock`
-pub fn find_bootstrapper(udpman: &UM::Manager, sock: &UdpSocket, room_id: Id, trackers: &Vec<SocketAddr>) -> Result<Option<(SocketAddr, Id)>> {
-    let mut timedout = 0;
-    'outer:
-    for track in trackers.iter() {
-        let sess = api::LookupSession::new(sock, *track, room_id);
-        for b in sess {
-            match b {
-                Ok(adr) => {
-                    if let Some(id) = is_alive(udpman, adr) {
-                        return Ok(Some((adr, id)));
-                    }
-                },
-                Err(NetworkError::Timeout) => {
-                    info!("tracker {} timed out", track);
-                    timedout += 1;
-                    continue 'outer;
-                },
-                Err(e) => return Err(e),
+impl<'a> Put<'a> {
+    pub fn new(udpman: &'a UM::Manager, service: u32, identity: &Identity, key: Id, value: Signed, myself: Entry, ktable: Arc<Mutex<Ktable>>) -> Self {
+        Put {
+            udpman,
+            service,
+            key,
+            msg: KadMsg::Store(key, value),
+            lookup: Some(IdLookup::new(udpman, service, identity, key, myself, ktable)),
+            storing: None,
+            stored: 0,
+        }
+    }
+
+    pub fn key(&self) -> Id {
+        self.key
+    }
+
+    /// the lookup for the closest nodes, None once it is done
+    pub fn lookup(&self) -> Option<&IdLookup<'a>> {
+        self.lookup.as_ref()
+    }
+
+    /// is the value stored?
+    pub fn is_done(&self) -> bool {
+        self.lookup.is_none() && self.storing.is_none()
+    }
+
+    /// how many nodes kept the value
+    pub fn stored(&self) -> usize {
+        self.stored
+    }
+
+    /// call this over and over until it is done
+    pub fn update(&mut self) {
+        if let Some(mut lookup) = self.lookup.take() {
+            lookup.update();
+            if !lookup.is_done() {
+                self.lookup = Some(lookup);
+                return;
+            }
+            let closest: Vec<SocketAddr> = lookup.into_answer().iter().map(|e| e.get_addr()).collect();
+            if closest.is_empty() {
+                debug!("no one to store {} at", self.key);
+            } else {
+                self.storing = Some(UM::send(self.udpman, &self.msg, closest, self.service));
             }
         }
+
+        let done = match self.storing.as_mut() {
+            Some(sendh) => {sendh.update(); sendh.is_done()},
+            None => false,
+        };
+        if done {
+            let sendh = self.storing.take().unwrap();
+            self.stored = sendh.iter()
+                .filter(|a| !sendh.is_dead(a))
+                .filter(|a| match sendh.borrow_answer(a) {KadMsg::Stored(ok) => *ok, _ => false})
+                .count();
+            debug!("{} was stored at {} nodes", self.key, self.stored);
+        }
     }
-    if timedout == trackers.len() {
-        Err(NetworkError::Timeout)
-    } else {
-        Ok(None)
+}
+
+/// decodes a serialized `KadMsg` into something readable
+pub fn describe(payload: &[u8]) -> Option<String> {
+    deserialize::<KadMsg>(payload).ok().map(|m| format!("{:?}", m))
+}
+
+/// tells `udpman` which transports the node behind `e` takes.
+/// Its relay is only used for what is sent to it with `UM::send_via`
+pub fn learn_entry(udpman: &UM::Manager, e: &Entry) {
+    udpman.set_transports(e.get_addr(), e.get_transports());
+}
+
+/// like `learn_entry`, but everything to `e` goes through its relay from now on.
+/// Only for entries that answered us through that relay
+pub fn learn_route(udpman: &UM::Manager, e: &Entry) {
+    learn_entry(udpman, e);
+    if e.get_relay().is_some() {
+        udpman.set_route(e.get_addr(), e.get_relay());
     }
 }
 
-/// simply checks whether `adr` is an alive kademlia node and returns its id
-pub fn is_alive(udpman: &UM::Manager, adr: SocketAddr) -> Option<Id> {
+/// are we, `me`, one of the `K` closest nodes to `key` that we know of?
+/// Those are the nodes that hold its value
+pub fn is_closest(ktable: &Arc<Mutex<Ktable>>, me: Id, key: Id) -> bool {
+    let closest = ktable.lock().unwrap().closest_to(K as u32, key);
+    closest.len() < K || closest.iter().any(|e| me.distance(&key) < e.get_id().distance(&key))
+}
 
-    let mut sendh =
-        UM::send(
-            udpman,
-            &KadMsg::Ping,
-            vec![adr],
-            super::KAD_SERVICE
-        );
+/// creates a ktable in a mutex for cross thread use
+pub fn create_ktable(myself: &Entry) -> Arc<Mutex<Ktable>> {
+    Arc::new(Mutex::new(Ktable::new(K as u32, myself.get_id(), myself.get_addr().ip())))
+}
+
+/// pings entries without blocking and keeps those that answer, with the
+/// id they proved. A node may have restarted with a new id since we saw it
+pub struct AliveCheck<'a> {
+    udpman: &'a UM::Manager,
+    pings: Vec<(Entry, u64, UM::SendHandle<KadMsg>)>,
+    alive: Vec<Entry>,
+}
+
+impl<'a> AliveCheck<'a> {
+    /// pings all `candidates` at once. Their ids don't matter, the pong proves one
+    pub fn new(udpman: &'a UM::Manager, service: u32, candidates: Vec<Entry>) -> Self {
+        let pings = candidates.into_iter().map(|c| {
+            learn_entry(udpman, &c);
+            let (nonce, sendh) = ping(udpman, service, &c);
+            (c, nonce, sendh)
+        }).collect();
+        AliveCheck{udpman, pings, alive: Vec::new()}
+    }
+
+    /// call this over and over until it is done
+    pub fn update(&mut self) {
+        for i in (0..self.pings.len()).rev() {
+            self.pings[i].2.update();
+            if self.pings[i].2.is_done() {
+                let (c, nonce, sendh) = self.pings.remove(i);
+                if let Some(id) = pong_id(&sendh, nonce) {
+                    let mut e = Entry::with_transports(c.get_addr(), id, c.get_transports());
+                    e.set_relay(c.get_relay());
+                    learn_route(self.udpman, &e);
+                    self.alive.push(e);
+                }
+            }
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pings.is_empty()
+    }
 
-    sendh.update_wait();
+    /// those that answered, in the order they did
+    pub fn into_alive(self) -> Vec<Entry> {
+        self.alive
+    }
+}
+
+/// starts pinging `e`, through its relay if it has one, without waiting
+/// for the answer. returns the nonce the pong has to sign
+fn ping(udpman: &UM::Manager, service: u32, e: &Entry) -> (u64, UM::SendHandle<KadMsg>) {
+    let nonce = get_hash();
+    let sendh = UM::send_via(
+        udpman,
+        &KadMsg::Ping(nonce),
+        e.get_addr(),
+        e.get_relay(),
+        service,
+        UM::Priority::High
+    );
+    (nonce, sendh)
+}
 
-    match sendh.get_single_answer() {
-        Some(KadMsg::Pong(id)) => Some(id),
+/// the id a finished ping was answered with, if the answer proves it
+fn pong_id(sendh: &UM::SendHandle<KadMsg>, nonce: u64) -> Option<Id> {
+    match sendh.borrow_single_answer() {
+        Some(KadMsg::Pong(proof)) => {
+            let id = proof.verify(&pong_bytes(nonce));
+            if id.is_none() {
+                warn!("pong had an invalid signature");
+            }
+            id
+        },
         Some(_) => {warn!("answer was not Pong"); None},
         None => None,
     }
 }
 
+/// pings the least recently seen entries of full buckets and
+/// evicts those that don't answer, as in the Kademlia paper
+pub struct Evictor<'a> {
+    udpman: &'a UM::Manager,
+    service: u32,
+    ktable: Arc<Mutex<Ktable>>,
+    active: Vec<(Eviction, u64, UM::SendHandle<KadMsg>)>,
+}
+
+impl<'a> Evictor<'a> {
+    pub fn new(udpman: &'a UM::Manager, service: u32, ktable: Arc<Mutex<Ktable>>) -> Self {
+        Evictor{udpman, service, ktable, active: Vec::new()}
+    }
+
+    /// starts pings for new evictions and resolves finished ones.
+    /// call this over and over
+    pub fn update(&mut self) {
+        let new = self.ktable.lock().unwrap().take_evictions();
+        for ev in new {
+            debug!("bucket full, pinging {} before evicting it", ev.old.get_addr());
+            let (nonce, sendh) = ping(self.udpman, self.service, &ev.old);
+            self.active.push((ev, nonce, sendh));
+        }
+
+        for i in (0..self.active.len()).rev() {
+            self.active[i].2.update();
+            if self.active[i].2.is_done() {
+                let (ev, nonce, sendh) = self.active.remove(i);
+                // it has to prove the same id, otherwise someone else took its address
+                let alive = pong_id(&sendh, nonce) == Some(ev.old.get_id());
+                if !alive {
+                    debug!("evicting {} for {}", ev.old.get_addr(), ev.candidate.get_addr());
+                }
+                self.ktable.lock().unwrap().resolve_eviction(ev, alive);
+            }
+        }
+    }
+}
+
+/// the `K` closest entries to `look_id` that a lookup from `requester_entry`
+/// is answered with. The requester is added to `ktable` if it proved its entry
+fn closest_for(ktable: &Arc<Mutex<Ktable>>, look_id: Id, requester_entry: Entry, nonce: u64, proof: &Proof, sender: SocketAddr) -> Vec<Entry> {
+    let verified = proof.verify(&lookup_bytes(look_id, &requester_entry, nonce)) == Some(requester_entry.get_id());
+    if !verified {
+        warn!("{} sent a lookup with an entry it couldn't prove", sender);
+    }
+    let mut closest;
+    {
+        let mut ktab = ktable.lock().unwrap();
+        closest = ktab.closest_to(K as u32 + 1, look_id);
+        // the proof only shows the requester owns the id, not that it can be
+        // reached at the entry's address. If it didn't come from there
+        // it has to answer a ping at that address first
+        if verified && requester_entry.get_addr() == sender {
+            ktab.offer(requester_entry);
+        } else if verified {
+            ktab.offer_unverified(requester_entry);
+        }
+    }
+    closest.retain(|e| e.get_id() != requester_entry.get_id());
+    if closest.len() > K {
+        closest.pop();
+    }
+    closest
+}
+
+/// pings entries that asked to be added from another address than their own,
+/// they are only offered to the ktable if they answer with their id
+pub struct Verifier<'a> {
+    udpman: &'a UM::Manager,
+    service: u32,
+    ktable: Arc<Mutex<Ktable>>,
+    active: Vec<(Entry, u64, UM::SendHandle<KadMsg>)>,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(udpman: &'a UM::Manager, service: u32, ktable: Arc<Mutex<Ktable>>) -> Self {
+        Verifier{udpman, service, ktable, active: Vec::new()}
+    }
+
+    /// starts pings for new entries and offers those that answered.
+    /// call this over and over
+    pub fn update(&mut self) {
+        let new = self.ktable.lock().unwrap().take_to_verify();
+        for e in new {
+            if self.active.iter().any(|a| a.0.get_addr() == e.get_addr()) {
+                continue;
+            }
+            debug!("pinging {} before adding it", e.get_addr());
+            learn_entry(self.udpman, &e);
+            let (nonce, sendh) = ping(self.udpman, self.service, &e);
+            self.active.push((e, nonce, sendh));
+        }
+
+        for i in (0..self.active.len()).rev() {
+            self.active[i].2.update();
+            if self.active[i].2.is_done() {
+                let (e, nonce, sendh) = self.active.remove(i);
+                if pong_id(&sendh, nonce) == Some(e.get_id()) {
+                    learn_route(self.udpman, &e);
+                    self.ktable.lock().unwrap().offer(e);
+                } else {
+                    debug!("{} couldn't be verified as {}", e.get_addr(), e.get_id());
+                }
+            }
+        }
+    }
+}
+
 /// handles many kademlia messages
-pub fn handle_msg(servh: &UM::ServiceHandle, my_id: Id, ktable: Arc<Mutex<Ktable>>) -> Result<()> {
+pub fn handle_msg(servh: &UM::ServiceHandle, identity: &Identity, ktable: Arc<Mutex<Ktable>>, store: &mut Store) -> Result<()> {
     let mut counter = 10;
     loop {
         if counter == 0 {
@@ -254,36 +649,43 @@ pub fn handle_msg(servh: &UM::ServiceHandle, my_id: Id, ktable: Arc<Mutex<Ktable
         }
         match UM::service_get(servh) {
             None => break,
-            Some((KadMsg::Ping, sender, id)) => {
+            Some((KadMsg::Ping(nonce), sender, id)) => {
                 debug!("{} pinged me!", sender);
                 UM::service_respond(
                     servh,
-                    &KadMsg::Pong(my_id),
+                    &KadMsg::Pong(identity.prove(&pong_bytes(nonce))),
                     id,
                     sender
                 )?;
             },
-            Some((KadMsg::Lookup(look_id, requester_entry), sender, id)) => {
-                // NOTE: sender is a temporary address
-                let mut closest;
-                {
-                    let mut ktab = ktable.lock().unwrap();
-                    closest = ktab.closest_to(K as u32 + 1, look_id);
-                    ktab.offer(requester_entry);
-                }
-                closest.retain(|e| e.get_id() != requester_entry.get_id());
-                if closest.len() > K {
-                    closest.pop();
-                }
+            Some((KadMsg::Lookup(look_id, requester_entry, nonce, proof), sender, id)) => {
+                let closest = closest_for(&ktable, look_id, requester_entry, nonce, &proof, sender);
                 let clos_len = closest.len();
                 UM::service_respond(
                     servh,
-                    &KadMsg::Answer(closest),
+                    &KadMsg::Answer(closest.clone(), identity.prove(&answer_bytes(nonce, &closest))),
                     id,
                     sender
                 )?;
                 debug!("{} wanted to lookup {}, i answered with {}/{} nodes", sender, look_id, clos_len, K);
             },
+            Some((KadMsg::FindValue(key, requester_entry, nonce, proof), sender, id)) => {
+                let closest = closest_for(&ktable, key, requester_entry, nonce, &proof, sender);
+                let resp = match store.get(key) {
+                    Some(value) => {
+                        debug!("{} wanted the value of {}, i had it", sender, key);
+                        KadMsg::Value(value.clone(), identity.prove(&answer_bytes(nonce, value)))
+                    },
+                    None => KadMsg::Answer(closest.clone(), identity.prove(&answer_bytes(nonce, &closest))),
+                };
+                UM::service_respond(servh, &resp, id, sender)?;
+            },
+            Some((KadMsg::Store(key, value), sender, id)) => {
+                let ttl = value.ttl().as_secs();
+                let ok = store.put(key, value, false);
+                debug!("{} wanted me to store {} for {}s, stored: {}", sender, key, ttl, ok);
+                UM::service_respond(servh, &KadMsg::Stored(ok), id, sender)?;
+            },
             Some(_) => {
                 warn!("someone sent weird KadMsg to kad_service");
             }
@@ -291,3 +693,29 @@ pub fn handle_msg(servh: &UM::ServiceHandle, my_id: Id, ktable: Arc<Mutex<Ktable
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answer_proof_covers_the_payload() {
+        let ident = Identity::generate();
+        let entries = vec![Entry::new("127.0.0.1:1".parse().unwrap(), Id::from_u64(1))];
+        let proof = ident.prove(&answer_bytes(7, &entries));
+        assert_eq!(proof.verify(&answer_bytes(7, &entries)), Some(ident.id()));
+        let swapped = vec![Entry::new("127.0.0.1:2".parse().unwrap(), Id::from_u64(1))];
+        assert_eq!(proof.verify(&answer_bytes(7, &swapped)), None);
+        assert_eq!(proof.verify(&answer_bytes(8, &entries)), None);
+    }
+
+    #[test]
+    fn largest_value_fits_a_relayed_packet() {
+        let ident = Identity::generate();
+        let key = Id::from_u64(1);
+        let value = Signed::new(&ident, key, vec![0; ::node::store::MAX_VALUE_SIZE], Duration::from_secs(60));
+        let answer = KadMsg::Value(value.clone(), ident.prove(&answer_bytes(1, &value)));
+        // a udp packet is 512 bytes, the udpmanager and relay headers take less than 100
+        assert!(serialize(&answer).unwrap().len() <= 412);
+    }
+}
@@ -1,23 +1,66 @@
 
-use std::net::SocketAddr;
-use common::id::Id;
+use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use common::id::{Id, ID_BITS};
+use network::Transports;
 use rand::Rng;
 
 #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug, Hash)]
 pub struct Entry {
     sock: SocketAddr,
     id: Id,
+    transports: Transports,
+    /// a node that forwards packets to this one if it can't be reached directly
+    relay: Option<SocketAddr>,
 }
 
+/// a bucket holds at most this many entries with the same ip
+const MAX_PER_IP: usize = 1;
+/// and at most this many from the same /24 (ipv4) or /64 (ipv6) subnet,
+/// so one host or network can't take over a bucket
+const MAX_PER_SUBNET: usize = 2;
+/// how many entries may wait for a verifying ping at once
+const MAX_TO_VERIFY: usize = 32;
+
 pub struct Ktable {
     table: Vec<Vec<Entry>>,
     k: u32,
     id: Id,
+    /// our own ip, other nodes on this host are exempt from the ip limits
+    ip: IpAddr,
+    /// when we last heard from every entry in `table`
+    last_seen: HashMap<Id, Instant>,
+    /// evictions that haven't been handed out by `take_evictions` yet
+    evictions: Vec<Eviction>,
+    /// ids of entries that are being (or are about to be) pinged for eviction
+    evicting: HashSet<Id>,
+    /// when every bucket was last looked up in or heard from, None if never
+    refreshed: Vec<Option<Instant>>,
+    /// entries that want to be added but have to answer a ping first
+    to_verify: Vec<Entry>,
+}
+
+/// a full bucket wants to replace its least recently seen entry `old`
+/// with `candidate`, which should only happen if `old` doesn't answer a ping
+#[derive(Debug, Clone, Copy)]
+pub struct Eviction {
+    pub old: Entry,
+    pub candidate: Entry,
 }
 
+/// the entries of one bucket by its index, with when each was last seen
+pub type Bucket = (usize, Vec<(Entry, Option<Instant>)>);
+
 impl Entry {
     pub fn new(sock: SocketAddr, id: Id) -> Self {
-        Entry {sock: sock, id: id}
+        Entry::with_transports(sock, id, Transports::udp_only())
+    }
+    pub fn with_transports(sock: SocketAddr, id: Id, transports: Transports) -> Self {
+        Entry {sock, id, transports, relay: None}
+    }
+    pub fn set_relay(&mut self, relay: Option<SocketAddr>) {
+        self.relay = relay;
     }
     pub fn get_id(&self) -> Id {
         self.id
@@ -25,24 +68,153 @@ impl Entry {
     pub fn get_addr(&self) -> SocketAddr {
         self.sock
     }
+    pub fn get_transports(&self) -> Transports {
+        self.transports
+    }
+    pub fn get_relay(&self) -> Option<SocketAddr> {
+        self.relay
+    }
 }
 
 impl Ktable {
-    pub fn new(k: u32, me: Id) -> Self {
-        // use Id length ??
-        Ktable {table: vec![Vec::new(); 64], k: k, id: me}
+    pub fn new(k: u32, me: Id, ip: IpAddr) -> Self {
+        Ktable {
+            table: vec![Vec::new(); ID_BITS],
+            k,
+            id: me,
+            ip,
+            last_seen: HashMap::new(),
+            evictions: Vec::new(),
+            evicting: HashSet::new(),
+            refreshed: vec![None; ID_BITS],
+            to_verify: Vec::new(),
+        }
     }
+    /// adds `offer` if its bucket has room, or marks it as seen if it is
+    /// already known. If the bucket is full an `Eviction` of the least
+    /// recently seen entry is queued instead, see `take_evictions`
     pub fn offer(&mut self, offer: Entry) {
         if offer.id == self.id{
             return;
         }
         let (v1_index, v2_index, found) = self.index_from_id(offer.id);
-        if !found {
-            if self.table[v1_index].len() < self.k as usize {
-                self.table[v1_index].insert(v2_index, offer);
+        if found {
+            self.touch(offer.id);
+        } else if !self.has_room_for(v1_index, offer.sock.ip()) {
+            debug!("bucket {} has too many entries from around {}", v1_index, offer.sock.ip());
+        } else if self.table[v1_index].len() < self.k as usize {
+            self.table[v1_index].insert(v2_index, offer);
+            self.touch(offer.id);
+        } else {
+            let lrs = self.least_recently_seen(v1_index);
+            if !self.evicting.contains(&lrs.id) {
+                self.evicting.insert(lrs.id);
+                self.evictions.push(Eviction{old: lrs, candidate: offer});
             }
         }
     }
+    /// marks the entry with `id` as seen just now, if we have it
+    pub fn touch(&mut self, id: Id) {
+        if id != self.id && self.index_from_id(id).2 {
+            self.last_seen.insert(id, Instant::now());
+            self.mark_refreshed(id);
+        }
+    }
+    /// marks the bucket `id` belongs in as refreshed, because a lookup
+    /// for it was started or one of its entries answered
+    pub fn mark_refreshed(&mut self, id: Id) {
+        if id != self.id {
+            let v1_index = self.id.common_bits(&id) as usize;
+            self.refreshed[v1_index] = Some(Instant::now());
+        }
+    }
+    /// the bucket that has gone the longest without being refreshed,
+    /// if that is longer than `interval`.
+    /// Buckets closer than our closest neighbour are always empty so
+    /// they are never returned
+    pub fn stalest_bucket(&self, interval: Duration) -> Option<usize> {
+        let deepest = self.table.iter().rposition(|b| !b.is_empty())?;
+        let now = Instant::now();
+        (0..deepest + 1)
+            .filter(|&i| self.refreshed[i].is_none_or(|t| now.duration_since(t) >= interval))
+            .min_by_key(|&i| self.refreshed[i])
+    }
+    /// a random id that belongs in bucket `v1_index`, used to refresh it
+    pub fn random_id_in_bucket(&self, v1_index: usize) -> Id {
+        self.id.random_with_common_bits(v1_index as u32)
+    }
+    /// is there an entry with both `id` and `adr`?
+    pub fn knows(&self, adr: SocketAddr, id: Id) -> bool {
+        self.table.iter().any(|b| b.iter().any(|e| e.id == id && e.sock == adr))
+    }
+
+    /// the non-empty buckets by index, with when every entry was last seen
+    pub fn buckets(&self) -> Vec<Bucket> {
+        self.table.iter()
+            .enumerate()
+            .filter(|&(_, b)| !b.is_empty())
+            .map(|(i, b)| (i, b.iter().map(|e| (*e, self.last_seen(e.id))).collect()))
+            .collect()
+    }
+    /// every entry, the most recently seen first
+    pub fn snapshot(&self) -> Vec<Entry> {
+        let mut all: Vec<Entry> = self.table.iter().flat_map(|b| b.iter().cloned()).collect();
+        all.sort_by_key(|e| std::cmp::Reverse(self.last_seen.get(&e.id).cloned()));
+        all
+    }
+
+    /// when we last heard from the entry with `id`
+    pub fn last_seen(&self, id: Id) -> Option<Instant> {
+        self.last_seen.get(&id).cloned()
+    }
+    /// the entry in bucket `v1_index` we haven't heard from in the longest time
+    fn least_recently_seen(&self, v1_index: usize) -> Entry {
+        *self.table[v1_index]
+            .iter()
+            .min_by_key(|e| self.last_seen.get(&e.id))
+            .expect("least_recently_seen on an empty bucket")
+    }
+    /// does bucket `v1_index` stay within the ip and subnet limits with one more entry from `ip`?
+    /// Our own and local addresses are exempt so a room can be run on one
+    /// machine or network
+    fn has_room_for(&self, v1_index: usize, ip: IpAddr) -> bool {
+        if ip == self.ip || is_local(ip) {
+            return true;
+        }
+        let bucket = &self.table[v1_index];
+        let same_ip = bucket.iter().filter(|e| e.sock.ip() == ip).count();
+        let same_subnet = bucket.iter().filter(|e| same_subnet(e.sock.ip(), ip)).count();
+        same_ip < MAX_PER_IP && same_subnet < MAX_PER_SUBNET
+    }
+    /// offers `entry` once it has proven it is reachable at its address,
+    /// see `take_to_verify`. Known entries are just marked as seen
+    pub fn offer_unverified(&mut self, entry: Entry) {
+        if self.knows(entry.sock, entry.id) {
+            self.touch(entry.id);
+        } else if self.to_verify.len() < MAX_TO_VERIFY && !self.to_verify.iter().any(|e| e.sock == entry.sock) {
+            self.to_verify.push(entry);
+        }
+    }
+    /// hands out the entries that have to be pinged before they are offered
+    pub fn take_to_verify(&mut self) -> Vec<Entry> {
+        std::mem::take(&mut self.to_verify)
+    }
+    /// hands out the evictions that full buckets want done.
+    /// Every one of them must be given back to `resolve_eviction`
+    pub fn take_evictions(&mut self) -> Vec<Eviction> {
+        std::mem::take(&mut self.evictions)
+    }
+    /// finishes an eviction. If `old` answered it stays and counts as seen,
+    /// otherwise it is replaced by the candidate
+    pub fn resolve_eviction(&mut self, ev: Eviction, old_alive: bool) {
+        self.evicting.remove(&ev.old.id);
+        if old_alive {
+            self.touch(ev.old.id);
+        } else {
+            self.delete_id(ev.old.id);
+            self.offer(ev.candidate);
+        }
+    }
     pub fn offer_replace(&mut self, offer: Entry) {
         if offer.id == self.id{
             return;
@@ -50,8 +222,10 @@ impl Ktable {
         let (v1_index, v2_index, found) = self.index_from_id(offer.id);
         if !found {
             self.table[v1_index].insert(v2_index, offer);
+            self.touch(offer.id);
             if self.table[v1_index].len() as u32 > self.k{
-                self.table[v1_index].pop();
+                let popped = self.table[v1_index].pop().unwrap();
+                self.last_seen.remove(&popped.id);
             }
         }
     }
@@ -62,6 +236,7 @@ impl Ktable {
         let (v1_index, v2_index, found) = self.index_from_id(id);
         if found {
             self.table[v1_index].remove(v2_index);
+            self.last_seen.remove(&id);
         }
     }
     pub fn delete_entry(&mut self, entry: Entry) {
@@ -71,10 +246,12 @@ impl Ktable {
         let (v1_index, v2_index, found) = self.index_from_id(entry.id);
         if found {
             self.table[v1_index].remove(v2_index);
+            self.last_seen.remove(&entry.id);
         }
     }
     pub fn clear(&mut self) {
         self.table.clear();
+        self.last_seen.clear();
     }
     pub fn get(&self, n: u32) -> Vec<Entry> {
         let mut result:Vec<Entry> = Vec::new();
@@ -117,21 +294,15 @@ impl Ktable {
     }
     pub fn random(&self) -> Option<Entry>{
         //Create weightings for selecting v1
-        let mut weightings = vec![0; 64];
-        let mut total = 0;
-        for i in 0..64{
-            let weight = self.table[i].len();
-            weightings[i] = weight;
-            total += weight;
-        }
+        let weightings: Vec<usize> = self.table.iter().map(|b| b.len()).collect();
+        let total: usize = weightings.iter().sum();
         if total == 0 {
             return None;
         }
         let mut rng = rand::thread_rng();
         let mut num = rng.gen_range(0, total) + 1; //[1 - total]
         let mut v1_selection = 0;
-        for i in 0..64{
-            let weighting = weightings[i];
+        for (i, &weighting) in weightings.iter().enumerate() {
             if weighting as u32 != 0 {
                 if num > weighting {
                     num -= weighting;
@@ -168,3 +339,50 @@ impl Ktable {
         (v1_index, v2_index, found)
     }
 }
+
+/// is `ip` loopback, private or link-local? Those are our own network,
+/// where many nodes can share an ip or subnet
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        // fc00::/7 is unique local, fe80::/10 link-local
+        IpAddr::V6(ip) => ip.is_loopback() || ip.segments()[0] & 0xfe00 == 0xfc00 || ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// are `a` and `b` in the same /24 (ipv4) or /64 (ipv6)?
+fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..3] == b.octets()[..3],
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.segments()[..4] == b.segments()[..4],
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// offers entries that all fall in the same bucket, from `ips`
+    fn offer_all(ips: &[[u8; 4]], my_ip: [u8; 4]) -> usize {
+        let mut ktab = Ktable::new(3, Id::from_u64(0), IpAddr::from(my_ip));
+        for (i, ip) in ips.iter().enumerate() {
+            ktab.offer(Entry::new(SocketAddr::from((*ip, 4000 + i as u16)), Id::from_u64(0x10 + i as u64)));
+        }
+        ktab.closest_to(10, Id::from_u64(0)).len()
+    }
+
+    #[test]
+    fn one_entry_per_public_ip() {
+        assert_eq!(offer_all(&[[8, 8, 8, 8], [8, 8, 8, 8], [8, 8, 8, 9]], [1, 1, 1, 1]), 2);
+        assert_eq!(offer_all(&[[8, 8, 8, 7], [8, 8, 8, 8], [8, 8, 8, 9]], [1, 1, 1, 1]), 2);
+    }
+
+    #[test]
+    fn local_and_own_ips_are_exempt() {
+        assert_eq!(offer_all(&[[192, 168, 1, 2]; 3], [1, 1, 1, 1]), 3);
+        assert_eq!(offer_all(&[[169, 254, 1, 2]; 3], [1, 1, 1, 1]), 3);
+        assert_eq!(offer_all(&[[127, 0, 0, 1]; 3], [1, 1, 1, 1]), 3);
+        assert_eq!(offer_all(&[[8, 8, 8, 8]; 3], [8, 8, 8, 8]), 3);
+    }
+}
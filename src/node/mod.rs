@@ -1,23 +1,91 @@
 pub mod nethandle;
 pub mod bot;
+pub mod inspect;
+pub mod presence;
 mod ktable;
 mod netthread;
 mod kademlia;
 mod cache;
+mod store;
+mod peers;
 mod broadcast;
+mod room;
+mod causal;
+mod history;
+mod overlay;
+mod direct;
 
 use std::net::SocketAddr;
 use common::id::Id;
-use std::time::SystemTime;
+use common::hlc::Timestamp;
+use common::identity::{Identity, Proof};
+use common::roomkey::RoomKeys;
+use common::get_hash;
+use bincode::serialize;
+use std::time::{Duration, SystemTime};
+use network::stream::{self, STREAM_SERVICE};
+use network::relay::{self, RELAY_SERVICE};
 
+/// the kinds of services every room has, see `room_service`
 const KAD_SERVICE: u32 = 1;
 const BROADCAST_SERVICE: u32 = 2;
+/// the stream port history is asked for on, stream ports don't collide with services
+const HISTORY_SERVICE: u32 = 3;
+const OVERLAY_SERVICE: u32 = 4;
+const DIRECT_SERVICE: u32 = 5;
+
+/// the service number the `kind` service of `room` is registered at.
+/// Every room gets its own services, so several rooms can share one udpmanager
+fn room_service(room: Id, kind: u32) -> u32 {
+    let b = room.as_bytes();
+    let prefix = (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32;
+    // the top bit is dropped so we stay clear of the reserved services near u32::MAX
+    (prefix >> 4) << 3 | kind
+}
 
 #[derive(Debug, Clone)]
 pub enum FromNetMsg {
     Error(Option<String>),
     NewMsg(Message),
-    NotSent,
+    /// messages of the room sent before we joined or while we were away, oldest first.
+    /// Messages we already had are left out
+    Backlog(Id, Vec<Message>),
+    /// how far the message with this id, sent to the room, has come
+    Status(Id, u64, SendStatus),
+    /// a `ToNetMsg::Put` to the room finished, the value was stored at this many nodes
+    Stored(Id, Id, usize),
+    /// answer to `ToNetMsg::Get` in the room, None if no one had the value
+    Value(Id, Id, Option<Vec<u8>>),
+    /// we are now in this room
+    Joined(Id),
+    /// we left this room
+    Left(Id),
+    /// someone in the room joined, left or changed their state, us included
+    Presence(Id, presence::Member),
+    /// a private message to us
+    Direct(DirectMessage),
+    /// if the direct message with this id, sent in the room, reached its receiver
+    DirectDelivered(Id, u64, bool),
+    /// answer to `ToNetMsg::Snapshot`
+    Snapshot(inspect::NodeSnapshot),
+}
+
+/// decodes the payload of a udpmanager message sent to `service`.
+/// `service` 0 means it was a response, which is either a `KadMsg`
+/// or an empty broadcast ack.
+/// returns None if it couldn't be decoded
+pub fn describe_payload(service: u32, payload: &[u8]) -> Option<String> {
+    match service {
+        0 if payload.is_empty() => Some("Ack".to_string()),
+        0 => kademlia::describe(payload),
+        STREAM_SERVICE => stream::describe(payload),
+        RELAY_SERVICE => relay::describe(payload),
+        s if s & 7 == KAD_SERVICE => kademlia::describe(payload),
+        s if s & 7 == BROADCAST_SERVICE => broadcast::describe(payload),
+        s if s & 7 == OVERLAY_SERVICE => overlay::describe(payload),
+        s if s & 7 == DIRECT_SERVICE => direct::describe(payload),
+        _ => None,
+    }
 }
 
 impl FromNetMsg {
@@ -30,28 +98,146 @@ impl FromNetMsg {
 pub enum ToNetMsg {
     /// Request termination of the network thread.
     Terminate,
-    NewMsg(String)
+    /// sends a message with this id to everyone in the room
+    NewMsg(Id, u64, String),
+    /// stores a value in the room's DHT for the given duration
+    Put(Id, Id, Vec<u8>, Duration),
+    /// looks up a value in the room's DHT
+    Get(Id, Id),
+    /// joins the room, asking these trackers if no saved peer is around
+    Join(RoomKeys, Vec<SocketAddr>),
+    /// takes the new keys of a room we are in after they were rotated
+    Rekey(RoomKeys),
+    /// tells all rooms we are away, or back if false
+    SetAway(bool),
+    /// sends a private message with this id to the user, found through the room
+    Direct(Id, Id, u64, String),
+    /// leaves the room
+    Leave(Id),
+    /// asks the closest nodes of the room for the messages in the range
+    SyncHistory(Id, HistoryRange),
+    /// asks for a `NodeSnapshot` of the routing table and what the node is doing
+    Snapshot,
+}
+
+/// what happened to a message we sent, see `FromNetMsg::Status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendStatus {
+    /// taken by the network thread, waiting to be sent
+    Queued,
+    /// was pushed to this many peers so far
+    Sent(usize),
+    /// no one could be reached after retrying, or it was too long
+    Failed,
+    /// about this many other members of the room got it, counted up along
+    /// the tree it was pushed on. Acks aren't signed, so a member of the
+    /// room could make the count bigger than it is
+    Acked(usize),
+}
+
+/// which part of a room's history to ask for
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum HistoryRange {
+    /// everything sent since then
+    Since(SystemTime),
+    /// everything delivered after the message with this id
+    After(u64),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    id: u64,
     msg: String,
     sender_id: Id,
     sender_name: String,
-    timestamp: SystemTime,
+    room_id: Id,
+    /// set when it is sent, see `CausalQueue`
+    clock: Timestamp,
+    /// the messages that have to be delivered before this one, with their clocks
+    deps: Vec<(u64, Timestamp)>,
+    /// the sender's signature of all of the above, None until it is signed.
+    /// Others drop it without one
+    proof: Option<Proof>,
+    /// if the signature was checked and is the sender's, set by whoever receives it
+    #[serde(skip)]
+    verified: bool,
     is_myself: bool,
 }
 
+/// a private message from one user to another
+#[derive(Debug, Clone)]
+pub struct DirectMessage {
+    id: u64,
+    /// the room the sender found us through
+    room_id: Id,
+    sender_id: Id,
+    sender_name: String,
+    msg: String,
+    sent: SystemTime,
+}
+
+impl DirectMessage {
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+    pub fn get_room_id(&self) -> Id {
+        self.room_id
+    }
+    /// proven, the message could only be sealed by the owner of this id
+    pub fn get_sender_id(&self) -> Id {
+        self.sender_id
+    }
+    pub fn get_sender_name(&self) -> &String {
+        &self.sender_name
+    }
+    pub fn get_message(&self) -> &String {
+        &self.msg
+    }
+    /// when it was sent, by the sender's clock
+    pub fn get_timestamp(&self) -> SystemTime {
+        self.sent
+    }
+}
+
 impl Message {
-    fn new(msg: String, sender_id: Id, sender_name: String, is_myself: bool) -> Self {
+    fn new(id: u64, msg: String, sender_id: Id, sender_name: String, room_id: Id, is_myself: bool) -> Self {
         Message {
+            id: id,
             msg: msg,
             sender_id: sender_id,
             sender_name: sender_name,
-            timestamp: SystemTime::now(),
+            room_id: room_id,
+            clock: Timestamp::default(),
+            deps: Vec::new(),
+            proof: None,
+            verified: false,
             is_myself: is_myself,
         }
     }
+
+    /// what the signature is made over, everything but the signature and local flags
+    fn signed_bytes(&self) -> Vec<u8> {
+        serialize(&(self.id, &self.msg, self.sender_id, &self.sender_name, self.room_id, self.clock, &self.deps))
+            .expect("could not serialize msg")
+    }
+
+    /// makes `identity` the sender and signs the message, after it is stamped
+    fn sign(&mut self, identity: &Identity) {
+        self.sender_id = identity.id();
+        self.proof = Some(identity.prove(&self.signed_bytes()));
+        self.verified = true;
+    }
+
+    /// checks the signature of a received message.
+    /// false if it isn't signed, or not by the sender or not over this content
+    fn check(&mut self) -> bool {
+        self.verified = match self.proof {
+            Some(ref proof) => proof.verify(&self.signed_bytes()) == Some(self.sender_id),
+            None => false,
+        };
+        self.verified
+    }
+
     pub fn get_message(&self) -> &String {
         &self.msg
     }
@@ -61,10 +247,57 @@ impl Message {
     pub fn get_sender_name(&self) -> &String {
         &self.sender_name
     }
+    pub fn get_room_id(&self) -> Id {
+        self.room_id
+    }
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+    /// when it was sent, by the sender's clock
     pub fn get_timestamp(&self) -> SystemTime {
-        self.timestamp
+        self.clock.to_system_time()
+    }
+    pub fn get_clock(&self) -> Timestamp {
+        self.clock
+    }
+    /// if the message was signed by the key `get_sender_id` belongs to,
+    /// so it really is from them. Anyone can claim any name though
+    pub fn is_verified(&self) -> bool {
+        self.verified
     }
     pub fn is_myself(&self) -> bool {
         self.is_myself
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> Message {
+        Message::new(1, "hi".to_string(), Id::from_u64(0), "a".to_string(), Id::from_u64(2), false)
+    }
+
+    #[test]
+    fn only_signed_messages_pass() {
+        assert!(!message().check());
+
+        let alice = Identity::generate();
+        let mut m = message();
+        m.sign(&alice);
+        m.verified = false;
+        assert!(m.check());
+        assert!(m.is_verified());
+
+        m.msg = "bye".to_string();
+        assert!(!m.check());
+    }
+
+    #[test]
+    fn the_signature_must_be_the_senders() {
+        let mut m = message();
+        m.sign(&Identity::generate());
+        m.sender_id = Identity::generate().id();
+        assert!(!m.check());
+    }
+}
@@ -1,8 +1,14 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::mpsc::{TryRecvError, Receiver, Sender, channel};
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 use log;
+use common::identity::Identity;
+use common::roomkey::RoomKeys;
+use node::presence::Member;
 
 use super::*;
 
@@ -12,21 +18,62 @@ pub enum SendError {
     // Dropped,
 }
 
+/// optional behaviour of a node
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// record every sent and received packet to this file
+    pub trace_file: Option<String>,
+    /// forward packets for nodes that can't be reached directly
+    pub relay: bool,
+    /// register with this relay and tell others to reach us through it
+    pub use_relay: Option<SocketAddr>,
+    /// keep the node's keypair, and with it its id, in this file.
+    /// The user's messages are signed with it too.
+    /// A new keypair is made every start if this is None
+    pub key_file: Option<String>,
+    /// save the routing table of the room in this directory and
+    /// try to rejoin through it before asking the tracker
+    pub state_dir: Option<String>,
+    /// answer every connection to this port on localhost with a
+    /// JSON `NodeSnapshot`, for inspecting a running node
+    pub control_port: Option<u16>,
+    /// how many peers a broadcast is pushed to, 3 if None.
+    /// More is faster and survives more failures, but sends more duplicates
+    pub fanout: Option<usize>,
+    /// push every message to every peer instead of only the ids to most,
+    /// to compare the traffic of the two
+    pub flood: bool,
+}
+
 pub struct NetHandle {
     join_handle: JoinHandle<()>,
     channel_in: Sender<ToNetMsg>,
     channel_out: Receiver<FromNetMsg>,
+    /// the room `send_message` sends to
+    room_id: Id,
+    /// the id our messages are signed as
+    user_id: Id,
+    /// the members of each room we are in, kept up to date by `read`
+    members: RefCell<HashMap<Id, HashMap<Id, Member>>>,
 }
 
 impl NetHandle {
+    /// starts a node in the room of `room`
     pub fn new(
-        user_id: Id,
         user_name: String,
-        room_id: Id,
-        trackers: Vec<SocketAddr>
+        room: RoomKeys,
+        trackers: Vec<SocketAddr>,
+        options: Options
     ) -> Self {
         log::debug!("Initializing new `NetHandle`");
 
+        let identity = match options.key_file {
+            Some(ref f) => Identity::load_or_create(f).expect("couldn't read or create the key file"),
+            None => Identity::generate(),
+        };
+        let user_id = identity.id();
+        let room_id = room.id();
+
         let (chan_out_send, chan_out_recv) = channel();
         let (chan_in_send, chan_in_recv) = channel();
 
@@ -34,16 +81,20 @@ impl NetHandle {
             netthread::run(
                 chan_in_recv,
                 chan_out_send,
-                user_id,
+                identity,
                 user_name,
-                room_id,
-                trackers);
+                room,
+                trackers,
+                options);
         });
 
         NetHandle {
             join_handle: jhandle,
             channel_in: chan_in_send,
             channel_out: chan_out_recv,
+            room_id,
+            user_id,
+            members: RefCell::new(HashMap::new()),
         }
     }
 
@@ -53,17 +104,115 @@ impl NetHandle {
     /// Err(SendError::Disconnected) if the nethandle died
     pub fn read(&self) -> Result<Option<FromNetMsg>, SendError> {
         match self.channel_out.try_recv() {
-            Ok(ok) => Ok(Some(ok)),
+            Ok(ok) => {
+                match ok {
+                    FromNetMsg::Presence(room, ref m) => {
+                        self.members.borrow_mut().entry(room).or_default().insert(m.get_id(), m.clone());
+                    }
+                    FromNetMsg::Left(room) => {
+                        self.members.borrow_mut().remove(&room);
+                    }
+                    _ => (),
+                }
+                Ok(Some(ok))
+            }
             Err(TryRecvError::Empty) => Ok(None),
             Err(TryRecvError::Disconnected) => Err(SendError::Disconnected),
         }
 }
 
-    /// sends `msg` to all other connected nodes.
-    /// returns the message struct that was sent
-    pub fn send_message(&self, msg: String) -> Result<(), SendError> {
-        self.send_to_net(ToNetMsg::NewMsg(msg))?;
-        Ok(())
+    /// leaves all rooms and waits for the net thread to finish
+    pub fn terminate(self) {
+        if self.channel_in.send(ToNetMsg::Terminate).is_ok() {
+            let _ = self.join_handle.join();
+        }
+    }
+
+    /// the id of the node, which the messages we send are signed as
+    pub fn user_id(&self) -> Id {
+        self.user_id
+    }
+
+    /// everyone we know of in `room`, as of the last `read`.
+    /// Members that left are kept, as `PresenceState::Offline`
+    pub fn members(&self, room: Id) -> Vec<Member> {
+        self.members.borrow().get(&room).map(|m| m.values().cloned().collect()).unwrap_or_default()
+    }
+
+    /// tells everyone in our rooms we are away, or back if `away` is false
+    pub fn set_away(&self, away: bool) -> Result<(), SendError> {
+        self.send_to_net(ToNetMsg::SetAway(away))
+    }
+
+    /// the room the node joined when it was started
+    pub fn room(&self) -> Id {
+        self.room_id
+    }
+
+    /// sends `msg` to all other nodes in the room the node was started with.
+    /// Returns the id the `FromNetMsg::Status` of the message will have
+    pub fn send_message(&self, msg: String) -> Result<u64, SendError> {
+        let room = self.room_id;
+        self.send_message_to(room, msg)
+    }
+
+    /// sends `msg` to all other nodes in `room`, see `send_message`
+    pub fn send_message_to(&self, room: Id, msg: String) -> Result<u64, SendError> {
+        let id = get_hash();
+        self.send_to_net(ToNetMsg::NewMsg(room, id, msg)).map(|_| id)
+    }
+
+    /// sends `msg` to the user `to` only, who is found through `room`.
+    /// Returns the id the `FromNetMsg::DirectDelivered` telling if it
+    /// arrived will have
+    pub fn send_direct(&self, room: Id, to: Id, msg: String) -> Result<u64, SendError> {
+        let id = get_hash();
+        self.send_to_net(ToNetMsg::Direct(room, to, id, msg)).map(|_| id)
+    }
+
+    /// joins another room on the same socket, asking `trackers` for
+    /// someone to bootstrap to if no saved peer of the room is around.
+    /// `read` gives a `FromNetMsg::Joined` once we are in
+    pub fn join_room(&self, room: RoomKeys, trackers: Vec<SocketAddr>) -> Result<(), SendError> {
+        self.send_to_net(ToNetMsg::Join(room, trackers))
+    }
+
+    /// gives the node the keys of a room it is in after they were rotated,
+    /// from now on our messages are sealed with the newest one
+    pub fn rekey(&self, room: RoomKeys) -> Result<(), SendError> {
+        self.send_to_net(ToNetMsg::Rekey(room))
+    }
+
+    /// leaves `room`, `read` gives a `FromNetMsg::Left` once we are out
+    pub fn leave_room(&self, room: Id) -> Result<(), SendError> {
+        self.send_to_net(ToNetMsg::Leave(room))
+    }
+
+    /// asks the closest nodes of `room` for the messages in `range`.
+    /// They come back from `read` as a `FromNetMsg::Backlog`, without
+    /// the messages we already have
+    pub fn sync_history(&self, room: Id, range: HistoryRange) -> Result<(), SendError> {
+        self.send_to_net(ToNetMsg::SyncHistory(room, range))
+    }
+
+    /// stores `value` under `key` in the DHT of `room` for `ttl`.
+    /// Values can be at most 180 bytes and are kept for at most a day.
+    /// They are signed by us, and the key is ours until the value expires.
+    /// The result comes back from `read` as a `FromNetMsg::Stored`
+    pub fn put(&self, room: Id, key: Id, value: Vec<u8>, ttl: Duration) -> Result<(), SendError> {
+        self.send_to_net(ToNetMsg::Put(room, key, value, ttl))
+    }
+
+    /// looks up the value under `key` in the DHT of `room`.
+    /// The result comes back from `read` as a `FromNetMsg::Value`
+    pub fn get(&self, room: Id, key: Id) -> Result<(), SendError> {
+        self.send_to_net(ToNetMsg::Get(room, key))
+    }
+
+    /// asks for a snapshot of the routing table and what the node is doing.
+    /// The result comes back from `read` as a `FromNetMsg::Snapshot`
+    pub fn request_snapshot(&self) -> Result<(), SendError> {
+        self.send_to_net(ToNetMsg::Snapshot)
     }
 
     fn send_to_net(&self, msg: ToNetMsg) -> Result<(), SendError> {
@@ -1,119 +1,111 @@
 use std::sync::mpsc::{Receiver, TryRecvError, Sender};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::thread;
+use std::net::TcpListener;
 
 use super::*;
 use network::NetworkError;
 use network::udpmanager as UM;
 use network::udp;
-use common::id::Id;
-use tracker::api;
-use common::timer::Timer;
-use node::broadcast::BroadcastManager;
+use network::trace::Recorder;
+use common::identity::Identity;
+use common::roomkey::RoomKeys;
+use node::nethandle::Options;
+use node::room::{Node, Room};
+use node::inspect::NodeSnapshot;
+use node::presence::PresenceState;
+use serde_json;
 
 const THREAD_SLEEP: Duration = Duration::from_millis(30);
+/// how long we wait for our relay to tell us our address at start
+const RELAY_WAIT: Duration = Duration::from_secs(2);
+/// how long we wait for the rooms to hear that we leave when terminating
+const LEAVE_WAIT: Duration = Duration::from_secs(1);
 
 pub fn run(chan_in: Receiver<ToNetMsg>,
            chan_out: Sender<FromNetMsg>,
-           user_id: Id,
+           identity: Identity,
            user_name: String,
-           room_id: Id,
-           trackers: Vec<SocketAddr>
+           room: RoomKeys,
+           trackers: Vec<SocketAddr>,
+           options: Options
 ) {
 
     let kad_sock = udp::open_any().unwrap();
     let local_addr = kad_sock.local_addr().unwrap();
-    let track_sock = udp::open_any().unwrap();
-    let my_id = Id::new_random();
-    let myself = ktable::Entry::new(local_addr, my_id);
-    let ktab = kademlia::create_ktable(my_id);
-
-    let udpman = UM::Manager::start(kad_sock);
-    let kad_service = udpman.register_service(KAD_SERVICE);
-    let broad_service = udpman.register_service(BROADCAST_SERVICE);
-
-    info!("my id is {}, and my address is {}", my_id, local_addr);
-
-    {
-        // find a bootstrapper
-        let boot_node = kademlia::find_bootstrapper(&udpman, &track_sock, room_id, &trackers).expect("no tracker responded");
-        if let Some((adr, id)) = boot_node {
-            info!("found {:?} to bootstrap to", adr);
-            ktab.lock().unwrap().offer(ktable::Entry::new(adr, id));
-            kademlia::IdLookup::new(
-                &udpman,
-                my_id,
-                myself,
-                ktab.clone()
-            ).update_wait();
-        } else {
-            info!("you are the first one to connect to this room");
-            // TODO: periodically check the trackers if something just goofed
+    let my_id = identity.id();
+
+    let recorder = options.trace_file.and_then(|f| {
+        Recorder::create(&f)
+            .inspect(|_| info!("recording a packet trace to {}", f))
+            .map_err(|e| error!("couldn't create trace file {}: {}", f, e))
+            .ok()
+    });
+    let udpman = UM::Manager::start_traced(kad_sock, recorder);
+    let mut myself = ktable::Entry::with_transports(local_addr, my_id, udpman.transports());
+    if options.relay {
+        udpman.enable_relay();
+    }
+    if let Some(relay) = options.use_relay {
+        udpman.use_relay(relay);
+        // the relay only forwards to the address it sees us as, which
+        // behind a NAT isn't `local_addr`
+        match udpman.relay_address(RELAY_WAIT) {
+            Some(observed) => myself = ktable::Entry::with_transports(observed, my_id, udpman.transports()),
+            None => warn!("relay {} didn't answer, others may not reach us through it", relay),
         }
+        myself.set_relay(Some(relay));
+    }
 
-        // ongoing id lookup
-        let mut looking: Option<kademlia::IdLookup> = None;
-        let mut broadcast_man = BroadcastManager::new(ktab.clone(), broad_service, &udpman, chan_out.clone(), my_id);
+    info!("my id is {}, and my address is {}", my_id, myself.get_addr());
+
+    let control = options.control_port.and_then(|port| {
+        TcpListener::bind(("127.0.0.1", port))
+            .and_then(|l| l.set_nonblocking(true).map(|_| l))
+            .inspect(|_| info!("answering snapshot requests on 127.0.0.1:{}", port))
+            .map_err(|e| error!("couldn't listen for snapshot requests on port {}: {}", port, e))
+            .ok()
+    });
+
+    let node = Node {
+        udpman,
+        identity,
+        myself,
+        chan_out: chan_out.clone(),
+        user_name,
+        state_dir: options.state_dir,
+        fanout: options.fanout.unwrap_or(overlay::DEFAULT_FANOUT),
+        flood: options.flood,
+    };
 
-        let mut tracker_timer = Timer::new_expired();
-        let mut lookup_timer = Timer::from_millis(1000*20);
+    {
+        // the rooms we are in, all of them share the udpmanager
+        let mut rooms: Vec<Room> = Vec::new();
+        join(&node, &mut rooms, room, trackers);
 
         'main:
         loop {
-            // check if we need to update ourself in the tracker
-            // TODO: we are only assuming we have one tracker
-            if tracker_timer.expired(0.95) {
-                debug!("we are now updating ourselves in a (the) tracker");
-                udp::clear(&track_sock).unwrap();
-                // can block for potentially long time
-                match api::update(&track_sock,
-                                  room_id,
-                                  local_addr,
-                                  trackers[0])
-                {
-                    Ok(ttl) => {
-                        debug!("we are updated for {} seconds", ttl.as_secs());
-                        tracker_timer.reset_with(ttl);
-                    },
-                    Err(NetworkError::Timeout) => {
-                        let again = 60;
-                        warn!("tracker on update is not responding, trying again in {}s", again);
-                        tracker_timer.reset_with(Duration::from_secs(again));
-                    },
-                    Err(e) => {
-                        error!("tracker update severe error {:?}", e);
-                        tracker_timer.disable();
-                    }
+            for i in (0..rooms.len()).rev() {
+                if let Err(e) = rooms[i].update() {
+                    let room = rooms.remove(i);
+                    join_failed(&node, room.id(), e);
                 }
             }
 
-            //handle kademlia messages
-            kademlia::handle_msg(&kad_service, my_id, ktab.clone()).expect("io error from handle_msg");
-
-            // update an ongoing id lookup
-            if looking.is_some() {
-                looking.as_mut().unwrap().update();
-                if looking.as_ref().unwrap().is_done() {
-                    debug!("id lookup finished");
-                    looking = None;
-                    lookup_timer.reset();
+            // someone wants to inspect us
+            if let Some(ref l) = control {
+                if let Ok((mut conn, from)) = l.accept() {
+                    let snap = snapshot(&node, &rooms);
+                    let res = conn.set_nonblocking(false)
+                        .and_then(|_| conn.set_write_timeout(Some(Duration::from_secs(1))))
+                        .map_err(serde_json::Error::io)
+                        .and_then(|_| serde_json::to_writer_pretty(&mut conn, &snap));
+                    if let Err(e) = res {
+                        warn!("couldn't send a snapshot to {}: {}", from, e);
+                    }
                 }
             }
 
-            //run an id_lookup on a random id to update our and all others ktables
-            if looking.is_none() && lookup_timer.expired(1.0) {
-                debug!("a random id lookup started");
-                looking = Some(kademlia::IdLookup::new(
-                    &udpman,
-                    Id::new_random(),
-                    myself,
-                    ktab.clone()
-                ));
-            }
-
-            //handle broadcasts
-            broadcast_man.update();
-
             //check if someone wants to say something
             // TODO: loop this to read more stuff?
             match chan_in.try_recv() {
@@ -121,15 +113,69 @@ pub fn run(chan_in: Receiver<ToNetMsg>,
                     info!("netthread is terminating as per request...");
                     break 'main;
                 }
-                Ok(ToNetMsg::NewMsg(ref msg)) if msg.len() > 100 => {
-                    warn!("message longer than 100 characters, didn't send it");
-                    chan_out.send(FromNetMsg::NotSent).unwrap();
+                Ok(ToNetMsg::Join(room, trackers)) => {
+                    join(&node, &mut rooms, room, trackers);
+                }
+                Ok(ToNetMsg::SetAway(away)) => {
+                    let state = if away { PresenceState::Away } else { PresenceState::Online };
+                    for room in rooms.iter_mut() {
+                        room.set_state(state);
+                    }
+                }
+                Ok(ToNetMsg::Rekey(keys)) => {
+                    match rooms.iter_mut().find(|r| r.id() == keys.id()) {
+                        Some(r) => r.rekey(&keys),
+                        None => warn!("not in room {}, ignored its new keys", keys.id()),
+                    }
+                }
+                Ok(ToNetMsg::Leave(room)) => {
+                    if let Some(i) = rooms.iter().position(|r| r.id() == room) {
+                        // dropping it unregisters its services
+                        rooms.remove(i).leave();
+                        info!("left room {}", room);
+                    }
+                    let _ = chan_out.send(FromNetMsg::Left(room));
+                }
+                Ok(ToNetMsg::NewMsg(room, id, msg)) => {
+                    match rooms.iter_mut().find(|r| r.id() == room) {
+                        Some(r) => r.send_message(id, msg),
+                        None => {
+                            warn!("not in room {}, didn't send the message", room);
+                            let _ = chan_out.send(FromNetMsg::Status(room, id, SendStatus::Failed));
+                        }
+                    }
+                }
+                Ok(ToNetMsg::Direct(room, to, id, msg)) => {
+                    match rooms.iter_mut().find(|r| r.id() == room) {
+                        Some(r) => r.send_direct(id, to, msg),
+                        None => {
+                            let _ = chan_out.send(FromNetMsg::DirectDelivered(room, id, false));
+                        }
+                    }
+                }
+                Ok(ToNetMsg::Put(room, key, value, ttl)) => {
+                    match rooms.iter_mut().find(|r| r.id() == room) {
+                        Some(r) => r.put(key, value, ttl),
+                        None => {
+                            let _ = chan_out.send(FromNetMsg::Stored(room, key, 0));
+                        }
+                    }
                 }
-                Ok(ToNetMsg::NewMsg(msg)) => {
-                    // debug!("'{}' is broadcasting '{}'", msg.get_sender_name(), msg.get_message());
-                    let m = Message::new(msg, user_id, user_name.clone(), true);
-                    chan_out.send(FromNetMsg::NewMsg(m.clone())).unwrap();
-                    broadcast_man.broadcast(m);
+                Ok(ToNetMsg::Get(room, key)) => {
+                    match rooms.iter_mut().find(|r| r.id() == room) {
+                        Some(r) => r.get(key),
+                        None => {
+                            let _ = chan_out.send(FromNetMsg::Value(room, key, None));
+                        }
+                    }
+                }
+                Ok(ToNetMsg::SyncHistory(room, range)) => {
+                    if let Some(r) = rooms.iter_mut().find(|r| r.id() == room) {
+                        r.sync_history(range);
+                    }
+                }
+                Ok(ToNetMsg::Snapshot) => {
+                    let _ = chan_out.send(FromNetMsg::Snapshot(snapshot(&node, &rooms)));
                 }
                 Err(TryRecvError::Empty) => (),
                 Err(TryRecvError::Disconnected) => {
@@ -140,9 +186,52 @@ pub fn run(chan_in: Receiver<ToNetMsg>,
 
             thread::sleep(THREAD_SLEEP);
         }
+
+        for room in rooms.iter_mut() {
+            room.leave();
+        }
+        // the others only hear that we left if it is sent before the udpmanager stops
+        let deadline = Instant::now() + LEAVE_WAIT;
+        for room in rooms.iter_mut() {
+            room.flush(deadline);
+        }
     }
     // TODO: gracefully tell everyone else that i am quitting
-    udpman.terminate();
+    node.udpman.terminate();
     info!("netthread terminated");
 }
 
+/// joins the room of `keys` unless we are already in it, and tells the user how it went
+fn join<'a>(node: &'a Node, rooms: &mut Vec<Room<'a>>, keys: RoomKeys, trackers: Vec<SocketAddr>) {
+    let room_id = keys.id();
+    if let Some(r) = rooms.iter_mut().find(|r| r.id() == room_id) {
+        r.rekey(&keys);
+        // one that is still joining tells the user once it is in
+        if !r.is_joining() {
+            let _ = node.chan_out.send(FromNetMsg::Joined(room_id));
+        }
+        return;
+    }
+    // it tells the user once it is in
+    rooms.push(Room::join(node, keys, trackers));
+}
+
+/// tells the user that joining `room_id` failed
+fn join_failed(node: &Node, room_id: Id, e: NetworkError) {
+    let msg = match e {
+        NetworkError::Timeout => format!("couldn't join room {}: no tracker responded", room_id),
+        e => format!("couldn't join room {}: {:?}", room_id, e),
+    };
+    error!("{}", msg);
+    let _ = node.chan_out.send(FromNetMsg::Error(Some(msg)));
+}
+
+/// collects what the node knows and is doing into a `NodeSnapshot`
+fn snapshot(node: &Node, rooms: &Vec<Room>) -> NodeSnapshot {
+    NodeSnapshot {
+        id: node.myself.get_id().to_string(),
+        addr: node.myself.get_addr(),
+        manager: node.udpman.stats(),
+        rooms: rooms.iter().map(|r| r.snapshot()).collect(),
+    }
+}
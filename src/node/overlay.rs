@@ -0,0 +1,425 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use rand::{thread_rng, Rng};
+use rand::seq::SliceRandom;
+
+use common::timer::Timer;
+use network::udpmanager as UM;
+use node::kademlia::learn_entry;
+use node::ktable::{Entry, Ktable};
+use bincode::deserialize;
+
+/// how many peers messages are pushed to if nothing else is configured
+pub const DEFAULT_FANOUT: usize = 3;
+/// how many peers we know of but aren't connected to, to replace failed ones
+const PASSIVE_SIZE: usize = 24;
+/// how far a join is passed on before someone takes it into the active view
+const ACTIVE_WALK: u32 = 6;
+/// at which point of a join's walk the node is put in the passive view
+const PASSIVE_WALK: u32 = 3;
+/// how far a shuffle is passed on
+const SHUFFLE_WALK: u32 = 4;
+/// how many entries a shuffle carries, small enough to fit in one packet
+const SHUFFLE_SIZE: usize = 6;
+/// how often we exchange passive views with someone
+const SHUFFLE_MS: u64 = 10 * 1000;
+/// how often active peers are checked to still be there
+const KEEPALIVE_MS: u64 = 5 * 1000;
+/// how long we wait for an answer to a join or a neighbor request
+const REQUEST_MS: u64 = 2 * 1000;
+
+/// the messages that keep the overlay together
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OverlayMsg {
+    /// a new node wants in
+    Join(Entry),
+    /// a join on its random walk, with the steps it has left
+    ForwardJoin(Entry, u32),
+    /// asks to be taken into the active view, always granted if it is a priority
+    Neighbor(Entry, bool),
+    /// answer to `Neighbor`
+    NeighborReply(Entry, bool),
+    /// the sender took us out of its active view
+    Disconnect(Entry),
+    /// a sample of the origin's views on a random walk, with the steps it has left
+    Shuffle(Entry, u32, Vec<Entry>),
+    /// a sample of the passive view of where the shuffle ended
+    ShuffleReply(Vec<Entry>),
+    /// checks that an active peer is still there
+    Alive,
+}
+
+/// decodes a serialized `OverlayMsg` into something readable
+pub fn describe(payload: &[u8]) -> Option<String> {
+    deserialize::<OverlayMsg>(payload).ok().map(|m| format!("{:?}", m))
+}
+
+/// did `from` send its own entry `e`?
+fn sent_by(e: &Entry, from: SocketAddr) -> bool {
+    if e.get_addr() != from {
+        debug!("{} sent an entry for {}, ignoring it", from, e.get_addr());
+        return false;
+    }
+    true
+}
+
+/// a HyParView membership overlay. Every node keeps a small, symmetric
+/// active view it pushes messages to, and a bigger passive view that is
+/// kept fresh by shuffles and replaces active peers that fail.
+/// The kademlia table is where we look for someone if both are empty.
+/// Entries others tell us about only go into the passive view, a node is
+/// only taken into the active view by a message it sent itself
+pub struct Overlay<'a> {
+    udpman: &'a UM::Manager,
+    service: UM::ServiceHandle,
+    service_no: u32,
+    myself: Entry,
+    ktable: Arc<Mutex<Ktable>>,
+    active: Vec<Entry>,
+    passive: Vec<Entry>,
+    active_size: usize,
+    /// the join or neighbor request we are waiting for
+    request: Option<(Entry, Timer)>,
+    /// active peers that are being checked
+    checks: Vec<(Entry, UM::SendHandle<()>)>,
+    keepalive_timer: Timer,
+    shuffle_timer: Timer,
+}
+
+impl<'a> Overlay<'a> {
+    /// `fanout` is how many peers we keep in the active view, so how many a message is pushed to
+    pub fn new(udpman: &'a UM::Manager, service: UM::ServiceHandle, service_no: u32, myself: Entry, ktable: Arc<Mutex<Ktable>>, fanout: usize) -> Self {
+        Overlay {
+            udpman,
+            service,
+            service_no,
+            myself,
+            ktable,
+            active: Vec::new(),
+            passive: Vec::new(),
+            active_size: fanout.max(1),
+            request: None,
+            checks: Vec::new(),
+            keepalive_timer: Timer::from_millis(KEEPALIVE_MS),
+            shuffle_timer: Timer::from_millis(SHUFFLE_MS),
+        }
+    }
+
+    /// the peers we are connected to
+    pub fn active(&self) -> &[Entry] {
+        &self.active
+    }
+
+    pub fn passive(&self) -> &[Entry] {
+        &self.passive
+    }
+
+    /// handles overlay messages, fills the active view, checks the peers in it and shuffles
+    pub fn update(&mut self) {
+        let mut count = 10;
+        while count > 0 {
+            count -= 1;
+            match UM::service_get::<OverlayMsg>(&self.service) {
+                Some((msg, from, id)) => {
+                    UM::service_respond(&self.service, &(), id, from).unwrap();
+                    self.handle(from, msg);
+                }
+                None => break,
+            }
+        }
+
+        // a peer that doesn't answer is gone for good
+        for i in (0..self.checks.len()).rev() {
+            self.checks[i].1.update();
+            if self.checks[i].1.is_done() {
+                let (e, sh) = self.checks.remove(i);
+                if sh.is_dead(&e.get_addr()) {
+                    self.failed(e.get_addr());
+                }
+            }
+        }
+        if self.keepalive_timer.expired(1.0) {
+            self.keepalive_timer.reset();
+            for e in self.active.clone() {
+                if !self.checks.iter().any(|&(c, _)| c == e) {
+                    let sh = UM::send(self.udpman, &OverlayMsg::Alive, vec![e.get_addr()], self.service_no);
+                    self.checks.push((e, sh));
+                }
+            }
+        }
+
+        let waiting = match self.request {
+            Some((_, ref t)) => !t.expired(1.0),
+            None => false,
+        };
+        if !waiting {
+            if let Some((e, _)) = self.request.take() {
+                // no answer, it won't be asked again
+                self.passive.retain(|p| *p != e);
+            }
+            if self.active.len() < self.active_size {
+                self.find_neighbor();
+            }
+        }
+
+        if self.shuffle_timer.expired(1.0) {
+            self.shuffle_timer.reset();
+            if let Some(to) = self.random_active(None) {
+                let mut sample = vec![self.myself];
+                sample.extend(self.sample(SHUFFLE_SIZE - 1));
+                self.send(to, OverlayMsg::Shuffle(self.myself, SHUFFLE_WALK, sample));
+            }
+        }
+    }
+
+    fn handle(&mut self, from: SocketAddr, msg: OverlayMsg) {
+        match msg {
+            OverlayMsg::Join(new) => {
+                if new == self.myself || !sent_by(&new, from) {
+                    return;
+                }
+                debug!("{} joins the overlay through us", from);
+                self.add_active(new);
+                self.send(new.get_addr(), OverlayMsg::NeighborReply(self.myself, true));
+                for e in self.active.clone() {
+                    if e != new {
+                        self.send(e.get_addr(), OverlayMsg::ForwardJoin(new, ACTIVE_WALK));
+                    }
+                }
+            }
+            OverlayMsg::ForwardJoin(new, walk) => {
+                if new == self.myself {
+                    return;
+                }
+                // it is taken in once it answers from its own address
+                if walk == 0 || self.active.len() <= 1 {
+                    self.send(new.get_addr(), OverlayMsg::Neighbor(self.myself, true));
+                    return;
+                }
+                if walk == PASSIVE_WALK {
+                    self.add_passive(new);
+                }
+                match self.random_active(Some(from)) {
+                    Some(next) => self.send(next, OverlayMsg::ForwardJoin(new, walk - 1)),
+                    None => self.send(new.get_addr(), OverlayMsg::Neighbor(self.myself, true)),
+                }
+            }
+            OverlayMsg::Neighbor(e, priority) => {
+                if !sent_by(&e, from) {
+                    return;
+                }
+                let accept = priority || self.active.len() < self.active_size || self.is_active(e.get_addr());
+                if accept {
+                    self.add_active(e);
+                } else {
+                    self.add_passive(e);
+                }
+                self.send(e.get_addr(), OverlayMsg::NeighborReply(self.myself, accept));
+            }
+            OverlayMsg::NeighborReply(e, accepted) => {
+                if !sent_by(&e, from) {
+                    return;
+                }
+                if self.request.as_ref().is_some_and(|&(r, _)| r.get_addr() == e.get_addr()) {
+                    self.request = None;
+                }
+                if accepted {
+                    self.add_active(e);
+                } else {
+                    self.add_passive(e);
+                }
+            }
+            OverlayMsg::Disconnect(e) => {
+                if sent_by(&e, from) && self.is_active(from) {
+                    debug!("{} disconnected from us", e.get_addr());
+                    self.active.retain(|a| a.get_addr() != from);
+                    self.add_passive(e);
+                }
+            }
+            OverlayMsg::Shuffle(origin, walk, sample) => {
+                if origin == self.myself {
+                    return;
+                }
+                if walk > 0 && self.active.len() > 1 {
+                    if let Some(next) = self.random_active(Some(from)) {
+                        self.send(next, OverlayMsg::Shuffle(origin, walk - 1, sample));
+                        return;
+                    }
+                }
+                let reply = self.sample(SHUFFLE_SIZE);
+                self.send(origin.get_addr(), OverlayMsg::ShuffleReply(reply));
+                for e in sample.into_iter().take(SHUFFLE_SIZE) {
+                    self.add_passive(e);
+                }
+            }
+            OverlayMsg::ShuffleReply(sample) => {
+                for e in sample.into_iter().take(SHUFFLE_SIZE) {
+                    self.add_passive(e);
+                }
+            }
+            OverlayMsg::Alive => (),
+        }
+    }
+
+    /// takes an active peer that didn't answer out of the overlay
+    pub fn failed(&mut self, adr: SocketAddr) {
+        if self.is_active(adr) {
+            debug!("{} failed, replacing it", adr);
+            self.active.retain(|a| a.get_addr() != adr);
+        }
+        self.passive.retain(|p| p.get_addr() != adr);
+    }
+
+    pub fn is_active(&self, adr: SocketAddr) -> bool {
+        self.active.iter().any(|a| a.get_addr() == adr)
+    }
+
+    /// asks someone from the passive view, or the kademlia table if we
+    /// know no one, to become our neighbor
+    fn find_neighbor(&mut self) {
+        let candidates: Vec<Entry> = self.passive.iter()
+            .filter(|p| !self.is_active(p.get_addr()))
+            .cloned()
+            .collect();
+        let timer = Timer::from_millis(REQUEST_MS);
+        if !candidates.is_empty() {
+            let e = candidates[thread_rng().gen_range(0, candidates.len())];
+            let priority = self.active.is_empty();
+            self.send(e.get_addr(), OverlayMsg::Neighbor(self.myself, priority));
+            self.request = Some((e, timer));
+        } else {
+            let contact = self.ktable.lock().unwrap().random();
+            if let Some(e) = contact {
+                if !self.is_active(e.get_addr()) {
+                    let msg = if self.active.is_empty() {
+                        OverlayMsg::Join(self.myself)
+                    } else {
+                        OverlayMsg::Neighbor(self.myself, false)
+                    };
+                    self.send(e.get_addr(), msg);
+                    self.request = Some((e, timer));
+                }
+            }
+        }
+    }
+
+    fn add_active(&mut self, e: Entry) {
+        if e == self.myself || self.is_active(e.get_addr()) {
+            return;
+        }
+        self.passive.retain(|p| p.get_addr() != e.get_addr());
+        if self.active.len() >= self.active_size {
+            let i = thread_rng().gen_range(0, self.active.len());
+            let dropped = self.active.remove(i);
+            self.send(dropped.get_addr(), OverlayMsg::Disconnect(self.myself));
+            self.add_passive(dropped);
+        }
+        debug!("{} is now an active peer", e.get_addr());
+        learn_entry(self.udpman, &e);
+        self.active.push(e);
+    }
+
+    fn add_passive(&mut self, e: Entry) {
+        if e == self.myself
+            || self.is_active(e.get_addr())
+            || self.passive.iter().any(|p| p.get_addr() == e.get_addr()) {
+            return;
+        }
+        if self.passive.len() >= PASSIVE_SIZE {
+            let i = thread_rng().gen_range(0, self.passive.len());
+            self.passive.remove(i);
+        }
+        self.passive.push(e);
+    }
+
+    fn random_active(&self, except: Option<SocketAddr>) -> Option<SocketAddr> {
+        let peers: Vec<SocketAddr> = self.active.iter()
+            .map(|a| a.get_addr())
+            .filter(|a| Some(*a) != except)
+            .collect();
+        if peers.is_empty() {
+            None
+        } else {
+            Some(peers[thread_rng().gen_range(0, peers.len())])
+        }
+    }
+
+    /// up to `n` random entries of both views
+    fn sample(&self, n: usize) -> Vec<Entry> {
+        let mut all: Vec<Entry> = self.active.iter().chain(self.passive.iter()).cloned().collect();
+        all.shuffle(&mut thread_rng());
+        all.truncate(n);
+        all
+    }
+
+    fn send(&self, to: SocketAddr, msg: OverlayMsg) {
+        let _: UM::SendHandle<()> = UM::send(self.udpman, &msg, vec![to], self.service_no);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::id::Id;
+    use std::net::UdpSocket;
+    use node::OVERLAY_SERVICE;
+
+    fn overlay<'a>(udpman: &'a UM::Manager, fanout: usize) -> Overlay<'a> {
+        let me = Entry::new("127.0.0.1:1".parse().unwrap(), Id::from_u64(1));
+        let ktable = Arc::new(Mutex::new(Ktable::new(20, me.get_id(), me.get_addr().ip())));
+        Overlay::new(udpman, udpman.register_service(OVERLAY_SERVICE), OVERLAY_SERVICE, me, ktable, fanout)
+    }
+
+    fn peer(port: u16) -> Entry {
+        Entry::new(SocketAddr::from(([127, 0, 0, 1], port)), Id::from_u64(port as u64))
+    }
+
+    #[test]
+    fn entries_are_only_taken_from_their_sender() {
+        let udpman = UM::Manager::start(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let mut o = overlay(&udpman, 3);
+        let e = peer(2000);
+        o.handle(peer(2001).get_addr(), OverlayMsg::Neighbor(e, true));
+        o.handle(peer(2001).get_addr(), OverlayMsg::Join(e));
+        o.handle(peer(2001).get_addr(), OverlayMsg::NeighborReply(e, true));
+        assert!(o.active().is_empty());
+        o.handle(e.get_addr(), OverlayMsg::Neighbor(e, true));
+        assert!(o.is_active(e.get_addr()));
+        o.handle(peer(2001).get_addr(), OverlayMsg::Disconnect(e));
+        assert!(o.is_active(e.get_addr()));
+        o.handle(e.get_addr(), OverlayMsg::Disconnect(e));
+        assert!(!o.is_active(e.get_addr()));
+    }
+
+    #[test]
+    fn forwarded_joins_must_answer_first() {
+        let udpman = UM::Manager::start(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let mut o = overlay(&udpman, 3);
+        let new = peer(2000);
+        o.handle(peer(2001).get_addr(), OverlayMsg::ForwardJoin(new, 0));
+        assert!(o.active().is_empty());
+        o.handle(new.get_addr(), OverlayMsg::NeighborReply(new, true));
+        assert!(o.is_active(new.get_addr()));
+    }
+
+    #[test]
+    fn shuffled_entries_stay_passive() {
+        let udpman = UM::Manager::start(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let mut o = overlay(&udpman, 3);
+        o.handle(peer(2001).get_addr(), OverlayMsg::Shuffle(peer(2001), 0, vec![peer(2002), peer(2003)]));
+        assert!(o.active().is_empty());
+        assert_eq!(o.passive().len(), 2);
+    }
+
+    #[test]
+    fn the_active_view_is_the_fanout() {
+        let udpman = UM::Manager::start(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let mut o = overlay(&udpman, 2);
+        for port in 2000..2005 {
+            o.handle(peer(port).get_addr(), OverlayMsg::Neighbor(peer(port), true));
+        }
+        assert_eq!(o.active().len(), 2);
+        assert_eq!(o.passive().len(), 3);
+    }
+}
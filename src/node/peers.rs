@@ -0,0 +1,43 @@
+use bincode::{deserialize_from, serialize_into};
+use common::id::Id;
+use node::ktable::Entry;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// a saved routing table, so a node can rejoin a room without the tracker
+#[derive(Serialize, Deserialize, Debug)]
+struct Snapshot {
+    room: Id,
+    /// most recently seen first
+    peers: Vec<Entry>,
+}
+
+/// the file the peers of `room` are kept in inside `dir`
+pub fn file_for(dir: &str, room: Id) -> PathBuf {
+    Path::new(dir).join(format!("{}.peers", room))
+}
+
+/// saves `peers` of `room` to `path`. The file is replaced in one go,
+/// so a crash while saving leaves the old snapshot
+pub fn save(path: &Path, room: Id, peers: Vec<Entry>) -> io::Result<()> {
+    let tmp = path.with_extension("peers.tmp");
+    {
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        serialize_into(&mut w, &Snapshot{room, peers})
+            .map_err(io::Error::other)?;
+        w.flush()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+/// loads the peers of `room` saved in `path`
+pub fn load(path: &Path, room: Id) -> io::Result<Vec<Entry>> {
+    let r = BufReader::new(File::open(path)?);
+    let snap: Snapshot = deserialize_from(r)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if snap.room != room {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the snapshot is of another room"));
+    }
+    Ok(snap.peers)
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::{thread_rng, Rng};
+
+use bincode::serialize;
+use common::id::Id;
+use common::identity::{Identity, Proof};
+use common::timer::Timer;
+
+/// how often we tell the room we are still here, in a small room
+const HEARTBEAT_MS: u64 = 20 * 1000;
+/// about how many heartbeats a room gets every `HEARTBEAT_MS`,
+/// the members of bigger rooms send theirs less often
+const ROOM_HEARTBEATS: usize = 10;
+/// a member whose last this many heartbeats didn't come is offline
+const MISSED_HEARTBEATS: u32 = 3;
+/// the most we wait before answering a new member, spread out so they
+/// don't all answer at once
+const WELCOME_MAX_MS: u64 = 2 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PresenceState {
+    Online,
+    /// still there, but the user said they are away
+    Away,
+    /// left the room, or stopped sending heartbeats
+    Offline,
+}
+
+/// someone in a room, as far as we know
+#[derive(Debug, Clone)]
+pub struct Member {
+    id: Id,
+    name: String,
+    state: PresenceState,
+    is_myself: bool,
+}
+
+impl Member {
+    pub fn get_id(&self) -> Id {
+        self.id
+    }
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+    pub fn get_state(&self) -> PresenceState {
+        self.state
+    }
+    pub fn is_myself(&self) -> bool {
+        self.is_myself
+    }
+}
+
+/// a signed "this is me and this is how I am", broadcast when joining,
+/// leaving and as a heartbeat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Announcement {
+    user_id: Id,
+    name: String,
+    state: PresenceState,
+    /// when it was sent, in milliseconds since the epoch. Older
+    /// announcements than the last one of a member are ignored, and
+    /// so are those older than a member can go without a heartbeat
+    sent: u64,
+    /// if the sender just joined and wants to hear from everyone
+    joined: bool,
+    proof: Proof,
+}
+
+impl Announcement {
+    fn new(identity: &Identity, room: Id, name: String, state: PresenceState, joined: bool) -> Self {
+        let sent = now_millis();
+        let bytes = signed_bytes(room, identity.id(), &name, state, sent, joined);
+        Announcement {
+            user_id: identity.id(),
+            name,
+            state,
+            sent,
+            joined,
+            proof: identity.prove(&bytes),
+        }
+    }
+
+    /// if the sender just joined. Everyone should hear this one
+    pub fn is_joined(&self) -> bool {
+        self.joined
+    }
+
+    /// if it was signed by the user it is about, for `room`
+    pub fn check(&self, room: Id) -> bool {
+        let bytes = signed_bytes(room, self.user_id, &self.name, self.state, self.sent, self.joined);
+        self.proof.verify(&bytes) == Some(self.user_id)
+    }
+}
+
+/// milliseconds since the epoch
+fn now_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() * 1000 + now.subsec_millis() as u64
+}
+
+// the room is signed too, so an announcement can't be replayed in another room
+fn signed_bytes(room: Id, user_id: Id, name: &String, state: PresenceState, sent: u64, joined: bool) -> Vec<u8> {
+    serialize(&(room, user_id, name, state, sent, joined)).unwrap()
+}
+
+/// who is in a room, kept up to date by the announcements of the members
+pub struct Members {
+    room_id: Id,
+    my_id: Id,
+    my_name: String,
+    my_state: PresenceState,
+    /// the members with when we last heard from them and the time of their last announcement
+    members: HashMap<Id, (Member, Instant, u64)>,
+    heartbeat_timer: Timer,
+    /// set when we just joined, so the first heartbeat says so
+    joining: bool,
+}
+
+impl Members {
+    pub fn new(room_id: Id, my_id: Id, my_name: String) -> Self {
+        let mut members = Members {
+            room_id,
+            my_id,
+            my_name: my_name.clone(),
+            my_state: PresenceState::Online,
+            members: HashMap::new(),
+            heartbeat_timer: Timer::new_expired(),
+            joining: true,
+        };
+        let me = Member{id: my_id, name: my_name, state: PresenceState::Online, is_myself: true};
+        members.members.insert(my_id, (me, Instant::now(), 0));
+        members
+    }
+
+    /// how often each member sends a heartbeat, the same for everyone
+    /// who knows about as many members
+    fn interval(&self) -> Duration {
+        let online = self.members.values().filter(|(m, _, _)| m.state != PresenceState::Offline).count();
+        Duration::from_millis(HEARTBEAT_MS) * (online / ROOM_HEARTBEATS).max(1) as u32
+    }
+
+    /// everyone we know of in the room, ourselves included
+    pub fn list(&self) -> Vec<Member> {
+        self.members.values().map(|(m, _, _)| m.clone()).collect()
+    }
+
+    /// takes in an announcement that was checked to be from who it says.
+    /// returns the member if something about them changed
+    pub fn heard(&mut self, a: Announcement) -> Option<Member> {
+        if a.user_id == self.my_id {
+            return None;
+        }
+        // someone could be replaying it, a member that left would seem to be back
+        let max_age = (self.interval() * MISSED_HEARTBEATS).as_millis() as u64;
+        if now_millis().saturating_sub(a.sent) > max_age {
+            debug!("dropped an announcement of {} that is too old", a.user_id);
+            return None;
+        }
+        if a.joined {
+            // say hello, a bit later so not everyone answers at once
+            let wait = thread_rng().gen_range(0, WELCOME_MAX_MS);
+            if self.heartbeat_timer.time_left() > Duration::from_millis(wait) {
+                self.heartbeat_timer.reset_with(Duration::from_millis(wait));
+            }
+        }
+        let changed = match self.members.get(&a.user_id) {
+            Some(&(ref m, _, sent)) => {
+                if a.sent <= sent {
+                    return None;
+                }
+                m.state != a.state || m.name != a.name
+            }
+            None => true,
+        };
+        let m = Member{id: a.user_id, name: a.name, state: a.state, is_myself: false};
+        self.members.insert(a.user_id, (m.clone(), Instant::now(), a.sent));
+        if changed {
+            Some(m)
+        } else {
+            None
+        }
+    }
+
+    /// our own announcement if it is time for a heartbeat
+    pub fn heartbeat(&mut self, identity: &Identity) -> Option<Announcement> {
+        if !self.heartbeat_timer.expired(1.0) {
+            return None;
+        }
+        let interval = self.interval();
+        self.heartbeat_timer.reset_with(interval);
+        let joined = self.joining;
+        self.joining = false;
+        Some(Announcement::new(identity, self.room_id, self.my_name.clone(), self.my_state, joined))
+    }
+
+    /// changes our own state, returns the announcement that tells the room
+    pub fn set_state(&mut self, identity: &Identity, state: PresenceState) -> Announcement {
+        self.my_state = state;
+        if let Some(&mut (ref mut me, _, _)) = self.members.get_mut(&self.my_id) {
+            me.state = state;
+        }
+        let interval = self.interval();
+        self.heartbeat_timer.reset_with(interval);
+        Announcement::new(identity, self.room_id, self.my_name.clone(), state, false)
+    }
+
+    /// marks the members we haven't heard from in a while as offline, returns them
+    pub fn update(&mut self) -> Vec<Member> {
+        let my_id = self.my_id;
+        let offline_after = self.interval() * MISSED_HEARTBEATS;
+        let mut gone = Vec::new();
+        for (id, &mut (ref mut m, seen, _)) in self.members.iter_mut() {
+            if *id != my_id && m.state != PresenceState::Offline && seen.elapsed() > offline_after {
+                m.state = PresenceState::Offline;
+                gone.push(m.clone());
+            }
+        }
+        gone
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members() -> (Members, Identity) {
+        let me = Identity::generate();
+        (Members::new(Id::from_u64(1), me.id(), "me".to_string()), me)
+    }
+
+    fn announcement(who: &Identity, state: PresenceState, joined: bool) -> Announcement {
+        Announcement::new(who, Id::from_u64(1), "them".to_string(), state, joined)
+    }
+
+    #[test]
+    fn announcements_are_for_one_room() {
+        let a = announcement(&Identity::generate(), PresenceState::Online, false);
+        assert!(a.check(Id::from_u64(1)));
+        assert!(!a.check(Id::from_u64(2)));
+    }
+
+    #[test]
+    fn only_newer_announcements_count() {
+        let (mut ms, _) = members();
+        let them = Identity::generate();
+        let mut away = announcement(&them, PresenceState::Away, false);
+        let online = announcement(&them, PresenceState::Online, false);
+        away.sent = online.sent + 1;
+        assert_eq!(ms.heard(away).unwrap().get_state(), PresenceState::Away);
+        assert!(ms.heard(online).is_none());
+        assert_eq!(ms.list().len(), 2);
+    }
+
+    #[test]
+    fn old_announcements_are_dropped() {
+        let (mut ms, _) = members();
+        let mut online = announcement(&Identity::generate(), PresenceState::Online, true);
+        online.sent -= (ms.interval() * MISSED_HEARTBEATS).as_millis() as u64 + 1000;
+        assert!(ms.heard(online).is_none());
+        assert_eq!(ms.list().len(), 1);
+    }
+
+    #[test]
+    fn newcomers_are_welcomed_soon() {
+        let (mut ms, me) = members();
+        assert!(ms.heartbeat(&me).unwrap().is_joined());
+        assert!(ms.heartbeat(&me).is_none());
+        ms.heard(announcement(&Identity::generate(), PresenceState::Online, true));
+        assert!(ms.heartbeat_timer.time_left() <= Duration::from_millis(WELCOME_MAX_MS));
+    }
+
+    #[test]
+    fn bigger_rooms_send_fewer_heartbeats() {
+        let (mut ms, _) = members();
+        assert_eq!(ms.interval(), Duration::from_millis(HEARTBEAT_MS));
+        for _ in 0..(3 * ROOM_HEARTBEATS) {
+            ms.heard(announcement(&Identity::generate(), PresenceState::Online, false));
+        }
+        assert_eq!(ms.interval(), Duration::from_millis(3 * HEARTBEAT_MS));
+        // who left doesn't count
+        for (_, &mut (ref mut m, _, _)) in ms.members.iter_mut().take(ROOM_HEARTBEATS + 1) {
+            m.state = PresenceState::Offline;
+        }
+        assert!(ms.interval() < Duration::from_millis(3 * HEARTBEAT_MS));
+    }
+}
@@ -0,0 +1,626 @@
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use std::io;
+use std::thread;
+use std::path::{Path, PathBuf};
+use std::net::{SocketAddr, UdpSocket};
+
+use super::*;
+use network::{NetworkError, Result};
+use network::udpmanager as UM;
+use network::udp;
+use network::stream::StreamListener;
+use common::id::Id;
+use common::identity::Identity;
+use common::roomkey::RoomKeys;
+use common::timer::Timer;
+use tracker::{api, BootNode};
+use node::ktable::{Entry, Ktable};
+use node::broadcast::{BroadcastManager, MAX_MESSAGE_LEN};
+use node::overlay::Overlay;
+use node::store::{Signed, Store, MAX_VALUE_SIZE};
+use node::inspect::*;
+use node::history::{self, History};
+use node::presence::{Members, PresenceState};
+use node::direct::Direct;
+
+/// a bucket that hasn't been looked up in or heard from for this long is refreshed
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// how often we look for buckets that need a refresh
+const REFRESH_CHECK_MS: u64 = 1000;
+/// stored values are stored at the closest nodes again this often,
+/// so they survive the nodes that held them leaving
+const REPUBLISH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// how often we look for values that need republishing
+const REPUBLISH_CHECK_MS: u64 = 60 * 1000;
+/// how often the routing table is saved, if there is a state dir
+const SAVE_PEERS_MS: u64 = 60 * 1000;
+/// how many of the saved peers we try to rejoin through
+const REJOIN_PEERS: usize = 8;
+/// how far back the history is asked for when joining a room
+const HISTORY_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// how many of the closest nodes history is asked from
+const HISTORY_PEERS: u32 = 2;
+
+/// what all rooms of a node share
+pub struct Node {
+    pub udpman: UM::Manager,
+    /// proves the node's id, and signs the user's messages
+    pub identity: Identity,
+    pub myself: Entry,
+    /// to the user. Sending fails once the `NetHandle` is dropped, that is
+    /// ignored, the net thread notices and terminates
+    pub chan_out: Sender<FromNetMsg>,
+    pub user_name: String,
+    pub state_dir: Option<String>,
+    /// how many peers broadcasts are pushed to
+    pub fanout: usize,
+    /// push every message to every peer instead of announcing it to most
+    pub flood: bool,
+}
+
+/// the steps of joining a room, see `Room::advance_join`
+enum Joining<'a> {
+    /// pinging the peers we knew last time
+    Rejoin(kademlia::AliveCheck<'a>),
+    /// asking the trackers for nodes in the room
+    Tracker(Receiver<Result<Vec<BootNode>>>),
+    /// pinging the nodes the trackers know
+    Boot(kademlia::AliveCheck<'a>),
+    /// looking up our own id to get to know our neighbourhood
+    Lookup(Box<kademlia::IdLookup<'a>>),
+}
+
+/// runs `f` with a socket of its own on another thread, talking to the
+/// trackers can take seconds. The result comes out of the receiver
+fn ask_trackers<T, F>(f: F) -> Receiver<Result<T>>
+where T: Send + 'static,
+      F: FnOnce(&UdpSocket) -> Result<T> + Send + 'static
+{
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let _ = tx.send(udp::open_any().and_then(|sock| f(&sock)));
+    });
+    rx
+}
+
+/// the routing table, broadcast network and DHT of one room
+pub struct Room<'a> {
+    node: &'a Node,
+    room_id: Id,
+    trackers: Vec<SocketAddr>,
+    /// how far joining has come, None once we are in the room
+    joining: Option<Joining<'a>>,
+    kad_service: UM::ServiceHandle,
+    kad_no: u32,
+    ktab: Arc<Mutex<Ktable>>,
+    // ongoing id lookup
+    looking: Option<kademlia::IdLookup<'a>>,
+    broadcast_man: BroadcastManager<'a>,
+    evictor: kademlia::Evictor<'a>,
+    verifier: kademlia::Verifier<'a>,
+    tracker_timer: Timer,
+    /// the answer to updating ourselves in the tracker, while we wait for it
+    tracker_update: Option<Receiver<Result<Duration>>>,
+    refresh_timer: Timer,
+    // the values we hold of the DHT and the ongoing puts and gets
+    store: Store,
+    puts: Vec<kademlia::Put<'a>>,
+    gets: Vec<(Id, kademlia::IdLookup<'a>)>,
+    republish_timer: Timer,
+    save_timer: Timer,
+    peers_file: Option<PathBuf>,
+    // the messages we hand out to others and the ones we are asking for
+    history: Arc<Mutex<History>>,
+    history_listener: StreamListener,
+    /// the threads that hand out and fetch history
+    transfers: history::Transfers,
+    fetches: Vec<Receiver<Vec<Message>>>,
+    /// if we had no one to broadcast to the last time we looked
+    alone: bool,
+    /// who else is in the room
+    members: Members,
+    direct: Direct<'a>,
+}
+
+impl<'a> Room<'a> {
+    /// starts joining the room of `keys` through the peers we knew last time,
+    /// or the trackers if none of them are still around. Joining goes on in
+    /// `update`, which sends `FromNetMsg::Joined` once we know our neighbourhood
+    pub fn join(node: &'a Node, keys: RoomKeys, trackers: Vec<SocketAddr>) -> Self {
+        let room_id = keys.id();
+        let udpman = &node.udpman;
+        let kad_no = room_service(room_id, KAD_SERVICE);
+        let kad_service = udpman.register_service(kad_no);
+        let broad_service = udpman.register_service(room_service(room_id, BROADCAST_SERVICE));
+        let overlay_no = room_service(room_id, OVERLAY_SERVICE);
+        let overlay_service = udpman.register_service(overlay_no);
+        let history_listener = udpman.listen(room_service(room_id, HISTORY_SERVICE));
+        let direct_no = room_service(room_id, DIRECT_SERVICE);
+        let direct_service = udpman.register_service(direct_no);
+        let ktab = kademlia::create_ktable(&node.myself);
+        let peers_file = node.state_dir.as_ref().map(|d| peers::file_for(d, room_id));
+
+        let saved = match peers_file {
+            Some(ref f) => peers::load(f, room_id).unwrap_or_else(|e| {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("couldn't load the saved peers from {}: {}", f.display(), e);
+                }
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        let joining = Joining::Rejoin(kademlia::AliveCheck::new(udpman, kad_no, saved.into_iter().take(REJOIN_PEERS).collect()));
+
+        let history = Arc::new(Mutex::new(History::new()));
+        let transfers = history::Transfers::new(history.clone());
+        let overlay = Overlay::new(udpman, overlay_service, overlay_no, node.myself, ktab.clone(), node.fanout);
+        Room {
+            node,
+            room_id,
+            trackers,
+            joining: Some(joining),
+            kad_service,
+            kad_no,
+            ktab: ktab.clone(),
+            looking: None,
+            broadcast_man: BroadcastManager::new(ktab.clone(), broad_service, udpman, node.chan_out.clone(), node.myself.get_id(), keys, history.clone(), overlay, node.flood),
+            evictor: kademlia::Evictor::new(udpman, kad_no, ktab.clone()),
+            verifier: kademlia::Verifier::new(udpman, kad_no, ktab.clone()),
+            tracker_timer: Timer::new_expired(),
+            tracker_update: None,
+            refresh_timer: Timer::from_millis(REFRESH_CHECK_MS),
+            store: Store::new(),
+            puts: Vec::new(),
+            gets: Vec::new(),
+            republish_timer: Timer::from_millis(REPUBLISH_CHECK_MS),
+            save_timer: Timer::from_millis(SAVE_PEERS_MS),
+            peers_file,
+            history,
+            history_listener,
+            transfers,
+            fetches: Vec::new(),
+            alone: true,
+            members: Members::new(room_id, node.identity.id(), node.user_name.clone()),
+            direct: Direct::new(node, room_id, direct_service, direct_no, kad_no, ktab.clone()),
+        }
+    }
+
+    /// takes joining one step further, without blocking.
+    /// returns Err if the room couldn't be joined
+    fn advance_join(&mut self) -> Result<()> {
+        let node = self.node;
+        let udpman = &node.udpman;
+        let next = match self.joining.take() {
+            None => return Ok(()),
+            Some(Joining::Rejoin(mut check)) => {
+                check.update();
+                if !check.is_done() {
+                    Joining::Rejoin(check)
+                } else {
+                    let rejoined = check.into_alive();
+                    if rejoined.is_empty() {
+                        let (room_id, trackers) = (self.room_id, self.trackers.clone());
+                        Joining::Tracker(ask_trackers(move |sock| api::find_boot_nodes(sock, room_id, &trackers, REJOIN_PEERS)))
+                    } else {
+                        info!("rejoined room {} through {} saved peers", self.room_id, rejoined.len());
+                        {
+                            let mut ktab = self.ktab.lock().unwrap();
+                            for e in rejoined.iter() {
+                                ktab.offer(*e);
+                            }
+                        }
+                        Joining::Lookup(Box::new(self.own_lookup()))
+                    }
+                }
+            }
+            Some(Joining::Tracker(rx)) => {
+                match rx.try_recv() {
+                    Err(TryRecvError::Empty) => Joining::Tracker(rx),
+                    Err(TryRecvError::Disconnected) => return Err(NetworkError::Timeout),
+                    Ok(res) => {
+                        let boots = res?.into_iter().map(|b| {
+                            // the tracker doesn't know ids, the pong proves which one it has
+                            let mut e = Entry::with_transports(b.adr, Id::from_u64(0), b.transports);
+                            e.set_relay(b.relay);
+                            e
+                        }).collect();
+                        Joining::Boot(kademlia::AliveCheck::new(udpman, self.kad_no, boots))
+                    }
+                }
+            }
+            Some(Joining::Boot(mut check)) => {
+                check.update();
+                if !check.is_done() {
+                    Joining::Boot(check)
+                } else {
+                    match check.into_alive().first() {
+                        Some(boot) => {
+                            info!("found {:?} to bootstrap to in room {}", boot.get_addr(), self.room_id);
+                            self.ktab.lock().unwrap().offer(*boot);
+                            Joining::Lookup(Box::new(self.own_lookup()))
+                        }
+                        None => {
+                            info!("you are the first one to connect to room {}", self.room_id);
+                            // TODO: periodically check the trackers if something just goofed
+                            self.joined();
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+            Some(Joining::Lookup(mut lookup)) => {
+                lookup.update();
+                if !lookup.is_done() {
+                    Joining::Lookup(lookup)
+                } else {
+                    self.joined();
+                    return Ok(());
+                }
+            }
+        };
+        self.joining = Some(next);
+        Ok(())
+    }
+
+    /// refreshes our own neighbourhood, the buckets further away
+    /// have never been refreshed and are taken care of in `update`
+    fn own_lookup(&self) -> kademlia::IdLookup<'a> {
+        let node = self.node;
+        kademlia::IdLookup::new(&node.udpman, self.kad_no, &node.identity, node.myself.get_id(), node.myself, self.ktab.clone())
+    }
+
+    /// we know our neighbourhood, tell the user and catch up with the room
+    fn joined(&mut self) {
+        let node = self.node;
+        let _ = node.chan_out.send(FromNetMsg::Joined(self.room_id));
+        for m in self.members.list() {
+            let _ = node.chan_out.send(FromNetMsg::Presence(self.room_id, m));
+        }
+        // catch up on what was said before we came
+        let since = SystemTime::now() - HISTORY_WINDOW;
+        self.sync_history(HistoryRange::Since(since));
+    }
+
+    pub fn id(&self) -> Id {
+        self.room_id
+    }
+
+    /// are we still looking for our place in the room?
+    pub fn is_joining(&self) -> bool {
+        self.joining.is_some()
+    }
+
+    /// does what needs doing in the room, call it regularly.
+    /// returns Err if joining the room failed, the room is no use then
+    pub fn update(&mut self) -> Result<()> {
+        let node = self.node;
+        let udpman = &node.udpman;
+
+        self.advance_join()?;
+
+        // check if we need to update ourself in the tracker, once we are in the room
+        // TODO: we are only assuming we have one tracker
+        if self.joining.is_none() && self.tracker_update.is_none() && !self.trackers.is_empty() && self.tracker_timer.expired(0.95) {
+            debug!("we are now updating ourselves in a (the) tracker");
+            let me = BootNode{adr: node.myself.get_addr(), transports: node.myself.get_transports(), relay: node.myself.get_relay()};
+            let (room_id, tracker) = (self.room_id, self.trackers[0]);
+            self.tracker_update = Some(ask_trackers(move |sock| api::update(sock, room_id, me, tracker)));
+        }
+        let res = match self.tracker_update.as_ref().map(|rx| rx.try_recv()) {
+            Some(Ok(res)) => Some(res),
+            Some(Err(TryRecvError::Disconnected)) => Some(Err(NetworkError::Timeout)),
+            _ => None,
+        };
+        if let Some(res) = res {
+            self.tracker_update = None;
+            match res {
+                Ok(ttl) => {
+                    debug!("we are updated for {} seconds", ttl.as_secs());
+                    self.tracker_timer.reset_with(ttl);
+                },
+                Err(NetworkError::Timeout) => {
+                    let again = 60;
+                    warn!("tracker on update is not responding, trying again in {}s", again);
+                    self.tracker_timer.reset_with(Duration::from_secs(again));
+                },
+                Err(e) => {
+                    error!("tracker update severe error {:?}", e);
+                    self.tracker_timer.disable();
+                }
+            }
+        }
+
+        //handle kademlia messages
+        kademlia::handle_msg(&self.kad_service, &node.identity, self.ktab.clone(), &mut self.store).expect("io error from handle_msg");
+
+        // update an ongoing id lookup
+        if self.looking.is_some() {
+            self.looking.as_mut().unwrap().update();
+            if self.looking.as_ref().unwrap().is_done() {
+                debug!("id lookup finished");
+                self.looking = None;
+            }
+        }
+
+        // refresh the bucket that has gone the longest without a lookup
+        // or an answer, by looking up a random id in its range
+        if self.looking.is_none() && self.refresh_timer.expired(1.0) {
+            self.refresh_timer.reset();
+            let stale = {
+                let ktab = self.ktab.lock().unwrap();
+                ktab.stalest_bucket(REFRESH_INTERVAL).map(|b| (b, ktab.random_id_in_bucket(b)))
+            };
+            if let Some((bucket, id)) = stale {
+                debug!("refreshing bucket {} with a lookup of {}", bucket, id);
+                self.looking = Some(kademlia::IdLookup::new(
+                    udpman,
+                    self.kad_no,
+                    &node.identity,
+                    id,
+                    node.myself,
+                    self.ktab.clone()
+                ));
+            }
+        }
+
+        // ping the least recently seen entries of full buckets
+        self.evictor.update();
+
+        // ping entries that asked to be added from somewhere else
+        self.verifier.update();
+
+        if self.save_timer.expired(1.0) {
+            self.save_timer.reset();
+            self.save_peers();
+        }
+
+        // update ongoing puts and gets
+        for i in (0..self.puts.len()).rev() {
+            self.puts[i].update();
+            if self.puts[i].is_done() {
+                let put = self.puts.remove(i);
+                let _ = node.chan_out.send(FromNetMsg::Stored(self.room_id, put.key(), put.stored()));
+            }
+        }
+        for i in (0..self.gets.len()).rev() {
+            self.gets[i].1.update();
+            if self.gets[i].1.is_done() {
+                let (key, mut lookup) = self.gets.remove(i);
+                let value = lookup.take_value().map(|(v, _)| v);
+                let _ = node.chan_out.send(FromNetMsg::Value(self.room_id, key, value));
+            }
+        }
+
+        // store the values we hold at the closest nodes again
+        if self.republish_timer.expired(1.0) {
+            self.republish_timer.reset();
+            self.store.cleanup();
+            for (key, value, mine) in self.store.due_for_republish(REPUBLISH_INTERVAL) {
+                // the others we got it from republish it themselves,
+                // and a store from them resets our timer
+                if !mine && !kademlia::is_closest(&self.ktab, node.myself.get_id(), key) {
+                    continue;
+                }
+                debug!("republishing {}", key);
+                self.puts.push(kademlia::Put::new(udpman, self.kad_no, &node.identity, key, value, node.myself, self.ktab.clone()));
+            }
+        }
+
+        //handle broadcasts
+        self.broadcast_man.update();
+
+        // private messages to and from us
+        self.direct.update();
+
+        // keep track of who is in the room, and tell them we are
+        for a in self.broadcast_man.take_presence() {
+            if let Some(m) = self.members.heard(a) {
+                let _ = node.chan_out.send(FromNetMsg::Presence(self.room_id, m));
+            }
+        }
+        for m in self.members.update() {
+            let _ = node.chan_out.send(FromNetMsg::Presence(self.room_id, m));
+        }
+        // an announcement with no one to hear it is lost, wait until we are connected
+        if !self.broadcast_man.connected().is_empty() {
+            if let Some(a) = self.members.heartbeat(&node.identity) {
+                self.broadcast_man.heartbeat(a);
+            }
+        }
+
+        // someone wants our history
+        while let Some(stream) = self.history_listener.accept() {
+            self.transfers.serve(stream, self.broadcast_man.keys().clone());
+        }
+
+        // we got someone's history
+        for i in (0..self.fetches.len()).rev() {
+            match self.fetches[i].try_recv() {
+                Ok(mut msgs) => {
+                    self.fetches.remove(i);
+                    for m in msgs.iter_mut() {
+                        m.is_myself = m.sender_id == node.identity.id();
+                    }
+                    self.broadcast_man.backlog(msgs);
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => {
+                    self.fetches.remove(i);
+                }
+            }
+        }
+
+        // we were cut off from the room, ask for what we missed
+        let alone = self.broadcast_man.connected().is_empty();
+        if self.alone && !alone {
+            let last = self.history.lock().unwrap().last_id();
+            if let Some(id) = last {
+                debug!("reconnected to room {}, asking for what we missed", self.room_id);
+                self.sync_history(HistoryRange::After(id));
+            }
+        }
+        self.alone = alone;
+        Ok(())
+    }
+
+    /// asks the closest nodes in the room for the messages in `range`,
+    /// they are delivered as a `FromNetMsg::Backlog`
+    pub fn sync_history(&mut self, range: HistoryRange) {
+        let node = self.node;
+        let closest = self.ktab.lock().unwrap().closest_to(HISTORY_PEERS, node.myself.get_id());
+        for e in closest {
+            let stream = node.udpman.connect(e.get_addr(), room_service(self.room_id, HISTORY_SERVICE));
+            self.fetches.push(self.transfers.fetch(stream, range, self.broadcast_man.keys().clone()));
+        }
+    }
+
+    /// changes how we appear to the others in the room
+    pub fn set_state(&mut self, state: PresenceState) {
+        let node = self.node;
+        let a = self.members.set_state(&node.identity, state);
+        self.broadcast_man.announce(a);
+        if let Some(me) = self.members.list().into_iter().find(|m| m.is_myself()) {
+            let _ = node.chan_out.send(FromNetMsg::Presence(self.room_id, me));
+        }
+    }
+
+    /// waits until `deadline` at most for what the room is sending to get out
+    pub fn flush(&mut self, deadline: Instant) {
+        self.broadcast_man.flush(deadline);
+    }
+
+    /// tells the room and the trackers we are leaving, call it before dropping the room
+    pub fn leave(&mut self) {
+        self.set_state(PresenceState::Offline);
+        self.save_peers();
+        let (room_id, me, trackers) = (self.room_id, self.node.myself.get_addr(), self.trackers.clone());
+        // nobody waits for the answer
+        let _ = ask_trackers(move |sock| {
+            for t in trackers {
+                if let Err(e) = api::remove(sock, room_id, me, t) {
+                    info!("couldn't tell tracker {} we left {}: {:?}", t, room_id, e);
+                }
+            }
+            Ok(())
+        });
+    }
+
+    /// takes the new keys of the room after it was rotated
+    pub fn rekey(&mut self, keys: &RoomKeys) {
+        self.broadcast_man.rekey(keys);
+    }
+
+    /// broadcasts `msg` to everyone in the room, its `FromNetMsg::Status` has `id`
+    pub fn send_message(&mut self, id: u64, msg: String) {
+        let node = self.node;
+        if msg.len() > MAX_MESSAGE_LEN {
+            warn!("message longer than {} characters, didn't send it", MAX_MESSAGE_LEN);
+            let _ = node.chan_out.send(FromNetMsg::Status(self.room_id, id, SendStatus::Failed));
+            return;
+        }
+        let m = Message::new(id, msg, node.identity.id(), node.user_name.clone(), self.room_id, true);
+        if !self.broadcast_man.fits(&m, &node.identity) {
+            warn!("message doesn't fit in a packet with the name {:?}, didn't send it", node.user_name);
+            let _ = node.chan_out.send(FromNetMsg::Status(self.room_id, id, SendStatus::Failed));
+            return;
+        }
+        self.broadcast_man.broadcast(m, &node.identity);
+    }
+
+    /// sends `msg` to the user `to` only, if they are in the room
+    pub fn send_direct(&mut self, id: u64, to: Id, msg: String) {
+        if msg.len() > MAX_MESSAGE_LEN {
+            warn!("message longer than {} characters, didn't send it", MAX_MESSAGE_LEN);
+            let _ = self.node.chan_out.send(FromNetMsg::DirectDelivered(self.room_id, id, false));
+            return;
+        }
+        self.direct.send(id, to, msg);
+    }
+
+    pub fn put(&mut self, key: Id, value: Vec<u8>, ttl: Duration) {
+        let node = self.node;
+        if value.len() > MAX_VALUE_SIZE {
+            warn!("value longer than {} bytes, didn't store it", MAX_VALUE_SIZE);
+            let _ = node.chan_out.send(FromNetMsg::Stored(self.room_id, key, 0));
+            return;
+        }
+        // we keep it too, so it can be republished from here
+        let value = Signed::new(&node.identity, key, value, ttl);
+        if !self.store.put(key, value.clone(), true) {
+            warn!("{} belongs to someone else, didn't store it", key);
+            let _ = node.chan_out.send(FromNetMsg::Stored(self.room_id, key, 0));
+            return;
+        }
+        self.puts.push(kademlia::Put::new(&node.udpman, self.kad_no, &node.identity, key, value, node.myself, self.ktab.clone()));
+    }
+
+    pub fn get(&mut self, key: Id) {
+        let node = self.node;
+        let local = self.store.get(key).map(|v| v.value.clone());
+        if local.is_some() {
+            let _ = node.chan_out.send(FromNetMsg::Value(self.room_id, key, local));
+        } else {
+            self.gets.push((key, kademlia::IdLookup::find_value(&node.udpman, self.kad_no, &node.identity, key, node.myself, self.ktab.clone())));
+        }
+    }
+
+    /// saves the routing table so we can rejoin without the tracker next time
+    pub fn save_peers(&self) {
+        if let Some(ref f) = self.peers_file {
+            save_peers(f, self.room_id, &self.ktab);
+        }
+    }
+
+    /// collects what we know and are doing in the room into a `RoomSnapshot`
+    pub fn snapshot(&self) -> RoomSnapshot {
+        let now = Instant::now();
+        let buckets = self.ktab.lock().unwrap().buckets().into_iter().map(|(i, entries)| BucketSnapshot {
+            index: i,
+            entries: entries.into_iter().map(|(e, seen)| EntrySnapshot {
+                id: e.get_id().to_string(),
+                addr: e.get_addr(),
+                relay: e.get_relay(),
+                last_seen_secs: seen.map(|t| {
+                    let d = now.duration_since(t);
+                    d.as_secs() as f64 + d.subsec_millis() as f64 / 1000.0
+                }),
+            }).collect(),
+        }).collect();
+
+        let lookup = |kind, l: &kademlia::IdLookup| LookupSnapshot {
+            kind,
+            target: l.target().to_string(),
+            in_flight: l.in_flight(),
+            candidates: l.candidates(),
+        };
+        let mut lookups: Vec<LookupSnapshot> = self.looking.iter().map(|l| lookup("refresh", l)).collect();
+        lookups.extend(self.gets.iter().map(|(_, l)| lookup("get", l)));
+        lookups.extend(self.puts.iter().filter_map(|p| p.lookup()).map(|l| lookup("put", l)));
+
+        RoomSnapshot {
+            room: self.room_id.to_string(),
+            buckets,
+            connected: self.broadcast_man.connected().into_iter()
+                .map(|(a, i)| PeerSnapshot{id: i.to_string(), addr: a, lazy: self.broadcast_man.is_lazy(a)})
+                .collect(),
+            lookups,
+            stored_values: self.store.len(),
+            held_messages: self.broadcast_man.held(),
+            passive_peers: self.broadcast_man.passive(),
+        }
+    }
+}
+
+fn save_peers(file: &Path, room_id: Id, ktab: &Arc<Mutex<Ktable>>) {
+    let snapshot = ktab.lock().unwrap().snapshot();
+    if snapshot.is_empty() {
+        // keep the old peers, they are better than nothing
+        return;
+    }
+    match peers::save(file, room_id, snapshot) {
+        Ok(()) => debug!("saved the routing table to {}", file.display()),
+        Err(e) => warn!("couldn't save the routing table to {}: {}", file.display(), e),
+    }
+}
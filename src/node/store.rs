@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use common::id::Id;
+use common::identity::{Identity, Proof};
+use bincode::serialize;
+
+/// values bigger than this don't fit in a packet together with both proofs
+pub const MAX_VALUE_SIZE: usize = 180;
+/// no value is kept for longer than this
+pub const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// how many values a node keeps for others
+const MAX_VALUES: usize = 1024;
+
+/// a value as its publisher signed it. Holders pass it on as it is,
+/// so nobody but the publisher can change it or make it live longer
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Signed {
+    pub value: Vec<u8>,
+    /// seconds since the unix epoch when it expires
+    expires: u64,
+    proof: Proof,
+}
+
+/// what the publisher of `value` under `key` signs
+fn signed_bytes(key: Id, value: &[u8], expires: u64) -> Vec<u8> {
+    serialize(&("store", key, value, expires)).unwrap()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Signed {
+    /// signs `value` under `key` for `ttl` (at most `MAX_TTL`)
+    pub fn new(identity: &Identity, key: Id, value: Vec<u8>, ttl: Duration) -> Self {
+        let expires = unix_now() + ttl.min(MAX_TTL).as_secs();
+        let proof = identity.prove(&signed_bytes(key, &value, expires));
+        Signed{value, expires, proof}
+    }
+
+    /// the id of whoever published it under `key`, None if the signature is bad
+    pub fn publisher(&self, key: Id) -> Option<Id> {
+        self.proof.verify(&signed_bytes(key, &self.value, self.expires))
+    }
+
+    /// how long it has left, at most `MAX_TTL`
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.expires.saturating_sub(unix_now())).min(MAX_TTL)
+    }
+}
+
+struct Value {
+    signed: Signed,
+    publisher: Id,
+    expires: Instant,
+    /// when the value was last stored at the closest nodes
+    published: Instant,
+    /// did we publish it ourselves?
+    mine: bool,
+}
+
+/// the values this node holds of the room's DHT.
+/// A key belongs to whoever stored it first until the value expires,
+/// only they can replace it
+pub struct Store {
+    values: HashMap<Id, Value>,
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Store{values: HashMap::new()}
+    }
+
+    /// stores `signed` under `key` until it expires, `mine` if we published it.
+    /// returns false if it isn't properly signed, is too big, another publisher
+    /// has the key, it is older than what we have or the store is full
+    pub fn put(&mut self, key: Id, signed: Signed, mine: bool) -> bool {
+        if signed.value.len() > MAX_VALUE_SIZE {
+            return false;
+        }
+        let publisher = match signed.publisher(key) {
+            Some(p) => p,
+            None => {
+                warn!("value for {} had a bad signature", key);
+                return false;
+            }
+        };
+        let ttl = signed.ttl();
+        if ttl == Duration::from_secs(0) {
+            return false;
+        }
+        let now = Instant::now();
+        let mut mine = mine;
+        if let Some(old) = self.values.get(&key).filter(|v| v.expires > now) {
+            if old.publisher != publisher {
+                debug!("{} belongs to {}, {} can't store it", key, old.publisher, publisher);
+                return false;
+            }
+            if signed.expires < old.signed.expires {
+                // an old copy, maybe replayed
+                return false;
+            }
+            mine |= old.mine;
+        }
+        if !self.values.contains_key(&key) && self.values.len() >= MAX_VALUES {
+            self.cleanup();
+            if self.values.len() >= MAX_VALUES {
+                warn!("the store is full, not storing {}", key);
+                return false;
+            }
+        }
+        self.values.insert(key, Value{signed, publisher, expires: now + ttl, published: now, mine});
+        true
+    }
+
+    /// the value under `key` as its publisher signed it
+    pub fn get(&self, key: Id) -> Option<&Signed> {
+        let now = Instant::now();
+        self.values.get(&key)
+            .filter(|v| v.expires > now)
+            .map(|v| &v.signed)
+    }
+
+    /// values that haven't been stored at the closest nodes for `interval`.
+    /// They are marked as published, returns (key, value, whether we published it)
+    pub fn due_for_republish(&mut self, interval: Duration) -> Vec<(Id, Signed, bool)> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for (k, v) in self.values.iter_mut() {
+            if v.expires > now && now.duration_since(v.published) >= interval {
+                v.published = now;
+                due.push((*k, v.signed.clone(), v.mine));
+            }
+        }
+        due
+    }
+
+    /// forgets expired values
+    pub fn cleanup(&mut self) {
+        let now = Instant::now();
+        self.values.retain(|_, v| v.expires > now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_publisher_replaces_a_value() {
+        let alice = Identity::generate();
+        let mallory = Identity::generate();
+        let key = Id::from_u64(1);
+        let mut store = Store::new();
+        assert!(store.put(key, Signed::new(&alice, key, b"a".to_vec(), Duration::from_secs(60)), false));
+        assert!(!store.put(key, Signed::new(&mallory, key, b"m".to_vec(), Duration::from_secs(60)), false));
+        assert!(store.put(key, Signed::new(&alice, key, b"b".to_vec(), Duration::from_secs(60)), false));
+        assert_eq!(store.get(key).unwrap().value, b"b".to_vec());
+    }
+
+    #[test]
+    fn changed_values_are_refused() {
+        let alice = Identity::generate();
+        let key = Id::from_u64(1);
+        let mut signed = Signed::new(&alice, key, b"a".to_vec(), Duration::from_secs(60));
+        assert!(signed.publisher(Id::from_u64(2)).is_none());
+        signed.value = b"z".to_vec();
+        assert!(!Store::new().put(key, signed, false));
+    }
+
+    #[test]
+    fn older_copies_are_refused() {
+        let alice = Identity::generate();
+        let key = Id::from_u64(1);
+        let mut store = Store::new();
+        let old = Signed::new(&alice, key, b"old".to_vec(), Duration::from_secs(60));
+        assert!(store.put(key, Signed::new(&alice, key, b"new".to_vec(), Duration::from_secs(120)), false));
+        assert!(!store.put(key, old, false));
+        assert_eq!(store.get(key).unwrap().value, b"new".to_vec());
+    }
+
+    #[test]
+    fn republishing_keeps_who_published() {
+        let alice = Identity::generate();
+        let key = Id::from_u64(1);
+        let mut store = Store::new();
+        store.put(key, Signed::new(&alice, key, b"a".to_vec(), Duration::from_secs(60)), true);
+        let due = store.due_for_republish(Duration::from_secs(0));
+        assert_eq!(due.len(), 1);
+        assert!(due[0].2);
+        assert_eq!(due[0].1.publisher(key), Some(alice.id()));
+    }
+}
@@ -0,0 +1,208 @@
+extern crate chrono;
+
+extern crate clap;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+extern crate peas_rf_cp;
+use peas_rf_cp::network::trace::{Direction, Reader, Record};
+use peas_rf_cp::network::{udp, udpmanager};
+use peas_rf_cp::node;
+use peas_rf_cp::tracker;
+
+use chrono::offset::Utc;
+use chrono::DateTime;
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+const CMD_SHOW: &str = "show";
+const CMD_REPLAY: &str = "replay";
+const ARG_FILE: &str = "file";
+const ARG_SERVICE: &str = "service";
+const ARG_PEER: &str = "peer";
+const ARG_DIRECTION: &str = "direction";
+const ARG_ID: &str = "id";
+const ARG_TO: &str = "to";
+const ARG_REALTIME: &str = "realtime";
+
+/// decides which records to look at
+struct Filter {
+    service: Option<u32>,
+    peer: Option<SocketAddr>,
+    direction: Option<Direction>,
+    id: Option<u64>,
+}
+
+impl Filter {
+    fn from_matches<'a>(matches: &ArgMatches<'a>) -> Self {
+        Filter {
+            service: matches.value_of(ARG_SERVICE).map(|s| s.parse().unwrap_or_else(|_| fail("service must be a number"))),
+            peer: matches.value_of(ARG_PEER).map(|s| s.parse().unwrap_or_else(|_| fail("peer must be ip:port"))),
+            direction: match matches.value_of(ARG_DIRECTION) {
+                Some("sent") => Some(Direction::Sent),
+                Some("received") => Some(Direction::Received),
+                None => None,
+                Some(_) => unreachable!(),
+            },
+            id: matches.value_of(ARG_ID).map(|s| s.parse().unwrap_or_else(|_| fail("id must be a number"))),
+        }
+    }
+
+    fn accepts(&self, rec: &Record) -> bool {
+        self.service.is_none_or(|s| s == rec.service)
+            && self.peer.is_none_or(|p| p == rec.peer)
+            && self.direction.is_none_or(|d| d == rec.direction)
+            && self.id.is_none_or(|i| i == rec.id)
+    }
+}
+
+fn main() {
+    let matches = create_app().get_matches();
+
+    match matches.subcommand() {
+        (CMD_SHOW, Some(sub)) => show(sub),
+        (CMD_REPLAY, Some(sub)) => replay(sub),
+        _ => unreachable!(),
+    }
+}
+
+/// prints every record that passes the filter
+fn show<'a>(matches: &ArgMatches<'a>) {
+    let filter = Filter::from_matches(matches);
+
+    for rec in open(matches) {
+        if filter.accepts(&rec) {
+            println!("{}", format_record(&rec));
+        }
+    }
+}
+
+/// sends every received record that passes the filter to a node,
+/// as if it came from the network
+fn replay<'a>(matches: &ArgMatches<'a>) {
+    let filter = Filter::from_matches(matches);
+    let to = matches.value_of(ARG_TO).unwrap()
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut a| a.next())
+        .unwrap_or_else(|| fail("could not resolve the replay destination"));
+    let realtime = matches.is_present(ARG_REALTIME);
+
+    let sock = udp::open_any().unwrap_or_else(|e| fail(&format!("could not open a socket: {}", e)));
+    let mut last = None;
+    let mut count = 0;
+
+    for rec in open(matches) {
+        if rec.direction != Direction::Received || !filter.accepts(&rec) {
+            continue;
+        }
+        if realtime {
+            if let Some(prev) = last {
+                if let Ok(gap) = rec.time.duration_since(prev) {
+                    thread::sleep(gap);
+                }
+            }
+            last = Some(rec.time);
+        } else {
+            thread::sleep(Duration::from_millis(5));
+        }
+        if let Err(e) = udpmanager::send_msg(&sock, rec.id, rec.service, &rec.payload, to) {
+            eprintln!("could not replay a packet: {}", e);
+        }
+        count += 1;
+    }
+    println!("replayed {} packets to {}", count, to);
+}
+
+/// reads all records of the trace, stopping at the first broken one
+fn open<'a>(matches: &ArgMatches<'a>) -> Vec<Record> {
+    let file = matches.value_of(ARG_FILE).unwrap();
+    let reader = Reader::open(file).unwrap_or_else(|e| fail(&format!("could not open {}: {}", file, e)));
+    let mut res = Vec::new();
+    for rec in reader {
+        match rec {
+            Ok(r) => res.push(r),
+            Err(e) => {
+                eprintln!("trace is broken after {} records: {}", res.len(), e);
+                break;
+            }
+        }
+    }
+    res
+}
+
+fn format_record(rec: &Record) -> String {
+    let datetime: DateTime<Utc> = rec.time.into();
+    let arrow = match rec.direction {
+        Direction::Sent => "->",
+        Direction::Received => "<-",
+    };
+    let decoded = node::describe_payload(rec.service, &rec.payload)
+        .or_else(|| tracker::describe(&rec.payload))
+        .unwrap_or_else(|| format!("<{} undecodable bytes>", rec.payload.len()));
+    format!("[{}] {} {} service={} id={} {}",
+            datetime.format("%T%.3f"), arrow, rec.peer, rec.service, rec.id, decoded)
+}
+
+fn fail(why: &str) -> ! {
+    eprintln!("{}", why);
+    process::exit(1);
+}
+
+fn filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name(ARG_FILE)
+            .help("Trace file recorded with --trace")
+            .required(true)
+            .index(1),
+        Arg::with_name(ARG_SERVICE)
+            .long("service")
+            .short("s")
+            .help("Only packets to this service (0 is responses)")
+            .takes_value(true),
+        Arg::with_name(ARG_PEER)
+            .long("peer")
+            .short("p")
+            .help("Only packets to or from this address")
+            .takes_value(true),
+        Arg::with_name(ARG_DIRECTION)
+            .long("direction")
+            .short("d")
+            .help("Only packets going this way")
+            .takes_value(true)
+            .possible_values(&["sent", "received"]),
+        Arg::with_name(ARG_ID)
+            .long("id")
+            .help("Only packets belonging to this session id")
+            .takes_value(true),
+    ]
+}
+
+fn create_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("peas-trace")
+        .version("0.0.0-alpha")
+        .about("Decodes and replays packet traces recorded by the client")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name(CMD_SHOW)
+                .about("Prints the decoded packets of a trace")
+                .args(&filter_args()),
+        ).subcommand(
+            SubCommand::with_name(CMD_REPLAY)
+                .about("Sends the received packets of a trace to a node")
+                .args(&filter_args())
+                .arg(
+                    Arg::with_name(ARG_TO)
+                        .long("to")
+                        .help("Address of the node to replay into")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name(ARG_REALTIME)
+                        .long("realtime")
+                        .help("Keeps the original time between packets"),
+                ),
+        )
+}
@@ -1,10 +1,21 @@
-
 use std::net::{SocketAddr, UdpSocket};
 use std::time::Duration;
 use super::*;
 use common::id::Id;
 use network::udp::*;
-use network::Result;
+use network::{tcp, NetworkError, Result};
+
+const RETRIES: u32 = 3;
+const TIMEOUT: Duration = Duration::from_millis(50);
+/// TCP has its own retransmissions so it only gets one, longer, attempt
+const TCP_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// how to reach a tracker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
 
 pub struct LookupSession<'a> {
     sock: &'a UdpSocket,
@@ -12,6 +23,7 @@ pub struct LookupSession<'a> {
     id: Id,
     last_lookup: u32,
     empty: bool,
+    transport: Transport,
 }
 
 impl<'a> LookupSession<'a> {
@@ -22,17 +34,23 @@ impl<'a> LookupSession<'a> {
             adr: track,
             id: room,
             last_lookup: 0,
-            empty: false
+            empty: false,
+            transport: Transport::Udp,
         }
     }
 
+    /// the transport the session currently talks to the tracker over
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
 }
 
 impl<'a> Iterator for LookupSession<'a> {
-    type Item = Result<SocketAddr>;
+    type Item = Result<BootNode>;
 
-    /// returns Ok(adr) with the next address from the tracker
-    /// Err(NetworkError::Timeout) if the tracker isn't responding
+    /// returns Ok(node) with the next node from the tracker.
+    /// If the tracker doesn't answer over UDP the session switches to TCP for good.
+    /// Err(NetworkError::Timeout) if the tracker isn't responding on either
     /// Err(_) if a severe network error occured
     /// returns None if the last thing was an error or if there aren't any more
     /// addresses from the tracker.
@@ -43,17 +61,22 @@ impl<'a> Iterator for LookupSession<'a> {
         let if_lookup = |r: &TrackResp| {r.is_lookup()};
 
         let q = TrackQuery::Lookup{id: self.id, last_lookup: self.last_lookup};
-        let resp = send_with_response(self.sock, &q, self.adr, 3, Duration::from_millis(50), if_lookup);
+        let mut resp = query(self.sock, &q, self.adr, self.transport, if_lookup);
+        if let (Err(NetworkError::Timeout), Transport::Udp) = (&resp, self.transport) {
+            info!("tracker {} doesn't answer over UDP, trying TCP", self.adr);
+            self.transport = Transport::Tcp;
+            resp = query(self.sock, &q, self.adr, self.transport, if_lookup);
+        }
 
         match resp {
             Err(e) => {
                 self.empty = true;
                 return Some(Err(e));
             },
-            Ok(TrackResp::LookupAns{adr, lookup_id}) => {
+            Ok(TrackResp::LookupAns{adr, transports, relay, lookup_id}) => {
                 if let Some(a) = adr {
                     self.last_lookup = lookup_id;
-                    return Some(Ok(a));
+                    return Some(Ok(BootNode{adr: a, transports, relay}));
                 }
                 self.empty = true;
                 return None;
@@ -65,16 +88,23 @@ impl<'a> Iterator for LookupSession<'a> {
     }
 }
 
-/// updates the room `room` at tracker `tracker` using `sock`. `my_adr` is the address
-/// the tracker should add to its database
+/// updates the room `room` at tracker `tracker` using `sock`. `me` is the node
+/// the tracker should add to its database.
+/// Falls back to TCP if the tracker doesn't answer over UDP
 /// returns Ok(ttl) which is the amount of time the entry will stay in the tracker
 /// Err(NetworkError::Timeout) if the tracker isn't responding
 /// Err(_) for something else
-pub fn update(sock: &UdpSocket, room: Id, my_adr: SocketAddr, tracker: SocketAddr) -> Result<Duration> {
+pub fn update(sock: &UdpSocket, room: Id, me: BootNode, tracker: SocketAddr) -> Result<Duration> {
     let if_update = |r: &TrackResp| {r.is_update()};
 
-    let q = TrackQuery::Update{id: room, adr: my_adr};
-    let resp = send_with_response(sock, &q, tracker, 3, Duration::from_millis(50), if_update)?;
+    let q = TrackQuery::Update{id: room, adr: me.adr, transports: me.transports, relay: me.relay};
+    let resp = match query(sock, &q, tracker, Transport::Udp, if_update) {
+        Err(NetworkError::Timeout) => {
+            info!("tracker {} doesn't answer over UDP, trying TCP", tracker);
+            query(sock, &q, tracker, Transport::Tcp, if_update)?
+        }
+        other => other?,
+    };
 
     if let TrackResp::UpdateSuccess{ttl, ..} = resp {
         return Ok(ttl);
@@ -83,3 +113,57 @@ pub fn update(sock: &UdpSocket, room: Id, my_adr: SocketAddr, tracker: SocketAdd
     }
     // Err(NetworkError::Other("update api failed for some reason (should never happen)"))
 }
+
+/// tells `tracker` that `me` left `room`, so it isn't handed out anymore.
+/// The tracker only accepts this from the ip of `me`
+pub fn remove(sock: &UdpSocket, room: Id, me: SocketAddr, tracker: SocketAddr) -> Result<()> {
+    let if_remove = |r: &TrackResp| {r.is_remove()};
+
+    let q = TrackQuery::Remove{id: room, adr: me};
+    match query(sock, &q, tracker, Transport::Udp, if_remove) {
+        Err(NetworkError::Timeout) => query(sock, &q, tracker, Transport::Tcp, if_remove).map(|_| ()),
+        other => other.map(|_| ()),
+    }
+}
+
+/// asks all `trackers` for nodes in `room`, at most `max` of them.
+/// returns Err(NetworkError::Timeout) if no tracker responded
+pub fn find_boot_nodes(sock: &UdpSocket, room: Id, trackers: &[SocketAddr], max: usize) -> Result<Vec<BootNode>> {
+    let mut timedout = 0;
+    let mut nodes = Vec::new();
+    'outer:
+    for track in trackers.iter() {
+        for b in LookupSession::new(sock, *track, room) {
+            match b {
+                Ok(boot) => {
+                    nodes.push(boot);
+                    if nodes.len() >= max {
+                        break 'outer;
+                    }
+                },
+                Err(NetworkError::Timeout) => {
+                    info!("tracker {} timed out", track);
+                    timedout += 1;
+                    continue 'outer;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    if timedout == trackers.len() {
+        Err(NetworkError::Timeout)
+    } else {
+        Ok(nodes)
+    }
+}
+
+/// sends `q` to `tracker` over `transport` and waits for an answer fulfilling `pred`
+fn query<F>(sock: &UdpSocket, q: &TrackQuery, tracker: SocketAddr, transport: Transport, pred: F) -> Result<TrackResp>
+where
+    F: Fn(&TrackResp) -> bool,
+{
+    match transport {
+        Transport::Udp => send_with_response(sock, q, tracker, RETRIES, TIMEOUT, pred),
+        Transport::Tcp => tcp::send_with_response(q, tracker, TCP_TIMEOUT, pred),
+    }
+}
@@ -2,8 +2,18 @@ pub mod server;
 pub mod api;
 
 use common::id::Id;
+use network::Transports;
 use std::net::SocketAddr;
 use std::time::Duration;
+use bincode::deserialize;
+
+/// a node the tracker hands out to bootstrap to, and how to reach it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BootNode {
+    pub adr: SocketAddr,
+    pub transports: Transports,
+    pub relay: Option<SocketAddr>,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 /// things that can be requested of the tracker
@@ -14,6 +24,17 @@ enum TrackQuery {
         id: Id,
         /// address where the room lives
         adr: SocketAddr,
+        /// transports the node at `adr` accepts
+        transports: Transports,
+        /// relay the node at `adr` is reachable through, if any
+        relay: Option<SocketAddr>,
+    },
+    /// forget a node that left a room, only the node itself may ask
+    Remove {
+        /// room id the node left
+        id: Id,
+        /// address of the node
+        adr: SocketAddr,
     },
     /// check where a room exists
     Lookup {
@@ -34,15 +55,31 @@ enum TrackResp {
         /// it will now be remembered for this long
         ttl: Duration,
     },
+    /// TrackQuery::Remove was handled, the node is gone if it was allowed
+    RemoveSuccess {
+        id: Id,
+    },
     /// TrackQuery::Lookup was successful
     LookupAns {
         /// this is the node to connect to (if one was found)
         adr: Option<SocketAddr>,
+        /// transports the node at `adr` accepts
+        transports: Transports,
+        /// relay the node at `adr` is reachable through, if any
+        relay: Option<SocketAddr>,
         /// reference to supply in the next request
         lookup_id: u32,
     }
 }
 
+/// decodes a serialized `TrackQuery` or `TrackResp` into something readable
+pub fn describe(payload: &[u8]) -> Option<String> {
+    if let Ok(q) = deserialize::<TrackQuery>(payload) {
+        return Some(format!("{:?}", q));
+    }
+    deserialize::<TrackResp>(payload).ok().map(|r| format!("{:?}", r))
+}
+
 impl TrackResp {
     pub fn is_lookup(&self) -> bool {
         match self {
@@ -56,4 +93,7 @@ impl TrackResp {
             _ => false,
         }
     }
+    pub fn is_remove(&self) -> bool {
+        matches!(self, TrackResp::RemoveSuccess{..})
+    }
 }
@@ -1,10 +1,18 @@
 
-use std::net::{IpAddr,UdpSocket,SocketAddr};
+use std::net::{IpAddr,UdpSocket,SocketAddr,TcpListener,TcpStream};
 use common::id::Id;
 use std::collections::HashMap;
 use std::time::{Duration,Instant};
-use super::{TrackResp,TrackQuery};
-use network::udp;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread;
+use super::{TrackResp,TrackQuery,BootNode};
+use network::{udp,tcp,Transports,NetworkError};
+
+/// how long a TCP client may stay silent before we hang up
+const TCP_IDLE: Duration = Duration::from_secs(10);
+/// the most TCP clients served at once, each one has a thread
+const MAX_TCP_CLIENTS: usize = 64;
 
 /// maps room ids to several entry (bootstrap) nodes
 struct Data(HashMap<Id, Vec<Boot>>);
@@ -13,6 +21,10 @@ struct Data(HashMap<Id, Vec<Boot>>);
 struct Boot {
     /// address to a entry node
     adr: SocketAddr,
+    /// transports the entry node accepts
+    transports: Transports,
+    /// relay the entry node is reachable through
+    relay: Option<SocketAddr>,
     /// the time the entry was added
     ttl: Instant,
     /// strictly increasing counter to act as an id for every boot
@@ -27,9 +39,12 @@ impl PartialEq for Boot {
 
 impl Boot {
     /// add a new boot
-    fn new(adr: SocketAddr, counter: &mut u32) -> Boot {
+    fn new(node: BootNode, counter: &mut u32) -> Boot {
         *counter += 1;
-        Boot {adr: adr, ttl: Instant::now(), counter: *counter}
+        Boot {adr: node.adr, transports: node.transports, relay: node.relay, ttl: Instant::now(), counter: *counter}
+    }
+    fn node(&self) -> BootNode {
+        BootNode{adr: self.adr, transports: self.transports, relay: self.relay}
     }
 }
 
@@ -38,40 +53,56 @@ impl Data {
         Data(HashMap::new())
     }
 
-    /// add `adr` as an bootstrap node for a room with id `id`.
-    /// if `adr` already exists for `id`, then update the ttl for it.
+    /// add `node` as an bootstrap node for a room with id `id`.
+    /// if `node` already exists for `id`, then update the ttl (and the rest) for it.
     /// `counter` is a global variable for ids.
-    fn update(&mut self, counter: &mut u32, id: Id, adr: SocketAddr) {
+    fn update(&mut self, counter: &mut u32, id: Id, node: BootNode) {
         let data = &mut self.0;
         if !data.contains_key(&id) {
-            data.insert(id, vec![Boot::new(adr, counter)]);
+            data.insert(id, vec![Boot::new(node, counter)]);
         } else if let Some(ref mut x) = data.get_mut(&id) {
 
             let mut contained = false;
             for ele in x.iter_mut() {
-                if ele.adr == adr {
+                if ele.adr == node.adr {
                     ele.ttl = Instant::now();
+                    ele.transports = node.transports;
+                    ele.relay = node.relay;
                     contained = true;
                     break;
                 }
             }
 
             if ! contained {
-                x.push(Boot::new(adr, counter));
+                x.push(Boot::new(node, counter));
             }
         }
     }
 
-    /// find the address and counter for the next bootstrap node for room with id `id`.
+    /// removes `adr` as a bootstrap node for the room with id `id`
+    fn remove(&mut self, id: Id, adr: SocketAddr) {
+        let empty = match self.0.get_mut(&id) {
+            Some(x) => {
+                x.retain(|b| b.adr != adr);
+                x.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.0.remove(&id);
+        }
+    }
+
+    /// find the node and counter for the next bootstrap node for room with id `id`.
     /// `counter` is the counter of the previously looked up node for room `id`,
     /// This makes sure that we aren't returning the same node twice.
     /// If this is the first lookup for `id`, then use `counter` = 0.
     /// If there aren't any nodes left in the "database", then `None` is returned.
-    fn lookup(&self, id: Id, counter: u32) -> Option<(SocketAddr, u32)> {
+    fn lookup(&self, id: Id, counter: u32) -> Option<(BootNode, u32)> {
         if let Some(ref x) = self.0.get(&id) {
             for ele in x.iter() {
                 if ele.counter > counter {
-                    return Some((ele.adr, ele.counter))
+                    return Some((ele.node(), ele.counter))
                 }
             }
         }
@@ -115,46 +146,163 @@ fn prtmadr(ma: Option<SocketAddr>) -> String {
     }
 }
 
+/// everything the tracker knows, shared between the UDP loop and TCP clients
+struct State {
+    data: Data,
+    counter: u32,
+    oldest_sys_time: Instant,
+    boot_ttl: Duration,
+}
+
+impl State {
+    /// answers one query from `sender`
+    fn handle(&mut self, query: TrackQuery, sender: SocketAddr) -> TrackResp {
+        let resp = match query {
+            TrackQuery::Update{id, adr, transports, relay} => {
+                self.data.update(&mut self.counter, id, BootNode{adr, transports, relay});
+                debug!("{} wants to update {}, counter is now {}", sender, id, self.counter);
+                TrackResp::UpdateSuccess{id, ttl: self.boot_ttl}
+            }
+            TrackQuery::Remove{id, adr} => {
+                // anyone could ask to remove anyone, only nodes on the same host may
+                if adr.ip() == sender.ip() {
+                    self.data.remove(id, adr);
+                    debug!("{} left {}", adr, id);
+                } else {
+                    warn!("{} wanted to remove {} from {}", sender, adr, id);
+                }
+                TrackResp::RemoveSuccess{id}
+            }
+            TrackQuery::Lookup{id, last_lookup} => {
+                let (boot, boot_cnt) = self.data.lookup(id, last_lookup)
+                    .map_or((None, 0), |(n,c)| (Some(n),c));
+                let boot_adr = boot.map(|n| n.adr);
+                debug!("{} wants to lookup {} with ll={}. We returned {} with ll={}", sender, id, last_lookup, prtmadr(boot_adr), boot_cnt);
+                TrackResp::LookupAns{
+                    adr: boot_adr,
+                    transports: boot.map_or(Transports::udp_only(), |n| n.transports),
+                    relay: boot.and_then(|n| n.relay),
+                    lookup_id: boot_cnt
+                }
+            }
+        };
+
+        let now = Instant::now();
+        let dur = now.duration_since(self.oldest_sys_time);
+        if dur > self.boot_ttl {
+            let len_before = self.data.length();
+            debug!("removing old stuffs...");
+            self.oldest_sys_time = self.data.remove_old(self.boot_ttl, now).unwrap_or(now);
+            let len_after = self.data.length();
+            debug!("done! {} were removed, {} remain", len_before - len_after, len_after);
+        }
+        resp
+    }
+}
+
 pub fn start(port: u16, ttl: u64) {
-    let mut data = Data::new();
-    let mut counter: u32 = 0;
-    let mut oldest_sys_time = Instant::now();
-    let boot_ttl = Duration::from_secs(ttl);
+    let state = Arc::new(Mutex::new(State{
+        data: Data::new(),
+        counter: 0,
+        oldest_sys_time: Instant::now(),
+        boot_ttl: Duration::from_secs(ttl),
+    }));
 
     let my_ip = ::network::find_internet_interface().expect("couldn't find a suitable interface, are you even connected to a network?");
-    let sock = UdpSocket::bind(SocketAddr::new(IpAddr::from(my_ip), port)).expect("couldn't bind socket, is the port already in use?");
+    let my_adr = SocketAddr::new(IpAddr::from(my_ip), port);
+    let sock = UdpSocket::bind(my_adr).expect("couldn't bind socket, is the port already in use?");
 
     udp::set_blocking(&sock).unwrap();
 
+    // the same protocol over TCP for clients whose network blocks UDP
+    match TcpListener::bind(my_adr) {
+        Ok(listener) => {
+            let state_clone = state.clone();
+            thread::spawn(move || tcp_main(listener, state_clone));
+        }
+        Err(e) => warn!("couldn't listen for TCP, only UDP will work: {}", e),
+    }
+
     info!("Tracker started on {}:{} with entry ttl {}s", my_ip, port, ttl);
 
     loop {
         let (sender, query): (_, TrackQuery) = udp::recv_until_msg(&sock).unwrap();
         info!("{} spoke to us!", sender);
 
-        match query {
-            TrackQuery::Update{id, adr} => {
-                data.update(&mut counter, id, adr);
-                debug!("{} wants to update {}, counter is now {}", sender, id, counter);
-                udp::send(&sock, &TrackResp::UpdateSuccess{id: id, ttl: boot_ttl}, sender).unwrap();
-            }
-            TrackQuery::Lookup{id, last_lookup} => {
-                let (boot_adr, boot_cnt) = data.lookup(id, last_lookup).map_or((None, 0), |(a,c)| (Some(a),c));
-                debug!("{} wants to lookup {} with ll={}. We returned {} with ll={}", sender, id, last_lookup, prtmadr(boot_adr), boot_cnt);
-                udp::send(&sock, &TrackResp::LookupAns{adr: boot_adr, lookup_id: boot_cnt}, sender).unwrap();
+        let resp = state.lock().unwrap().handle(query, sender);
+        udp::send(&sock, &resp, sender).unwrap();
+    }
+}
+
+/// accepts TCP clients, each one is served in its own thread.
+/// Clients over MAX_TCP_CLIENTS are turned away
+fn tcp_main(listener: TcpListener, state: Arc<Mutex<State>>) {
+    let clients = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(s) => {
+                if clients.fetch_add(1, Ordering::SeqCst) >= MAX_TCP_CLIENTS {
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                    warn!("too many TCP clients, turned one away");
+                    continue;
+                }
+                let state_clone = state.clone();
+                let clients = clients.clone();
+                thread::spawn(move || {
+                    serve_tcp(s, state_clone);
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                });
             }
+            Err(e) => warn!("couldn't accept a TCP client: {}", e),
         }
+    }
+}
 
-        let now = Instant::now();
-        let dur = now.duration_since(oldest_sys_time);
-        if dur > boot_ttl {
-            let len_before = data.length();
-            debug!("removing old stuffs...");
-            oldest_sys_time = data.remove_old(boot_ttl, now).unwrap_or(now);
-            let len_after = data.length();
-            debug!("done! {} were removed, {} remain", len_before - len_after, len_after);
-        }
+/// answers queries from one TCP client until it hangs up or goes quiet
+fn serve_tcp(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    let sender = match stream.peer_addr() {
+        Ok(a) => a,
+        Err(_) => return,
+    };
+    if stream.set_read_timeout(Some(TCP_IDLE)).is_err() {
+        return;
+    }
+    loop {
+        let query: TrackQuery = match tcp::read_frame(&mut stream) {
+            Ok(q) => q,
+            Err(NetworkError::NoMessage) => continue,
+            Err(_) => break,
+        };
+        info!("{} spoke to us over TCP!", sender);
 
+        let resp = state.lock().unwrap().handle(query, sender);
+        if tcp::write_frame(&mut stream, &resp).is_err() {
+            break;
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        State{data: Data::new(), counter: 0, oldest_sys_time: Instant::now(), boot_ttl: Duration::from_secs(60)}
+    }
+
+    fn update(id: Id, adr: SocketAddr) -> TrackQuery {
+        TrackQuery::Update{id, adr, transports: Transports::udp_only(), relay: None}
+    }
+
+    #[test]
+    fn only_the_node_itself_can_leave() {
+        let mut s = state();
+        let room = Id::from_u64(1);
+        let node: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        s.handle(update(room, node), node);
+        s.handle(TrackQuery::Remove{id: room, adr: node}, "10.0.0.2:4000".parse().unwrap());
+        assert!(s.data.lookup(room, 0).is_some());
+        s.handle(TrackQuery::Remove{id: room, adr: node}, "10.0.0.1:5000".parse().unwrap());
+        assert!(s.data.lookup(room, 0).is_none());
+    }
+}
@@ -1,7 +1,8 @@
 
 use node::nethandle::NetHandle;
-use node::Message;
-use node::FromNetMsg;
+use node::{DirectMessage, Message};
+use node::{FromNetMsg, SendStatus};
+use node::presence::PresenceState;
 
 use cursive::*;
 use cursive::align::VAlign::Bottom;
@@ -40,7 +41,7 @@ pub fn cursive_main(neth: Arc<Mutex<NetHandle>>) {
             match opt {
                 Some(FromNetMsg::NewMsg(msg)) => {
                     let mut hist = unsafe {
-                        HISTORY.as_ref().unwrap().lock().unwrap()
+                        (*std::ptr::addr_of!(HISTORY)).as_ref().unwrap().lock().unwrap()
                     };
                     hist.push(msg.clone());
                     sender.send(Box::new(move |s: &mut Cursive| {
@@ -49,10 +50,60 @@ pub fn cursive_main(neth: Arc<Mutex<NetHandle>>) {
                         output.append("\n");
                     })).unwrap();
                 }
-                Some(FromNetMsg::NotSent) => {
+                Some(FromNetMsg::Backlog(_, msgs)) => {
+                    let mut hist = unsafe {
+                        (*std::ptr::addr_of!(HISTORY)).as_ref().unwrap().lock().unwrap()
+                    };
+                    hist.extend(msgs.iter().cloned());
+                    sender.send(Box::new(move |s: &mut Cursive| {
+                        let mut output = s.find_id::<TextView>("output").unwrap();
+                        output.append(format!("--- {} earlier messages ---\n", msgs.len()).as_str());
+                        for msg in msgs.iter() {
+                            output.append(format_message(msg).as_str());
+                            output.append("\n");
+                        }
+                        output.append("---\n");
+                    })).unwrap();
+                }
+                Some(FromNetMsg::Presence(_, member)) => {
+                    if member.is_myself() {
+                        continue;
+                    }
+                    sender.send(Box::new(move |s: &mut Cursive| {
+                        let mut output = s.find_id::<TextView>("output").unwrap();
+                        let state = match member.get_state() {
+                            PresenceState::Online => "online",
+                            PresenceState::Away => "away",
+                            PresenceState::Offline => "offline",
+                        };
+                        output.append(format!("--- {} is {} ---\n", member.get_name(), state).as_str());
+                    })).unwrap();
+                }
+                Some(FromNetMsg::Direct(msg)) => {
                     sender.send(Box::new(move |s: &mut Cursive| {
                         let mut output = s.find_id::<TextView>("output").unwrap();
-                        output.append("The previous message was not sent\n");
+                        output.append(format_direct(&msg).as_str());
+                        output.append("\n");
+                    })).unwrap();
+                }
+                Some(FromNetMsg::DirectDelivered(_, _, false)) => {
+                    sender.send(Box::new(move |s: &mut Cursive| {
+                        let mut output = s.find_id::<TextView>("output").unwrap();
+                        output.append("A private message could not be delivered\n");
+                    })).unwrap();
+                }
+                Some(FromNetMsg::Status(_, id, SendStatus::Failed)) => {
+                    // our own messages are in the history from when they were sent
+                    let text = unsafe {
+                        (*std::ptr::addr_of!(HISTORY)).as_ref().unwrap().lock().unwrap()
+                            .iter().find(|m| m.get_id() == id).map(|m| m.get_message().clone())
+                    };
+                    sender.send(Box::new(move |s: &mut Cursive| {
+                        let mut output = s.find_id::<TextView>("output").unwrap();
+                        match text {
+                            Some(text) => output.append(format!("The message \"{}\" was not sent\n", text).as_str()),
+                            None => output.append("A message was not sent\n"),
+                        }
                     })).unwrap();
                 }
                 _ => {
@@ -89,7 +140,12 @@ pub fn cursive_main(neth: Arc<Mutex<NetHandle>>) {
                                           .on_pre_event(Key::Enter, move |c| {
                                               let mut input = c.find_id::<TextArea>("input").unwrap();
                                               let neth = neth_clone1.lock().unwrap();
-                                              neth.send_message(String::from(input.get_content())).unwrap();
+                                              let content = String::from(input.get_content());
+                                              if let Some(rest) = content.strip_prefix("/msg ") {
+                                                  send_direct(c, &neth, rest);
+                                              } else {
+                                                  neth.send_message(content).unwrap();
+                                              }
 
                                               input.set_content("");
                                           }
@@ -100,10 +156,42 @@ pub fn cursive_main(neth: Arc<Mutex<NetHandle>>) {
     cursive.run();
 }
 
+/// `/msg NAME text` sends text to the member of the room called NAME only
+fn send_direct(c: &mut Cursive, neth: &NetHandle, args: &str) {
+    let mut output = c.find_id::<TextView>("output").unwrap();
+    let mut parts = args.trim().splitn(2, ' ');
+    let (name, text) = match (parts.next(), parts.next()) {
+        (Some(name), Some(text)) => (name, text),
+        _ => {
+            output.append("usage: /msg NAME text\n");
+            return;
+        }
+    };
+    let room = neth.room();
+    let found: Vec<_> = neth.members(room).into_iter()
+        .filter(|m| !m.is_myself() && m.get_name() == name)
+        .collect();
+    match found.len() {
+        0 => output.append(format!("nobody called {} is in the room\n", name).as_str()),
+        1 => {
+            neth.send_direct(room, found[0].get_id(), String::from(text)).unwrap();
+            output.append(format!("[to {}]: {}\n", name, text).as_str());
+        }
+        _ => output.append(format!("more than one {} is in the room\n", name).as_str()),
+    }
+}
+
+fn format_direct(msg: &DirectMessage) -> String {
+    let datetime: DateTime<Utc> = msg.get_timestamp().into();
+    format!("[{}]-[{} (private) ✓]: {}", datetime.format("%T"), msg.get_sender_name(), msg.get_message())
+}
+
 fn format_message(msg: &Message) -> String {
     let s_name = msg.get_sender_name();
     let t_stamp = msg.get_timestamp();
     let datetime: DateTime<Utc> = t_stamp.into();
-    format!("[{}]-[{}]: {}", datetime.format("%T"), s_name, msg.get_message())
+    // unsigned messages could be from anyone
+    let mark = if msg.is_verified() { "✓" } else { "?" };
+    format!("[{}]-[{} {}]: {}", datetime.format("%T"), s_name, mark, msg.get_message())
 
 }